@@ -1,6 +1,6 @@
-use super::base::Processor;
+use super::base::{Fault, Processor};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Mode {
     ZeroPage,
     Absolute,
@@ -17,74 +17,112 @@ pub enum Mode {
     Relative,
 }
 
+/**
+ * What an instruction works on, accumulator mode has a value but no address
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    Address(usize),
+    Value(u8),
+}
+
 impl Processor {
+    /**
+     * The byte following the opcode, wrapping past $FFFF like the PC does
+     */
+    pub fn fetch_operand_u8(&mut self) -> u8 {
+        self.mem.read((self.state.pc + 1) & 0xFFFF)
+    }
+
+    /**
+     * The two bytes following the opcode as a little-endian word
+     */
+    pub fn fetch_operand_u16(&mut self) -> u16 {
+        let low = self.mem.read((self.state.pc + 1) & 0xFFFF) as u16;
+        let high = self.mem.read((self.state.pc + 2) & 0xFFFF) as u16;
+        low | (high << 8)
+    }
+
+    /**
+     * Accumulator mode operates on A, every other mode resolves an address
+     * the way a store does
+     */
+    pub fn lookup_operand(&mut self, mode: Mode) -> Operand {
+        match mode {
+            Mode::Accumulator => Operand::Value(self.state.a),
+            _ => Operand::Address(self.lookup_write(mode)),
+        }
+    }
+
     pub fn lookup(&mut self, mode: Mode) -> usize {
+        if self.latched {
+            return self.address_latch as usize;
+        }
         match mode {
-            Mode::Accumulator => self.state.a as usize,
+            // A isn't an address, handlers taking it go through
+            // lookup_operand. Fault rather than bring the emulator down.
+            Mode::Accumulator => {
+                self.fault = Some(Fault::UnsupportedMode("lookup", mode));
+                0
+            }
             Mode::Absolute => {
                 self.cycles += 2;
-                let high = self.mem.read(self.state.pc + 2) as usize;
-                let low = self.mem.read(self.state.pc + 1) as usize;
-                low | (high << 8)
+                self.fetch_operand_u16() as usize
             }
             Mode::AbsoluteX => {
                 self.cycles += 2;
-                let carry = self.state.status & 1;
-                let high = self.mem.read(self.state.pc + 2) as usize;
-                let low = self.mem.read(self.state.pc + 1) as usize;
-                let address = (low | (high << 8))
-                    + carry as u32 as usize
-                    + self.state.x as u32 as usize;
-                if address >> 8 > high {
+                let base = self.fetch_operand_u16() as usize;
+                let address = (base + self.state.x as usize) & 0xFFFF;
+                if address >> 8 > base >> 8 {
                     self.cycles += 1;
                 }
                 address
             }
             Mode::AbsoluteY => {
                 self.cycles += 2;
-                let carry = self.state.status & 1;
-                let high = self.mem.read(self.state.pc + 2) as usize;
-                let low = self.mem.read(self.state.pc + 1) as usize;
-                let address = (low | (high << 8))
-                    + carry as u32 as usize
-                    + self.state.y as u32 as usize;
-                if address >> 8 > high {
+                let base = self.fetch_operand_u16() as usize;
+                let address = (base + self.state.y as usize) & 0xFFFF;
+                if address >> 8 > base >> 8 {
                     self.cycles += 1;
                 }
                 address
             }
-            Mode::Immediate => (self.state.pc + 1) as usize,
+            Mode::Immediate => (self.state.pc + 1) & 0xFFFF,
             Mode::Implied => {
                 self.cycles += 1;
                 0
             }
             Mode::Indirect => {
-                self.cycles += 5;
-                let high = self.mem.read(self.state.pc + 2) as usize;
-                let low = self.mem.read(self.state.pc + 1) as usize;
-                self.mem.read(low | (high << 8)) as usize
+                // JMP is the only user, it charges all five cycles
+                let pointer = self.fetch_operand_u16() as usize;
+                // The 6502 never carries into the pointer's high byte, so a
+                // pointer at $xxFF fetches its high byte from $xx00
+                let pointer_high = (pointer & 0xFF00) | ((pointer + 1) & 0xFF);
+                let target_low = self.mem.read(pointer) as usize;
+                let target_high = self.mem.read(pointer_high) as usize;
+                target_low | (target_high << 8)
             }
             Mode::IndexedX => {
                 self.cycles += 4;
                 let base_index =
-                    (self.mem.read(self.state.pc + 1) + self.state.x) as usize;
-                let high = self.mem.read(base_index) as usize;
-                let low = self.mem.read(base_index + 1) as usize;
+                    self.fetch_operand_u8().wrapping_add(self.state.x) as usize;
+                // The pointer lives in the zero page, a base of $FF takes its
+                // high byte from $00 rather than the stack page
+                let low = self.mem.read(base_index) as usize;
+                let high = self.mem.read((base_index + 1) & 0xFF) as usize;
                 low | (high << 8)
             }
             Mode::IndexedY => {
                 // by default 3 cycles
                 self.cycles += 3;
-                let carry = self.state.status & 1;
-                let base_index = self.mem.read(self.state.pc + 1) as usize;
-                let high = self.mem.read(base_index) as usize;
-                let low = self.mem.read(base_index + 1) as usize;
-                let address = (low | (high << 8))
-                    + carry as u32 as usize
-                    + self.state.y as u32 as usize;
-                // If page boundary is crossed IE. high byte is incremented at all
-                // then add a cycle
-                if address >> 8 > high {
+                let pointer = self.fetch_operand_u8() as usize;
+                // Both pointer bytes come from the zero page
+                let low = self.mem.read(pointer) as usize;
+                let high = self.mem.read((pointer + 1) & 0xFF) as usize;
+                let base = low | (high << 8);
+                let address = (base + self.state.y as usize) & 0xFFFF;
+                // Adding Y carried into the high byte, costs a cycle to fix up
+                if base & 0xFF00 != address & 0xFF00 {
                     self.cycles += 1;
                 }
                 address
@@ -93,36 +131,202 @@ impl Processor {
                 self.cycles += 1;
                 // Read as i8 is important as a negative 8 bit value will fit
                 // into a 32 bit signed integer and become a positive
-                let offset = self.mem.read(self.state.pc + 1) as i8 as i32;
+                let offset = self.fetch_operand_u8() as i8 as i32;
+                // The offset counts from the instruction after the branch
+                let next = self.state.pc.wrapping_add(2);
                 let address = if offset.is_negative() {
-                    self.state.pc - offset.wrapping_abs() as usize
+                    next.wrapping_sub(offset.wrapping_abs() as usize)
                 } else {
-                    self.state.pc + offset as usize
-                };
-                // Crossing a page boundary with a jump will cause an extra cycle
-                if address >> 8 > self.state.pc >> 8 {
+                    next.wrapping_add(offset as usize)
+                } & 0xFFFF;
+                // Landing on another page than the next instruction costs an
+                // extra cycle, backwards as well as forwards
+                if address >> 8 != next >> 8 {
                     self.cycles += 1;
                 }
                 address
             }
             Mode::ZeroPage => {
                 self.cycles += 1;
-                self.mem.read(self.state.pc + 1) as usize
+                self.fetch_operand_u8() as usize
             }
             Mode::ZeroPageX => {
                 self.cycles += 2;
                 let address =
-                    self.mem.read(self.state.pc + 1).wrapping_add(self.state.x);
+                    self.fetch_operand_u8().wrapping_add(self.state.x);
 
                 address as usize
             }
             Mode::ZeroPageY => {
                 self.cycles += 2;
-                let address = (0xff
-                    & (self.mem.read(self.state.pc + 1) + self.state.y))
-                    as usize;
-                address
+                let address =
+                    self.fetch_operand_u8().wrapping_add(self.state.y);
+
+                address as usize
             }
         }
     }
 }
+
+impl Processor {
+    /**
+     * lookup for stores. Indexed writes always spend the page crossing
+     * cycle, the CPU can't write until it knows the high byte is right.
+     */
+    pub fn lookup_write(&mut self, mode: Mode) -> usize {
+        let start = self.cycles;
+        let address = self.lookup(mode);
+        let cycles = match mode {
+            Mode::AbsoluteX | Mode::AbsoluteY => 3,
+            Mode::IndexedY => 4,
+            _ => return address,
+        };
+        self.cycles = start + cycles;
+        address
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fetch_operand_u16() {
+        let mut cpu = Processor::new(None);
+        cpu.state.pc = 0x0200;
+        cpu.mem.load(0x0200, &[0x4c, 0x34, 0x12]).unwrap();
+        assert_eq!(cpu.fetch_operand_u16(), 0x1234);
+        assert_eq!(cpu.lookup(Mode::Absolute), 0x1234);
+    }
+
+    #[test]
+    fn test_operand_wraps() {
+        // the operand of an opcode at $FFFF is at $0000
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x0000, &[0x20]).unwrap();
+        cpu.mem.load(0x0020, &[0x34, 0x12]).unwrap();
+        cpu.state.pc = 0xffff;
+        cpu.state.x = 0x01;
+        cpu.state.y = 0x01;
+        assert_eq!(cpu.fetch_operand_u8(), 0x20);
+        assert_eq!(cpu.lookup(Mode::Immediate), 0x0000);
+        assert_eq!(cpu.lookup(Mode::ZeroPage), 0x0020);
+        assert_eq!(cpu.lookup(Mode::ZeroPageX), 0x0021);
+        assert_eq!(cpu.lookup(Mode::ZeroPageY), 0x0021);
+        assert_eq!(cpu.lookup(Mode::IndexedX), 0x0012);
+        assert_eq!(cpu.lookup(Mode::IndexedY), 0x1235);
+        // $10001 + $20 wrapped
+        assert_eq!(cpu.lookup(Mode::Relative), 0x0021);
+    }
+
+    #[test]
+    fn test_lookup_accumulator() {
+        let mut cpu = Processor::new(None);
+        cpu.state.a = 0x42;
+        assert_eq!(cpu.lookup_operand(Mode::Accumulator), Operand::Value(0x42));
+        assert_eq!(cpu.fault, None);
+        assert_eq!(cpu.lookup(Mode::Accumulator), 0);
+        assert_eq!(
+            cpu.fault,
+            Some(Fault::UnsupportedMode("lookup", Mode::Accumulator))
+        );
+    }
+
+    #[test]
+    fn test_indexed_x() {
+        let mut cpu = Processor::new(None);
+        cpu.state.pc = 0x0200;
+        cpu.mem.load(0x0200, &[0xa1, 0x20]).unwrap();
+        cpu.mem.load(0x0024, &[0x34, 0x12]).unwrap();
+        cpu.state.x = 0x04;
+        assert_eq!(cpu.lookup(Mode::IndexedX), 0x1234);
+
+        // (operand + X) == $FF wraps to $00 for the high pointer byte
+        cpu.state.x = 0xdf;
+        cpu.mem.load(0x00ff, &[0x78]).unwrap();
+        cpu.mem.load(0x0000, &[0x56]).unwrap();
+        cpu.mem.load(0x0100, &[0xee]).unwrap();
+        assert_eq!(cpu.lookup(Mode::IndexedX), 0x5678);
+    }
+
+    #[test]
+    fn test_indexed_y() {
+        let mut cpu = Processor::new(None);
+        cpu.state.pc = 0x0200;
+        cpu.mem.load(0x0200, &[0xb1, 0x20]).unwrap();
+        cpu.mem.load(0x0020, &[0x10, 0x12]).unwrap();
+        // the carry flag plays no part in the address
+        cpu.state.status = 0xff;
+
+        // no page cross
+        cpu.state.y = 0x05;
+        cpu.cycles = 0;
+        assert_eq!(cpu.lookup(Mode::IndexedY), 0x1215);
+        assert_eq!(cpu.cycles, 3);
+
+        // $1210 + $F0 crosses into $13xx
+        cpu.state.y = 0xf0;
+        cpu.cycles = 0;
+        assert_eq!(cpu.lookup(Mode::IndexedY), 0x1300);
+        assert_eq!(cpu.cycles, 4);
+
+        // a pointer at $FF takes its high byte from $00
+        cpu.mem.load(0x0201, &[0xff]).unwrap();
+        cpu.mem.load(0x00ff, &[0x80]).unwrap();
+        cpu.mem.load(0x0000, &[0x34]).unwrap();
+        cpu.mem.load(0x0100, &[0xee]).unwrap();
+        cpu.state.y = 0x01;
+        cpu.cycles = 0;
+        assert_eq!(cpu.lookup(Mode::IndexedY), 0x3481);
+        assert_eq!(cpu.cycles, 3);
+    }
+
+    #[test]
+    fn test_zero_page_pointer_wrap() {
+        let mut cpu = Processor::new(None);
+        cpu.state.pc = 0x0200;
+        // each zero page byte holds its own address, the stack page would
+        // give away a read past $FF
+        for address in 0..0x100 {
+            cpu.mem.poke(address, address as u8);
+        }
+        cpu.mem.poke(0x0100, 0xee);
+
+        let cases = [
+            // pointer, X
+            (0x00, 0x00),
+            (0xfe, 0x00),
+            (0xff, 0x00),
+            // X moves the pointer to exactly $FF
+            (0xf0, 0x0f),
+            // and past the end of the zero page
+            (0x80, 0x80),
+            (0xff, 0xff),
+        ];
+        for &(operand, x) in cases.iter() {
+            cpu.mem.load(0x0200, &[0xa1, operand]).unwrap();
+            cpu.state.x = x;
+            let pointer = operand.wrapping_add(x) as usize;
+            let expected = pointer | ((pointer + 1) & 0xff) << 8;
+            assert_eq!(
+                cpu.lookup(Mode::IndexedX),
+                expected,
+                "(${:02x},X) with X {:#04x}",
+                operand,
+                x
+            );
+        }
+
+        cpu.state.y = 0;
+        for &pointer in [0x00usize, 0xfe, 0xff].iter() {
+            cpu.mem.load(0x0200, &[0xb1, pointer as u8]).unwrap();
+            let expected = pointer | ((pointer + 1) & 0xff) << 8;
+            assert_eq!(
+                cpu.lookup(Mode::IndexedY),
+                expected,
+                "(${:02x}),Y",
+                pointer
+            );
+        }
+    }
+}
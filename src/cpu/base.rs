@@ -1,4 +1,8 @@
+use super::addressing::Mode;
+use super::debug::{TraceEntry, Tracer, TRACE_BUFFER_SIZE};
 use super::memory::{Memory, ZERO_PAGE_TOP};
+use super::micro::MicroOp;
+use std::collections::VecDeque;
 
 pub const N_FLAG: u8 = 0b1000_0000;
 pub const V_FLAG: u8 = 0b0100_0000;
@@ -10,6 +14,11 @@ pub const Z_FLAG: u8 = 0b0000_0010;
 pub const C_FLAG: u8 = 0b0000_0001;
 pub const SIGN_BIT: u8 = 0b1000_0000;
 
+// Register state after the reset sequence, as found in nestest.log
+pub const RESET_STATUS: u8 = I_FLAG | F_FLAG;
+pub const RESET_SP: u8 = 0xFD;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Reg {
     A,
     X,
@@ -27,10 +36,38 @@ pub struct State {
     pub status: u8,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fault {
+    UnsupportedMode(&'static str, Mode),
+    Jam,
+    // Opcode without a handler while strict, with the PC it was fetched from
+    UnimplementedOpcode(u8, usize),
+}
+
 pub struct Processor {
     pub mem: Memory,
     pub state: State,
     pub cycles: u32,
+    // instructions started, see total_instructions
+    pub instruction_count: u64,
+    // cycles left of the instruction in flight, see tick
+    pub busy: u32,
+    // bus cycles left of a micro-op instruction, see tick
+    pub micro_ops: VecDeque<MicroOp>,
+    pub address_latch: u16,
+    pub data_latch: u8,
+    // lookup hands back address_latch while a micro-op runs a handler
+    pub latched: bool,
+    pub fault: Option<Fault>,
+    pub nmi_at: Option<u64>,
+    // print every executed instruction
+    pub trace: bool,
+    // fault on unimplemented opcodes instead of running them as NOPs
+    pub strict: bool,
+    // ring buffer of the last instructions, see recent_trace
+    pub recent: VecDeque<TraceEntry>,
+    // called before every instruction, see set_tracer
+    pub tracer: Option<Box<dyn Tracer>>,
 }
 
 impl Processor {
@@ -44,15 +81,71 @@ impl Processor {
             status: 0,
         };
         Processor {
-            mem: mem.unwrap_or(Memory::new()),
+            mem: mem.unwrap_or_default(),
             state,
             cycles: 0,
+            instruction_count: 0,
+            busy: 0,
+            micro_ops: VecDeque::new(),
+            address_latch: 0,
+            data_latch: 0,
+            latched: false,
+            fault: None,
+            nmi_at: None,
+            trace: false,
+            strict: false,
+            recent: VecDeque::with_capacity(TRACE_BUFFER_SIZE),
+            tracer: None,
         }
     }
+
+    pub fn total_instructions(&self) -> u64 {
+        self.instruction_count
+    }
+
     pub fn get_pc(&self) -> usize {
         self.state.pc
     }
 
+    // Bit 5 isn't wired to anything and always reads as set
+    pub fn get_status(&self) -> u8 {
+        self.state.status | F_FLAG
+    }
+
+    /**
+     * Register edits for debuggers. PC wraps to 16 bits and bit 5 of the
+     * status stays set.
+     */
+    pub fn set_pc(&mut self, address: usize) -> &mut Self {
+        self.state.pc = address & 0xFFFF;
+        self
+    }
+
+    pub fn set_a(&mut self, value: u8) -> &mut Self {
+        self.set_reg(Reg::A, value)
+    }
+
+    pub fn set_x(&mut self, value: u8) -> &mut Self {
+        self.set_reg(Reg::X, value)
+    }
+
+    pub fn set_y(&mut self, value: u8) -> &mut Self {
+        self.set_reg(Reg::Y, value)
+    }
+
+    pub fn set_status(&mut self, value: u8) -> &mut Self {
+        self.set_reg(Reg::S, value | F_FLAG)
+    }
+
+    /**
+     * Status as restored by PLP & RTI, B only exists on the stack and bit 5
+     * can't be cleared
+     */
+    pub fn set_status_from_stack(&mut self, value: u8) -> &mut Self {
+        self.state.status = (value & !B_FLAG) | F_FLAG;
+        self
+    }
+
     pub fn stack_top(&self) -> usize {
         ZERO_PAGE_TOP + self.state.sp as usize
     }
@@ -80,16 +173,24 @@ impl Processor {
 
     pub fn update_pc(&mut self, delta: i32) -> &mut Self {
         // println!("Update pc {:#04x} with {}", self.state.pc, delta);
-        if delta.is_negative() {
-            self.state.pc -= delta.wrapping_abs() as u32 as usize;
+        // The program counter is 16 bits wide and wraps around on hardware
+        let pc = if delta.is_negative() {
+            self.state
+                .pc
+                .wrapping_sub(delta.wrapping_abs() as u32 as usize)
         } else {
-            self.state.pc += delta as usize;
-        }
+            self.state.pc.wrapping_add(delta as usize)
+        };
+        self.state.pc = pc & 0xFFFF;
         self
     }
 
+    /**
+     * Set PC for a control-flow instruction, wrapping to 16 bits. The target
+     * is final, don't follow it with update_pc.
+     */
     pub fn jump(&mut self, new_pc: usize) -> &mut Self {
-        self.state.pc = new_pc;
+        self.state.pc = new_pc & 0xFFFF;
         self
     }
 
@@ -112,7 +213,14 @@ impl Processor {
         self
     }
 
+    // Unsigned so the count can't be wound back, execute checks the total
     pub fn update_cycles(&mut self, cycles: u32) -> &mut Self {
+        debug_assert!(
+            self.cycles.checked_add(cycles).is_some(),
+            "cycle count {} overflows adding {}",
+            self.cycles,
+            cycles
+        );
         self.cycles += cycles;
         self
     }
@@ -211,8 +319,7 @@ mod test {
 
         let mut cpu = Processor::new(None);
 
-        for i in 0..overflow_table.len() {
-            let (m, n, result, expected) = overflow_table[i];
+        for &(m, n, result, expected) in overflow_table.iter() {
             cpu.update_status(m, n, result as u8, V_FLAG);
             assert_eq!(
                 cpu.state.status, expected,
@@ -221,4 +328,33 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_register_edits() {
+        let mut cpu = Processor::new(None);
+        cpu.set_pc(0x1234);
+        assert_eq!(cpu.get_pc(), 0x1234);
+        cpu.set_pc(0x1_0000);
+        assert_eq!(cpu.get_pc(), 0x0000, "PC is 16 bits");
+
+        cpu.set_a(0x01).set_x(0x02).set_y(0x03).set_status(C_FLAG);
+        assert_eq!((cpu.state.a, cpu.state.x, cpu.state.y), (1, 2, 3));
+        assert_eq!(cpu.state.status, C_FLAG | F_FLAG);
+    }
+
+    #[test]
+    fn test_update_cycles() {
+        let mut cpu = Processor::new(None);
+        cpu.update_cycles(2).update_cycles(0).update_cycles(5);
+        assert_eq!(cpu.cycles, 7);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overflows")]
+    fn test_update_cycles_overflow() {
+        let mut cpu = Processor::new(None);
+        cpu.cycles = u32::MAX - 1;
+        cpu.update_cycles(2);
+    }
 }
@@ -1,65 +1,722 @@
-#[allow(dead_code)]
-pub const ADC: u8 = 0x69;
-#[allow(dead_code)]
-pub const BCC: u8 = 0x90;
-#[allow(dead_code)]
-pub const BCS: u8 = 0xb0;
-#[allow(dead_code)]
-pub const BEQ: u8 = 0xf0;
-#[allow(dead_code)]
-pub const BIT_Z: u8 = 0x24;
-#[allow(dead_code)]
-pub const BIT_A: u8 = 0x2c;
-#[allow(dead_code)]
-pub const BMI: u8 = 0x30;
-#[allow(dead_code)]
-pub const BNE: u8 = 0xd0;
-#[allow(dead_code)]
-pub const BPL: u8 = 0x10;
-#[allow(dead_code)]
-pub const CLC: u8 = 0x18;
-#[allow(dead_code)]
-pub const SEC: u8 = 0x38;
-#[allow(dead_code)]
-pub const NOP: u8 = 0xea;
-#[allow(dead_code)]
-pub const LDA: u8 = 0xa9;
+use super::addressing::Mode;
+use super::base::{Processor, State};
+use super::memory::{
+    Memory, IRQ_BRK_VECTOR, MEMORY_MAX, NMI_VECTOR, RESET_VECTOR, ZERO_PAGE_TOP,
+};
+use super::opcodes::{is_jam, opcode_len};
+use std::collections::BTreeSet;
 
+#[rustfmt::skip]
 const INSTRUCTION_STRINGS: &[&str] = &[
 //           0:8    1:9    2:a    3:b    4:c    5:d    6:e    7:f
-/* 0x00 */  "BRK", "---", "---", "---", "---", "---", "---", "---",
-/* 0x08 */  "PHP", "---", "---", "---", "---", "---", "---", "---",
-/* 0x10 */  "BPL", "---", "---", "---", "---", "---", "---", "---",
-/* 0x18 */  "CLC", "---", "---", "---", "---", "---", "---", "---",
-/* 0x20 */  "JSR", "---", "---", "---", "---", "---", "---", "---",
-/* 0x28 */  "PLP", "---", "---", "---", "BIT", "---", "---", "---",
-/* 0x30 */  "BMI", "---", "---", "---", "---", "---", "---", "---",
-/* 0x38 */  "SEC", "---", "---", "---", "---", "---", "---", "---",
-/* 0x40 */  "RTI", "---", "---", "---", "---", "---", "---", "---",
-/* 0x48 */  "PHA", "---", "---", "---", "JMP", "---", "---", "---",
-/* 0x50 */  "BVC", "---", "---", "---", "---", "---", "---", "---",
-/* 0x58 */  "CLI", "---", "---", "---", "---", "---", "---", "---",
-/* 0x60 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0x68 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0x70 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0x78 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0x80 */  "---", "---", "---", "---", "STY", "---", "---", "---",
-/* 0x88 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0x90 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0x98 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xa0 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xa8 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xb0 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xb8 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xc0 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xc8 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xd0 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xd8 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xe0 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xe8 */  "---", "---", "NOP", "---", "---", "---", "---", "---",
-/* 0xf0 */  "---", "---", "---", "---", "---", "---", "---", "---",
-/* 0xf8 */  "---", "---", "---", "---", "---", "---", "---", "---",
-]
-
-pub fn parse_asm() -> Vec<u8> {
+/* 0x00 */  "BRK", "ORA", "JAM", "SLO", "NOP", "ORA", "ASL", "SLO",
+/* 0x08 */  "PHP", "ORA", "ASL", "ANC", "NOP", "ORA", "ASL", "SLO",
+/* 0x10 */  "BPL", "ORA", "JAM", "SLO", "NOP", "ORA", "ASL", "SLO",
+/* 0x18 */  "CLC", "ORA", "NOP", "SLO", "NOP", "ORA", "ASL", "SLO",
+/* 0x20 */  "JSR", "AND", "JAM", "RLA", "BIT", "AND", "ROL", "RLA",
+/* 0x28 */  "PLP", "AND", "ROL", "ANC", "BIT", "AND", "ROL", "RLA",
+/* 0x30 */  "BMI", "AND", "JAM", "RLA", "NOP", "AND", "ROL", "RLA",
+/* 0x38 */  "SEC", "AND", "NOP", "RLA", "NOP", "AND", "ROL", "RLA",
+/* 0x40 */  "RTI", "EOR", "JAM", "SRE", "NOP", "EOR", "LSR", "SRE",
+/* 0x48 */  "PHA", "EOR", "LSR", "ALR", "JMP", "EOR", "LSR", "SRE",
+/* 0x50 */  "BVC", "EOR", "JAM", "SRE", "NOP", "EOR", "LSR", "SRE",
+/* 0x58 */  "CLI", "EOR", "NOP", "SRE", "NOP", "EOR", "LSR", "SRE",
+/* 0x60 */  "RTS", "ADC", "JAM", "RRA", "NOP", "ADC", "ROR", "RRA",
+/* 0x68 */  "PLA", "ADC", "ROR", "ARR", "JMP", "ADC", "ROR", "RRA",
+/* 0x70 */  "BVS", "ADC", "JAM", "RRA", "NOP", "ADC", "ROR", "RRA",
+/* 0x78 */  "SEI", "ADC", "NOP", "RRA", "NOP", "ADC", "ROR", "RRA",
+/* 0x80 */  "NOP", "STA", "NOP", "SAX", "STY", "STA", "STX", "SAX",
+/* 0x88 */  "DEY", "NOP", "TXA", "ANE", "STY", "STA", "STX", "SAX",
+/* 0x90 */  "BCC", "STA", "JAM", "SHA", "STY", "STA", "STX", "SAX",
+/* 0x98 */  "TYA", "STA", "TXS", "TAS", "SHY", "STA", "SHX", "SHA",
+/* 0xa0 */  "LDY", "LDA", "LDX", "LAX", "LDY", "LDA", "LDX", "LAX",
+/* 0xa8 */  "TAY", "LDA", "TAX", "LXA", "LDY", "LDA", "LDX", "LAX",
+/* 0xb0 */  "BCS", "LDA", "JAM", "LAX", "LDY", "LDA", "LDX", "LAX",
+/* 0xb8 */  "CLV", "LDA", "TSX", "LAS", "LDY", "LDA", "LDX", "LAX",
+/* 0xc0 */  "CPY", "CMP", "NOP", "DCP", "CPY", "CMP", "DEC", "DCP",
+/* 0xc8 */  "INY", "CMP", "DEX", "SBX", "CPY", "CMP", "DEC", "DCP",
+/* 0xd0 */  "BNE", "CMP", "JAM", "DCP", "NOP", "CMP", "DEC", "DCP",
+/* 0xd8 */  "CLD", "CMP", "NOP", "DCP", "NOP", "CMP", "DEC", "DCP",
+/* 0xe0 */  "CPX", "SBC", "NOP", "ISB", "CPX", "SBC", "INC", "ISB",
+/* 0xe8 */  "INX", "SBC", "NOP", "SBC", "CPX", "SBC", "INC", "ISB",
+/* 0xf0 */  "BEQ", "SBC", "JAM", "ISB", "NOP", "SBC", "INC", "ISB",
+/* 0xf8 */  "SED", "SBC", "NOP", "ISB", "NOP", "SBC", "INC", "ISB",
+];
+
+const JSR_OPCODE: u8 = 0x20;
+const JMP_OPCODE: u8 = 0x4c;
+
+// Instructions kept for recent_trace
+pub const TRACE_BUFFER_SIZE: usize = 256;
+
+/**
+ * An executed opcode with the registers as they were before it ran
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct TraceEntry {
+    pub opcode: u8,
+    pub state: State,
+}
+
+/**
+ * Hook for custom instruction logs, called with the CPU as it is before
+ * each instruction executes. Install one with `Processor::set_tracer`.
+ */
+pub trait Tracer {
+    fn on_instruction(&mut self, cpu: &Processor, mem: &Memory);
+}
+
+/**
+ * Prints each instruction in the layout of nestest.log, without the PPU
+ * columns, eg. `C000  4C F5 C5  JMP $C5F5  A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+ */
+pub struct NestestTracer;
+
+impl Tracer for NestestTracer {
+    fn on_instruction(&mut self, cpu: &Processor, _mem: &Memory) {
+        println!("{}", cpu.nestest_line());
+    }
+}
+
+/**
+ * What exec_traced ran, `cycles` includes any interrupt taken after it
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecutedInstruction {
+    pub pc: usize,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    pub operand_bytes: Vec<u8>,
+    pub cycles: u32,
+}
+
+pub fn opcode_name(opcode: u8) -> &'static str {
+    INSTRUCTION_STRINGS[opcode as usize]
+}
+
+/**
+ * Assembly syntax for an instruction's operand, `bytes` excludes the opcode
+ */
+pub fn format_operand(mode: Mode, pc: usize, bytes: &[u8]) -> String {
+    let byte = || bytes[0];
+    let word = || bytes[0] as usize | ((bytes[1] as usize) << 8);
+    match mode {
+        Mode::Implied => String::new(),
+        Mode::Accumulator => String::from("A"),
+        Mode::Immediate => format!("#${:02X}", byte()),
+        Mode::ZeroPage => format!("${:02X}", byte()),
+        Mode::ZeroPageX => format!("${:02X},X", byte()),
+        Mode::ZeroPageY => format!("${:02X},Y", byte()),
+        Mode::Absolute => format!("${:04X}", word()),
+        Mode::AbsoluteX => format!("${:04X},X", word()),
+        Mode::AbsoluteY => format!("${:04X},Y", word()),
+        Mode::Indirect => format!("(${:04X})", word()),
+        Mode::IndexedX => format!("(${:02X},X)", byte()),
+        Mode::IndexedY => format!("(${:02X}),Y", byte()),
+        // Show the branch target, resolved the same way lookup does
+        Mode::Relative => {
+            let next = pc.wrapping_add(2);
+            let target = next.wrapping_add(byte() as i8 as usize) & 0xFFFF;
+            format!("${:04X}", target)
+        }
+    }
+}
+
+impl Processor {
+    /**
+     * exec, describing the instruction it ran
+     */
+    pub fn exec_traced(&mut self) -> ExecutedInstruction {
+        let pc = self.state.pc;
+        let opcode = self.mem.peek_mapped(pc);
+        let (_, mode) = self.decode(opcode);
+        let len = opcode_len(mode) as usize;
+        let operand_bytes = (1..len)
+            .map(|i| self.mem.peek_mapped((pc + i) & 0xFFFF))
+            .collect();
+
+        let cycles = self.execute();
+        self.step_ppu(cycles);
+        ExecutedInstruction {
+            pc,
+            opcode,
+            mnemonic: opcode_name(opcode),
+            mode,
+            operand_bytes,
+            cycles,
+        }
+    }
+
+    /**
+     * Hash of the registers, all of memory and the cycle count, equal
+     * hashes after equal runs show the emulation is deterministic
+     */
+    #[cfg(test)]
+    pub fn state_hash(&self) -> u64 {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        let state = &self.state;
+        (state.a, state.x, state.y, state.sp, state.pc, state.status)
+            .hash(&mut hasher);
+        self.mem.ram.hash(&mut hasher);
+        self.cycles.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn record_trace(&mut self, opcode: u8) {
+        if self.recent.len() == TRACE_BUFFER_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(TraceEntry {
+            opcode,
+            state: self.state,
+        });
+    }
+
+    /**
+     * The last TRACE_BUFFER_SIZE instructions, oldest first
+     */
+    pub fn recent_trace(&self) -> Vec<TraceEntry> {
+        self.recent.iter().copied().collect()
+    }
+
+    /**
+     * Instruction text and length at `address`, eg. `JMP $C5F5`
+     */
+    fn disassemble(&self, address: usize) -> (String, usize) {
+        // Peek, disassembly must not trigger I/O reads
+        let value = self.mem.peek_mapped(address);
+        let (_, mode) = self.decode(value);
+        let len = opcode_len(mode) as usize;
+        let operand: Vec<u8> = (1..len)
+            .map(|i| self.mem.peek_mapped((address + i) & 0xFFFF))
+            .collect();
+        let text = format!(
+            "{} {}",
+            opcode_name(value),
+            format_operand(mode, address, &operand)
+        );
+        (String::from(text.trim_end()), len)
+    }
+
+    /**
+     * Disassemble the instruction at PC, eg. `C000: 4C F5 C5 JMP $C5F5`
+     */
+    pub fn trace_line(&self) -> String {
+        let pc = self.state.pc;
+        let (hex, text) = self.instruction_at(pc);
+        format!("{:04X}: {:<8} {}", pc, hex, text)
+    }
+
+    /**
+     * trace_line in the nestest.log layout with the registers and cycles
+     */
+    pub fn nestest_line(&self) -> String {
+        let pc = self.state.pc;
+        let (hex, text) = self.instruction_at(pc);
+        format!(
+            "{:04X}  {:<8}  {:<30}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            pc,
+            hex,
+            text,
+            self.state.a,
+            self.state.x,
+            self.state.y,
+            self.get_status(),
+            self.state.sp,
+            self.cycles
+        )
+    }
+
+    // Hex bytes and disassembly of the instruction at `address`
+    fn instruction_at(&self, address: usize) -> (String, String) {
+        let (text, len) = self.disassemble(address);
+        let hex: Vec<String> = (0..len)
+            .map(|i| {
+                format!("{:02X}", self.mem.peek_mapped((address + i) & 0xFFFF))
+            })
+            .collect();
+        (hex.join(" "), text)
+    }
+
+    /**
+     * Up to `radius` instructions either side of PC as (address, text, is
+     * PC). Instructions are variable length so the ones before PC are a
+     * guess: the furthest start that decodes into a run landing on PC.
+     */
+    pub fn disassembly_window(
+        &self,
+        radius: usize,
+    ) -> Vec<(usize, String, bool)> {
+        let pc = self.state.pc;
+        let mut window = Vec::new();
+        for start in pc.saturating_sub(radius * 3)..pc {
+            let mut address = start;
+            let mut run = Vec::new();
+            while address < pc {
+                let (text, len) = self.disassemble(address);
+                run.push((address, text, false));
+                address += len;
+            }
+            if address == pc {
+                window = run.split_off(run.len().saturating_sub(radius));
+                break;
+            }
+        }
+
+        let mut address = pc;
+        for _ in 0..=radius {
+            if address >= MEMORY_MAX {
+                break;
+            }
+            let (text, len) = self.disassemble(address);
+            window.push((address, text, address == pc));
+            address += len;
+        }
+        window
+    }
+
+    /**
+     * Return addresses of the subroutines being run, innermost first.
+     *
+     * This is a heuristic, the stack holds no frame markers. Walking up from
+     * SP every pair of bytes that points just past a JSR is taken as a return
+     * address, so pushed data that happens to look like one is reported too
+     * and frames popped with PLA or abandoned with TXS are missed.
+     */
+    pub fn call_stack(&self) -> Vec<usize> {
+        let mut frames = Vec::new();
+        let mut sp = self.state.sp as usize + 1;
+        // PCH is pushed first so PCL is the lower of the two bytes
+        while sp < 0xFF {
+            let pcl = self.mem.peek_mapped(ZERO_PAGE_TOP + sp) as usize;
+            let pch = self.mem.peek_mapped(ZERO_PAGE_TOP + sp + 1) as usize;
+            let pushed = pcl | (pch << 8);
+            if pushed >= 2 && self.mem.peek_mapped(pushed - 2) == JSR_OPCODE {
+                frames.push((pushed + 1) & 0xFFFF);
+                sp += 2;
+            } else {
+                sp += 1;
+            }
+        }
+        frames
+    }
+
+    fn peek_u16(&self, address: usize) -> usize {
+        let low = self.mem.peek_mapped(address & 0xFFFF) as usize;
+        let high = self.mem.peek_mapped((address + 1) & 0xFFFF) as usize;
+        low | (high << 8)
+    }
+
+    /**
+     * Addresses of every byte reachable as code from the reset, NMI and
+     * IRQ/BRK vectors, following branches, JMP and JSR. Whatever isn't
+     * reached is taken to be data. Blocks end at RTS, RTI, BRK, JAM,
+     * opcodes without a handler and indirect jumps, whose target is only
+     * known at run time.
+     */
+    pub fn code_addresses(&self) -> BTreeSet<usize> {
+        let mut code = BTreeSet::new();
+        let mut blocks: Vec<usize> = [RESET_VECTOR, NMI_VECTOR, IRQ_BRK_VECTOR]
+            .iter()
+            .map(|&vector| self.peek_u16(vector))
+            .collect();
+
+        while let Some(start) = blocks.pop() {
+            let mut address = start;
+            while !code.contains(&address) {
+                let value = self.mem.peek_mapped(address);
+                let mode = match self.decode_implemented(value) {
+                    Some((_, mode)) if !is_jam(value) => mode,
+                    _ => break,
+                };
+                let len = opcode_len(mode) as usize;
+                for i in 0..len {
+                    code.insert((address + i) & 0xFFFF);
+                }
+                let next = (address + len) & 0xFFFF;
+
+                match value {
+                    JSR_OPCODE => blocks.push(self.peek_u16(address + 1)),
+                    JMP_OPCODE => {
+                        blocks.push(self.peek_u16(address + 1));
+                        break;
+                    }
+                    // BRK, RTI, RTS & JMP indirect
+                    0x00 | 0x40 | 0x60 | 0x6c => break,
+                    // Branches fall through as well
+                    _ if value & 0x1f == 0x10 => {
+                        let offset = self.mem.peek_mapped(address + 1) as i8;
+                        blocks.push(
+                            (next as isize + offset as isize) as usize & 0xFFFF,
+                        );
+                    }
+                    _ => {}
+                }
+                address = next;
+            }
+        }
+        code
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::opcodes::encode;
+    use crate::nes::mapper::uxrom::Uxrom;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    const GROUP_ONE: &[Mode] = &[
+        Mode::IndexedX,
+        Mode::ZeroPage,
+        Mode::Immediate,
+        Mode::Absolute,
+        Mode::IndexedY,
+        Mode::ZeroPageX,
+        Mode::AbsoluteY,
+        Mode::AbsoluteX,
+    ];
+    // Everything both the assembler and the disassembler understand.
+    // Branches are missing because disassembly shows the target where the
+    // assembler takes a `!$offset`.
+    const ROUND_TRIP: &[(&str, &[Mode])] = &[
+        ("ADC", GROUP_ONE),
+        ("AND", GROUP_ONE),
+        (
+            "ASL",
+            &[
+                Mode::Accumulator,
+                Mode::ZeroPage,
+                Mode::ZeroPageX,
+                Mode::Absolute,
+                Mode::AbsoluteX,
+            ],
+        ),
+        ("BIT", &[Mode::ZeroPage, Mode::Absolute]),
+        ("BRK", &[Mode::Implied]),
+        ("CLC", &[Mode::Implied]),
+        ("CLD", &[Mode::Implied]),
+        ("CMP", GROUP_ONE),
+        ("EOR", GROUP_ONE),
+        ("JMP", &[Mode::Absolute, Mode::Indirect]),
+        ("JSR", &[Mode::Absolute]),
+        (
+            "LDX",
+            &[
+                Mode::Immediate,
+                Mode::ZeroPage,
+                Mode::ZeroPageY,
+                Mode::Absolute,
+            ],
+        ),
+        (
+            "LDY",
+            &[
+                Mode::Immediate,
+                Mode::ZeroPage,
+                Mode::ZeroPageX,
+                Mode::Absolute,
+            ],
+        ),
+        ("LDA", GROUP_ONE),
+        ("NOP", &[Mode::Implied]),
+        ("ORA", GROUP_ONE),
+        ("RTI", &[Mode::Implied]),
+        ("RTS", &[Mode::Implied]),
+        ("SEC", &[Mode::Implied]),
+        ("SED", &[Mode::Implied]),
+        (
+            "STA",
+            &[
+                Mode::IndexedX,
+                Mode::ZeroPage,
+                Mode::Absolute,
+                Mode::IndexedY,
+                Mode::ZeroPageX,
+                Mode::AbsoluteY,
+                Mode::AbsoluteX,
+            ],
+        ),
+        ("STX", &[Mode::ZeroPage, Mode::ZeroPageY, Mode::Absolute]),
+        ("STY", &[Mode::ZeroPage, Mode::ZeroPageX, Mode::Absolute]),
+    ];
+
+    // Assembler syntax, written out independently of format_operand
+    fn source(name: &str, mode: Mode, operand: u16) -> String {
+        let byte = operand & 0xFF;
+        let operand = match mode {
+            Mode::Implied => String::new(),
+            Mode::Accumulator => String::from(" A"),
+            Mode::Immediate => format!(" #${:02X}", byte),
+            Mode::ZeroPage => format!(" ${:02X}", byte),
+            Mode::ZeroPageX => format!(" ${:02X},X", byte),
+            Mode::ZeroPageY => format!(" ${:02X},Y", byte),
+            Mode::Absolute => format!(" ${:04X}", operand),
+            Mode::AbsoluteX => format!(" ${:04X},X", operand),
+            Mode::AbsoluteY => format!(" ${:04X},Y", operand),
+            Mode::IndexedX => format!(" (${:02X},X)", byte),
+            Mode::IndexedY => format!(" (${:02X}),Y", byte),
+            Mode::Indirect => format!(" (${:04X})", operand),
+            _ => panic!("No syntax for {:?}", mode),
+        };
+        format!("{}{};", name, operand)
+    }
+
+    // Assemble, disassemble and assemble again, the bytes must not change
+    fn round_trip(cpu: &mut Processor, line: &str) -> Result<(), String> {
+        let bytes = encode(line).map_err(|e| e.to_string())?;
+        cpu.mem.load(0x8000, &bytes).unwrap();
+        let (text, len) = cpu.disassemble(0x8000);
+        let again = encode(&format!("{};", text))
+            .map_err(|e| format!("{} -> {}", text, e))?;
+        if len != bytes.len() || again != bytes {
+            return Err(format!(
+                "{:02X?} -> {} -> {:02X?}",
+                bytes, text, again
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut cpu = Processor::new(None);
+        // xorshift, fixed seed so failures reproduce
+        let mut seed: u32 = 0x2a03_2c02;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+
+        for _ in 0..2000 {
+            let (name, modes) =
+                ROUND_TRIP[random() as usize % ROUND_TRIP.len()];
+            let mode = modes[random() as usize % modes.len()];
+            let mut operand = random() as u16;
+            if round_trip(&mut cpu, &source(name, mode, operand)).is_ok() {
+                continue;
+            }
+
+            // shrink the operand while the instruction keeps failing
+            while operand > 0
+                && round_trip(&mut cpu, &source(name, mode, operand / 2))
+                    .is_err()
+            {
+                operand /= 2;
+            }
+            let line = source(name, mode, operand);
+            let error = round_trip(&mut cpu, &line).unwrap_err();
+            panic!("{} does not round trip: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn test_trace_line() {
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0xC000, &[0x4c, 0xf5, 0xc5]).unwrap();
+        cpu.state.pc = 0xC000;
+
+        let line = cpu.trace_line();
+        assert_eq!(line, "C000: 4C F5 C5 JMP $C5F5");
+
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0xC5F5);
+
+        cpu.mem.load(0xC5F5, &[0xea]).unwrap();
+        assert_eq!(cpu.trace_line(), "C5F5: EA       NOP");
+    }
+
+    #[test]
+    fn test_recent_trace() {
+        let mut cpu = Processor::new(None);
+        let count = TRACE_BUFFER_SIZE + 44;
+        // SECs then a CLC
+        let mut program = vec![0x38; count];
+        program[count - 1] = 0x18;
+        cpu.mem.load(0x0200, &program).unwrap();
+        cpu.state.pc = 0x0200;
+        for _ in 0..count {
+            cpu.exec();
+        }
+
+        let trace = cpu.recent_trace();
+        assert_eq!(trace.len(), TRACE_BUFFER_SIZE);
+        for (i, entry) in trace.iter().enumerate() {
+            assert_eq!(entry.state.pc, 0x0200 + 44 + i);
+        }
+        let last = trace[TRACE_BUFFER_SIZE - 1];
+        assert_eq!(last.opcode, 0x18);
+        assert_eq!(last.state.status, 1, "registers from before the CLC");
+    }
+
+    #[test]
+    fn test_exec_traced() {
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x0200, &[0xa9, 0x01]).unwrap();
+        cpu.state.pc = 0x0200;
+
+        let executed = cpu.exec_traced();
+        assert_eq!(
+            executed,
+            ExecutedInstruction {
+                pc: 0x0200,
+                opcode: 0xa9,
+                mnemonic: "LDA",
+                mode: Mode::Immediate,
+                operand_bytes: vec![0x01],
+                cycles: 2,
+            }
+        );
+        assert_eq!(cpu.state.a, 0x01);
+        assert_eq!(cpu.mem.ppu.dot, 6);
+
+        // Operands come from the bank the mapper has switched in
+        let mut prg = vec![0; 0x8000];
+        prg[0x4000..0x4003].copy_from_slice(&[0xad, 0x34, 0x12]);
+        cpu.mem.load(0x8000, &[0xad, 0xff, 0xff]).unwrap();
+        cpu.mem.mapper = Some(Box::new(Uxrom::new(&prg)));
+        cpu.mem.write(0x8000, 1);
+        assert_eq!(cpu.mem.peek_mapped(0x8000), 0xad);
+        assert_eq!(cpu.mem.peek_mapped(0x8001), 0x34);
+        assert_eq!(cpu.mem.peek(0x8001), 0xff, "peek stays raw");
+        cpu.state.pc = 0x8000;
+        let executed = cpu.exec_traced();
+        assert_eq!(executed.operand_bytes, vec![0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_tracer() {
+        struct Counter(Rc<Cell<usize>>);
+        impl Tracer for Counter {
+            fn on_instruction(&mut self, cpu: &Processor, mem: &Memory) {
+                assert_eq!(mem.peek(cpu.state.pc), 0x38, "before it runs");
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x0200, &[0x38; 5]).unwrap();
+        cpu.state.pc = 0x0200;
+        let count = Rc::new(Cell::new(0));
+        cpu.set_tracer(Box::new(Counter(count.clone())));
+        for _ in 0..5 {
+            cpu.exec();
+        }
+        assert_eq!(count.get(), 5);
+
+        cpu.state.pc = 0x0200;
+        cpu.state.status = 0x04;
+        cpu.cycles = 7;
+        assert_eq!(
+            cpu.nestest_line(),
+            "0200  38        SEC                             A:00 X:00 Y:00 P:24 SP:00 CYC:7"
+        );
+    }
+
+    #[test]
+    fn test_code_addresses() {
+        let mut cpu = Processor::new(None);
+        #[rustfmt::skip]
+        let program = [
+            0x20, 0x0a, 0x80, // $8000 JSR $800A
+            0x4c, 0x03, 0x80, // $8003 JMP $8003
+            0xde, 0xad, 0xbe, 0xef, // $8006 table
+            0xbd, 0x06, 0x80, // $800A LDA $8006,X
+            0xf0, 0x01, // $800D BEQ $8010
+            0x60, // $800F RTS
+            0x40, // $8010 RTI
+            0x02, // $8011 JAM
+            0x03, // $8012 SLO ($nn,X), no handler
+        ];
+        cpu.mem.load(0x8000, &program).unwrap();
+        cpu.mem.set_vector(RESET_VECTOR, 0x8000);
+        cpu.mem.set_vector(NMI_VECTOR, 0x8011);
+        cpu.mem.set_vector(IRQ_BRK_VECTOR, 0x8012);
+
+        let code = cpu.code_addresses();
+        let expected: BTreeSet<usize> =
+            (0x8000..0x8006).chain(0x800a..=0x8010).collect();
+        assert_eq!(code, expected, "the table at $8006 is data");
+    }
+
+    #[test]
+    fn test_state_hash() {
+        fn run() -> Processor {
+            let mut cpu = Processor::new(None);
+            cpu.run_program(
+                "
+                LDA #$20;
+                ADC #$22;
+                STA $10 ;
+                ASL $10 ;
+                LDX $10 ;
+                STX $0300;
+                BRK     ;
+                ",
+            )
+            .unwrap();
+            cpu
+        }
+
+        let mut first = run();
+        let second = run();
+        assert_eq!(first.mem.peek(0x0300), 0x84);
+        assert_eq!(first.state_hash(), second.state_hash());
+
+        first.mem.poke(0x0301, 1);
+        assert_ne!(first.state_hash(), second.state_hash());
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut cpu = Processor::new(None);
+        cpu.state.sp = 0xfd;
+        // 0200: JSR $0210 / 0210: PHA, JSR $0220 / 0220: NOP
+        cpu.mem.load(0x0200, &[0x20, 0x10, 0x02]).unwrap();
+        cpu.mem.load(0x0210, &[0x48, 0x20, 0x20, 0x02]).unwrap();
+        cpu.mem.load(0x0220, &[0xea]).unwrap();
+        cpu.state.pc = 0x0200;
+        assert_eq!(cpu.call_stack(), vec![]);
+
+        for _ in 0..3 {
+            cpu.exec();
+        }
+        assert_eq!(cpu.state.pc, 0x0220);
+        assert_eq!(cpu.call_stack(), vec![0x0214, 0x0203]);
+    }
+
+    #[test]
+    fn test_disassembly_window() {
+        let mut cpu = Processor::new(None);
+        // LDA #$01; STA $10; SEC; ADC #$01; STA $0200; BRK
+        cpu.mem
+            .load(
+                0x8000,
+                &[
+                    0xa9, 0x01, 0x85, 0x10, 0x38, 0x69, 0x01, 0x8d, 0x00, 0x02,
+                    0x00,
+                ],
+            )
+            .unwrap();
+        cpu.state.pc = 0x8004;
+
+        let window = cpu.disassembly_window(2);
+        assert_eq!(
+            window,
+            vec![
+                (0x8000, String::from("LDA #$01"), false),
+                (0x8002, String::from("STA $10"), false),
+                (0x8004, String::from("SEC"), true),
+                (0x8005, String::from("ADC #$01"), false),
+                (0x8007, String::from("STA $0200"), false),
+            ]
+        );
+    }
 }
@@ -0,0 +1,327 @@
+// Harness for Tom Harte's SingleStepTests (github.com/SingleStepTests/65x02)
+//
+// Each `nes6502/v1/<opcode>.json` file holds 10000 cases describing the
+// registers and memory before and after a single instruction, along with
+// every bus cycle it took. The files are large and not vendored, point
+// SINGLE_STEP_TESTS at the `v1` directory to run them. Without the files the
+// test is skipped.
+use super::base::Processor;
+use super::memory::{IO_REGISTERS_TOP, RAM_TOP};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// Opcodes with a real handler in `decode`, extend as the instruction set grows
+const IMPLEMENTED: &[u8] = &[
+    0x00, 0x01, 0x04, 0x05, 0x06, 0x08, 0x09, 0x0a, 0x0c, 0x0d, 0x10, 0x11,
+    0x14, 0x15, 0x18, 0x19, 0x1a, 0x1c, 0x1d, 0x20, 0x21, 0x24, 0x25, 0x28,
+    0x29, 0x2c, 0x2d, 0x30, 0x31, 0x34, 0x35, 0x38, 0x39, 0x3a, 0x3c, 0x3d,
+    0x40, 0x41, 0x44, 0x45, 0x48, 0x49, 0x4c, 0x4d, 0x50, 0x51, 0x54, 0x55,
+    0x58, 0x59, 0x5a, 0x5c, 0x5d, 0x60, 0x61, 0x64, 0x65, 0x68, 0x69, 0x6c,
+    0x6d, 0x70, 0x71, 0x74, 0x75, 0x78, 0x79, 0x7a, 0x7c, 0x7d, 0x80, 0x81,
+    0x82, 0x84, 0x85, 0x88, 0x89, 0x8a, 0x8c, 0x8d, 0x90, 0x91, 0x94, 0x95,
+    0x98, 0x99, 0x9a, 0x9d, 0xa0, 0xa1, 0xa2, 0xa4, 0xa5, 0xa6, 0xa8, 0xa9,
+    0xaa, 0xac, 0xad, 0xae, 0xb0, 0xb1, 0xb4, 0xb5, 0xb6, 0xb8, 0xb9, 0xba,
+    0xbc, 0xbd, 0xbe, 0xc0, 0xc1, 0xc2, 0xc4, 0xc5, 0xc8, 0xc9, 0xca, 0xcc,
+    0xcd, 0xd0, 0xd1, 0xd4, 0xd5, 0xd8, 0xd9, 0xda, 0xdc, 0xdd, 0xe0, 0xe1,
+    0xe2, 0xe4, 0xe5, 0xe8, 0xe9, 0xea, 0xeb, 0xec, 0xed, 0xf0, 0xf1, 0xf4,
+    0xf5, 0xf8, 0xf9, 0xfa, 0xfc, 0xfd,
+];
+
+#[derive(Debug)]
+pub(crate) enum Json {
+    Number(u64),
+    Text(String),
+    List(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+    Bool(bool),
+    Null,
+}
+
+impl Json {
+    pub(crate) fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(map) => &map[key],
+            _ => panic!("Expected an object with key {}", key),
+        }
+    }
+
+    pub(crate) fn number(&self) -> u64 {
+        match self {
+            Json::Number(value) => *value,
+            _ => panic!("Expected a number, got {:?}", self),
+        }
+    }
+
+    fn list(&self) -> &Vec<Json> {
+        match self {
+            Json::List(items) => items,
+            _ => panic!("Expected a list, got {:?}", self),
+        }
+    }
+
+    pub(crate) fn boolean(&self) -> bool {
+        match self {
+            Json::Bool(value) => *value,
+            _ => panic!("Expected a boolean, got {:?}", self),
+        }
+    }
+}
+
+/**
+ * Just enough of a JSON reader for the test files: no floats, no negative
+ * numbers and no escapes beyond skipping the escaped character.
+ */
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len()
+            && self.bytes[self.pos].is_ascii_whitespace()
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) {
+        self.skip_whitespace();
+        assert_eq!(
+            self.bytes[self.pos], byte,
+            "Malformed JSON at {}",
+            self.pos
+        );
+        self.pos += 1;
+    }
+
+    // Consume `byte` if it's next
+    fn accept(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.bytes[self.pos] == byte {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn text(&mut self) -> String {
+        self.expect(b'"');
+        let start = self.pos;
+        while self.bytes[self.pos] != b'"' {
+            if self.bytes[self.pos] == b'\\' {
+                self.pos += 1;
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        String::from_utf8_lossy(&self.bytes[start..self.pos - 1]).into_owned()
+    }
+
+    fn value(&mut self) -> Json {
+        self.skip_whitespace();
+        match self.bytes[self.pos] {
+            b'{' => {
+                self.pos += 1;
+                let mut map = BTreeMap::new();
+                if self.accept(b'}') {
+                    return Json::Object(map);
+                }
+                loop {
+                    let key = self.text();
+                    self.expect(b':');
+                    map.insert(key, self.value());
+                    if !self.accept(b',') {
+                        self.expect(b'}');
+                        return Json::Object(map);
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.accept(b']') {
+                    return Json::List(items);
+                }
+                loop {
+                    items.push(self.value());
+                    if !self.accept(b',') {
+                        self.expect(b']');
+                        return Json::List(items);
+                    }
+                }
+            }
+            b'"' => Json::Text(self.text()),
+            b'0'..=b'9' => {
+                let mut value = 0;
+                while self.bytes[self.pos].is_ascii_digit() {
+                    value = value * 10 + (self.bytes[self.pos] - b'0') as u64;
+                    self.pos += 1;
+                }
+                Json::Number(value)
+            }
+            _ => {
+                let start = self.pos;
+                while self.bytes[self.pos].is_ascii_alphabetic() {
+                    self.pos += 1;
+                }
+                match &self.bytes[start..self.pos] {
+                    b"true" => Json::Bool(true),
+                    b"false" => Json::Bool(false),
+                    _ => Json::Null,
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn parse(bytes: &[u8]) -> Json {
+    Reader { bytes, pos: 0 }.value()
+}
+
+// Memory is not a flat bus: low addresses mirror, PPU, APU and IO registers
+// have side effects
+fn is_flat(address: usize) -> bool {
+    !(RAM_TOP..IO_REGISTERS_TOP).contains(&address)
+}
+
+fn ram(state: &Json) -> Vec<(usize, u8)> {
+    state
+        .get("ram")
+        .list()
+        .iter()
+        .map(|pair| {
+            let pair = pair.list();
+            (pair[0].number() as usize, pair[1].number() as u8)
+        })
+        .collect()
+}
+
+/**
+ * Run a single case returning a description of the first mismatching field
+ */
+fn run_case(cpu: &mut Processor, case: &Json) -> Option<String> {
+    let initial = case.get("initial");
+    let expected = case.get("final");
+    let initial_ram = ram(initial);
+    let final_ram = ram(expected);
+    let cycles = case.get("cycles").list();
+    let touched = cycles.iter().map(|cycle| cycle.list()[0].number() as usize);
+    if !initial_ram
+        .iter()
+        .chain(final_ram.iter())
+        .map(|&(address, _)| address)
+        .chain(touched)
+        .all(is_flat)
+    {
+        return None;
+    }
+
+    cpu.state.pc = initial.get("pc").number() as usize;
+    cpu.state.sp = initial.get("s").number() as u8;
+    cpu.state.a = initial.get("a").number() as u8;
+    cpu.state.x = initial.get("x").number() as u8;
+    cpu.state.y = initial.get("y").number() as u8;
+    cpu.state.status = initial.get("p").number() as u8;
+    cpu.cycles = 0;
+    for &(address, value) in initial_ram.iter() {
+        cpu.mem.ram[address] = value;
+    }
+
+    cpu.exec();
+
+    let registers = [
+        ("pc", cpu.state.pc as u64),
+        ("s", cpu.state.sp as u64),
+        ("a", cpu.state.a as u64),
+        ("x", cpu.state.x as u64),
+        ("y", cpu.state.y as u64),
+        ("p", cpu.state.status as u64),
+    ];
+    let mut mismatch = None;
+    for &(name, actual) in registers.iter() {
+        let wanted = expected.get(name).number();
+        if mismatch.is_none() && actual != wanted {
+            mismatch = Some(format!(
+                "{}: expected {:#04x} got {:#04x}",
+                name, wanted, actual
+            ));
+        }
+    }
+    for &(address, wanted) in final_ram.iter() {
+        let actual = cpu.mem.ram[address];
+        if mismatch.is_none() && actual != wanted {
+            mismatch = Some(format!(
+                "ram[{:#06x}]: expected {:#04x} got {:#04x}",
+                address, wanted, actual
+            ));
+        }
+    }
+    if mismatch.is_none() && cpu.cycles as usize != cycles.len() {
+        mismatch = Some(format!(
+            "cycles: expected {} got {}",
+            cycles.len(),
+            cpu.cycles
+        ));
+    }
+
+    // leave memory clean for the next case
+    for &(address, _) in initial_ram.iter().chain(final_ram.iter()) {
+        cpu.mem.ram[address] = 0;
+    }
+
+    mismatch
+}
+
+#[test]
+fn test_single_step() {
+    let root = std::env::var("SINGLE_STEP_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/nes6502/v1")
+        });
+    if !root.is_dir() {
+        println!("SingleStepTests not found at {:?}, skipping", root);
+        return;
+    }
+
+    let mut cpu = Processor::new(None);
+    for &opcode in IMPLEMENTED.iter() {
+        let path = root.join(format!("{:02x}.json", opcode));
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+
+        for case in parse(&bytes).list() {
+            if let Some(mismatch) = run_case(&mut cpu, case) {
+                let name = match case.get("name") {
+                    Json::Text(name) => name.clone(),
+                    _ => String::new(),
+                };
+                panic!("Opcode {:#04x} case '{}' {}", opcode, name, mismatch);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_single_step_case() {
+    let case = parse(
+        br#"{
+            "name": "a9 01 00",
+            "initial": {
+                "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                "ram": [[512, 169], [513, 1]]
+            },
+            "final": {
+                "pc": 514, "s": 253, "a": 1, "x": 0, "y": 0, "p": 36,
+                "ram": [[512, 169], [513, 1]]
+            },
+            "cycles": [[512, 169, "read"], [513, 1, "read"]]
+        }"#,
+    );
+
+    let mut cpu = Processor::new(None);
+    assert_eq!(run_case(&mut cpu, &case), None);
+}
@@ -1,48 +1,414 @@
+use crate::error::MemoryError;
+use crate::nes::apu::{Apu, APU_STATUS};
+use crate::nes::joypad::Joypad;
+use crate::nes::mapper::Mapper;
+use crate::nes::ppu::{Ppu, OAMDATA};
+
 pub const MEMORY_MAX: usize = 0x10000;
 pub const RAM_TOP: usize = 0x800;
 pub const MIRROR_TOP: usize = 0x2000;
+pub const PPU_REGISTERS_TOP: usize = 0x4000;
 #[allow(dead_code)]
 pub const ZERO_PAGE_TOP: usize = 0x100;
 #[allow(dead_code)]
 pub const STACK_TOP: usize = 0x200;
 #[allow(dead_code)]
+pub const NMI_VECTOR: usize = 0xFFFA;
+#[allow(dead_code)]
 pub const RESET_VECTOR: usize = 0xFFFC;
 #[allow(dead_code)]
 pub const IRQ_BRK_VECTOR: usize = 0xFFFE;
+pub const OAM_DMA: usize = 0x4014;
+pub const JOYPAD_1: usize = 0x4016;
+pub const JOYPAD_2: usize = 0x4017;
+// APU and IO registers end here, $4018-$401F are disabled test registers
+pub const IO_REGISTERS_TOP: usize = 0x4020;
 #[allow(dead_code)]
 pub const ROM_START: usize = 0x8000;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/**
+ * A single bus access, `pc` is the instruction that made it
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: usize,
+    pub value: u8,
+    pub pc: usize,
+}
+
+pub type AccessLog = Box<dyn FnMut(Access)>;
+
+/**
+ * Read and write counts for every address, cheaper than an AccessLog when
+ * only the totals are wanted
+ */
+pub struct AccessProfile {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+}
+
+impl Default for AccessProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessProfile {
+    pub fn new() -> AccessProfile {
+        AccessProfile {
+            reads: vec![0; MEMORY_MAX],
+            writes: vec![0; MEMORY_MAX],
+        }
+    }
+
+    fn count(&mut self, kind: AccessKind, addr: usize) {
+        let counts = match kind {
+            AccessKind::Read => &mut self.reads,
+            AccessKind::Write => &mut self.writes,
+        };
+        counts[addr & 0xFFFF] += 1;
+    }
+
+    /**
+     * Every address accessed as `(address, reads, writes)`, the most
+     * accesses first
+     */
+    pub fn hottest(&self) -> Vec<(usize, u64, u64)> {
+        let mut counts: Vec<(usize, u64, u64)> = (0..MEMORY_MAX)
+            .map(|addr| (addr, self.reads[addr], self.writes[addr]))
+            .filter(|&(_, reads, writes)| reads + writes > 0)
+            .collect();
+        counts.sort_by_key(|&(addr, reads, writes)| {
+            (std::cmp::Reverse(reads + writes), addr)
+        });
+        counts
+    }
+}
+
+/**
+ * Size of the internal RAM and where its mirrors end, the NES has 2KB
+ * repeated up to $2000
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemoryConfig {
+    pub ram_size: usize,
+    pub mirror_top: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            ram_size: RAM_TOP,
+            mirror_top: MIRROR_TOP,
+        }
+    }
+}
+
 pub struct Memory {
     pub ram: [u8; MEMORY_MAX],
+    pub config: MemoryConfig,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub joypads: [Joypad; 2],
+    pub mapper: Option<Box<dyn Mapper>>,
+    pub access_log: Option<AccessLog>,
+    pub profile: Option<AccessProfile>,
+    // PC of the executing instruction, set by the CPU for the access log
+    pub pc: usize,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Memory {
+        Memory::with_config(MemoryConfig::default())
+    }
+
+    /**
+     * Memory with `ram_size` bytes of RAM mirrored up to `mirror_top`, the
+     * PPU registers take over from there
+     */
+    pub fn new_with_config(
+        config: MemoryConfig,
+    ) -> Result<Memory, MemoryError> {
+        if config.ram_size == 0 {
+            return Err(MemoryError::EmptyRam);
+        }
+        Ok(Memory::with_config(config))
+    }
+
+    fn with_config(config: MemoryConfig) -> Memory {
         Memory {
             ram: [0; MEMORY_MAX],
+            config,
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            joypads: [Joypad::new(); 2],
+            mapper: None,
+            access_log: None,
+            profile: None,
+            pc: 0,
         }
     }
 
     pub fn write(&mut self, address: usize, value: u8) {
+        self.log(AccessKind::Write, address, value);
+        self.write_bus(address, value);
+    }
+
+    pub fn read(&mut self, address: usize) -> u8 {
+        let value = self.read_bus(address);
+        self.log(AccessKind::Read, address, value);
+        value
+    }
+
+    fn log(&mut self, kind: AccessKind, addr: usize, value: u8) {
+        let pc = self.pc;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.count(kind, addr);
+        }
+        if let Some(log) = self.access_log.as_mut() {
+            log(Access {
+                kind,
+                addr,
+                value,
+                pc,
+            });
+        }
+    }
+
+    fn write_bus(&mut self, address: usize, value: u8) {
+        if address < self.config.mirror_top {
+            self.ram[address % self.config.ram_size] = value;
+            return;
+        }
+        if address < PPU_REGISTERS_TOP {
+            self.ppu.write_register(address, value);
+            return;
+        }
+        if (PPU_REGISTERS_TOP..IO_REGISTERS_TOP).contains(&address) {
+            match address {
+                // The strobe line is shared by both controller ports
+                JOYPAD_1 => {
+                    for joypad in self.joypads.iter_mut() {
+                        joypad.write(value);
+                    }
+                }
+                OAM_DMA => self.oam_dma(value),
+                // $4017 writes go to the APU frame counter
+                _ => self.apu.write_register(address, value),
+            }
+            return;
+        }
+        if address >= ROM_START {
+            // ROM can't be written, on most boards these are mapper registers
+            if let Some(mapper) = self.mapper.as_mut() {
+                mapper.cpu_write(address, value);
+                return;
+            }
+        }
+        self.ram[address] = value;
+    }
+
+    fn read_bus(&mut self, address: usize) -> u8 {
+        if address < self.config.mirror_top {
+            return self.ram[address % self.config.ram_size];
+        }
+        if address < PPU_REGISTERS_TOP {
+            return self.ppu.read_register(address);
+        }
+        if address < IO_REGISTERS_TOP {
+            return match address {
+                APU_STATUS => self.apu.read_status(),
+                JOYPAD_1 => self.joypads[0].read(),
+                JOYPAD_2 => self.joypads[1].read(),
+                // Write-only, the bus still holds the high byte of the address
+                _ => (address >> 8) as u8,
+            };
+        }
+        if address >= ROM_START {
+            if let Some(value) =
+                self.mapper.as_ref().and_then(|m| m.cpu_read(address))
+            {
+                return value;
+            }
+        }
+        self.ram[address]
+    }
+
+    /**
+     * Run the PPU for `cycles` CPU cycles, the mapper sees the addresses
+     * rendering fetched on the way
+     */
+    pub fn step_ppu(&mut self, cycles: u32) {
+        self.ppu.step_cpu(cycles);
+        self.forward_ppu_fetches();
+    }
+
+    /**
+     * step_ppu counted in PPU dots
+     */
+    pub fn step_ppu_dots(&mut self, dots: u32) {
+        self.ppu.step(dots);
+        self.forward_ppu_fetches();
+    }
+
+    fn forward_ppu_fetches(&mut self) {
+        let fetches = self.ppu.take_fetches();
+        if let Some(mapper) = self.mapper.as_mut() {
+            for address in fetches {
+                mapper.on_ppu_address(address);
+            }
+        }
+    }
+
+    /**
+     * Copy the 256 byte page `page` into OAM through OAMDATA. The CPU stall
+     * the copy costs on hardware isn't modelled.
+     */
+    fn oam_dma(&mut self, page: u8) {
+        let start = (page as usize) << 8;
+        for address in start..start + 0x100 {
+            let value = self.read_bus(address);
+            self.ppu.write_register(OAMDATA, value);
+        }
+    }
+
+    /**
+     * Memory seeded with (address, value) pairs. RAM mirrors fold onto
+     * internal RAM, everything else is stored without side effects.
+     */
+    pub fn from_pairs(pairs: &[(usize, u8)]) -> Memory {
+        pairs
+            .iter()
+            .fold(MemoryBuilder::new(), |builder, &(address, value)| {
+                builder.byte(address, value)
+            })
+            .build()
+    }
+
+    /**
+     * Raw access to the backing array for tests and debugging, no RAM
+     * mirroring and no register side effects
+     */
+    pub fn poke(&mut self, address: usize, value: u8) {
         self.ram[address] = value;
     }
 
-    pub fn read(&self, address: usize) -> u8 {
-        if address < MIRROR_TOP {
-            return self.ram[address % RAM_TOP];
+    pub fn peek(&self, address: usize) -> u8 {
+        self.ram[address]
+    }
+
+    /**
+     * peek with ROM read through the mapper, so the disassembler and tracers
+     * see the banks the CPU does. Still no register side effects.
+     */
+    pub fn peek_mapped(&self, address: usize) -> u8 {
+        if address >= ROM_START {
+            if let Some(value) =
+                self.mapper.as_ref().and_then(|m| m.cpu_read(address))
+            {
+                return value;
+            }
         }
         self.ram[address]
     }
 
-    pub fn load(&mut self, address: usize, data: &[u8]) {
+    /**
+     * `len` bytes of the backing array from `start` for bulk reads, like
+     * peek there's no mirroring or mapper. Cut short at the end of memory.
+     */
+    pub fn slice(&self, start: usize, len: usize) -> &[u8] {
+        let end = (start + len).min(MEMORY_MAX);
+        &self.ram[start.min(end)..end]
+    }
+
+    // 16 bit values are stored little-endian, low byte first
+    pub fn read_u16(&mut self, address: usize) -> usize {
+        let low = self.read(address) as usize;
+        let high = self.read(address + 1) as usize;
+        low | (high << 8)
+    }
+
+    /**
+     * Point an interrupt vector (RESET_VECTOR etc) at `target`
+     */
+    pub fn set_vector(&mut self, vector: usize, target: usize) {
+        self.write(vector, (target & 0xFF) as u8);
+        self.write(vector + 1, ((target & 0xFF00) >> 8) as u8);
+    }
+
+    pub fn load(
+        &mut self,
+        address: usize,
+        data: &[u8],
+    ) -> Result<(), MemoryError> {
+        if address + data.len() > MEMORY_MAX {
+            return Err(MemoryError::OutOfRange {
+                address,
+                len: data.len(),
+            });
+        }
         self.ram[address..address + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/**
+ * Fluent setup for test memory, eg.
+ * `MemoryBuilder::new().bytes(0x8000, &[0xa9, 0x01]).build()`
+ */
+#[derive(Default)]
+pub struct MemoryBuilder {
+    mem: Memory,
+}
+
+impl MemoryBuilder {
+    pub fn new() -> MemoryBuilder {
+        MemoryBuilder::default()
+    }
+
+    pub fn byte(mut self, address: usize, value: u8) -> MemoryBuilder {
+        let config = self.mem.config;
+        let address = if address < config.mirror_top {
+            address % config.ram_size
+        } else {
+            address
+        };
+        self.mem.poke(address, value);
+        self
+    }
+
+    pub fn bytes(self, address: usize, data: &[u8]) -> MemoryBuilder {
+        data.iter().enumerate().fold(self, |builder, (i, &value)| {
+            builder.byte(address + i, value)
+        })
+    }
+
+    pub fn vector(self, vector: usize, target: usize) -> MemoryBuilder {
+        self.bytes(vector, &[(target & 0xFF) as u8, (target >> 8) as u8])
+    }
+
+    pub fn build(self) -> Memory {
+        self.mem
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::nes::ppu::PPUCTRL;
 
     #[test]
     fn test_memory() {
@@ -53,5 +419,154 @@ mod test {
         assert_eq!(mem.read(0x800), 24);
         assert_eq!(mem.read(0x800 * 2), 24);
         assert_eq!(mem.read(0x800 * 3), 24);
+
+        // PPU registers are not memory
+        mem.write(0x2000, 0x80);
+        assert_eq!(mem.ppu.ctrl, 0x80);
+        assert_eq!(mem.ram[0x2000], 0);
+
+        assert_eq!(
+            mem.load(0xFFFF, &[1, 2]),
+            Err(MemoryError::OutOfRange {
+                address: 0xFFFF,
+                len: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_poke() {
+        let mut mem = Memory::new();
+        mem.poke(0x0800, 0x42);
+        assert_eq!(mem.peek(0x0800), 0x42);
+        assert_eq!(mem.read(0x0800), 0, "reads mirror ram[0]");
+
+        // no PPU register write
+        mem.poke(PPUCTRL, 0x80);
+        assert_eq!(mem.ppu.ctrl, 0);
+        assert_eq!(mem.peek(PPUCTRL), 0x80);
+    }
+
+    #[test]
+    fn test_slice() {
+        let mut mem = Memory::new();
+        mem.load(0xC000, &[0x4c, 0xf5, 0xc5]).unwrap();
+        let bytes: Vec<u8> = (0xC000..0xC003).map(|a| mem.peek(a)).collect();
+        assert_eq!(mem.slice(0xC000, 3), &bytes[..]);
+        assert_eq!(mem.slice(0xFFFE, 4).len(), 2);
+    }
+
+    #[test]
+    fn test_from_pairs() {
+        let mut mem = Memory::from_pairs(&[(0x0010, 0x42), (0x0801, 0x24)]);
+        assert_eq!(mem.read(0x0010), 0x42);
+        assert_eq!(mem.read(0x1810), 0x42, "mirrors see the seeded byte");
+        assert_eq!(mem.read(0x0001), 0x24, "seeding a mirror folds");
+
+        let mut mem = MemoryBuilder::new()
+            .bytes(0x8000, &[0xa9, 0x01])
+            .vector(RESET_VECTOR, 0x8000)
+            .build();
+        assert_eq!(mem.read(0x8001), 0x01);
+        assert_eq!(mem.read_u16(RESET_VECTOR), 0x8000);
+
+        // writes to a mirror land in internal RAM as well
+        mem.write(0x0810, 0x99);
+        assert_eq!(mem.read(0x0010), 0x99);
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut mem = Memory::new();
+        mem.set_vector(RESET_VECTOR, 0xC000);
+
+        assert_eq!(mem.read(RESET_VECTOR), 0x00);
+        assert_eq!(mem.read(RESET_VECTOR + 1), 0xC0);
+        assert_eq!(mem.read_u16(RESET_VECTOR), 0xC000);
+    }
+
+    #[test]
+    fn test_memory_config() {
+        let mut mem = Memory::new_with_config(MemoryConfig {
+            ram_size: 0x1000,
+            ..MemoryConfig::default()
+        })
+        .unwrap();
+        mem.write(0x0801, 0x42);
+        assert_eq!(mem.read(0x0801), 0x42);
+        assert_eq!(mem.read(0x0001), 0x00, "2KB doesn't mirror any more");
+        assert_eq!(mem.read(0x1801), 0x42, "mirrored every 4KB");
+
+        // Reads and writes agree the PPU registers start at mirror_top
+        let mut mem = Memory::new_with_config(MemoryConfig {
+            mirror_top: 0x1000,
+            ..MemoryConfig::default()
+        })
+        .unwrap();
+        mem.write(0x1000, 0x80);
+        assert_eq!(mem.ppu.ctrl, 0x80);
+        assert_eq!(mem.peek(0x1000), 0x00, "the write didn't reach RAM");
+        mem.write(0x0801, 0x42);
+        assert_eq!(mem.read(0x0001), 0x42);
+
+        assert!(matches!(
+            Memory::new_with_config(MemoryConfig {
+                ram_size: 0,
+                ..MemoryConfig::default()
+            }),
+            Err(MemoryError::EmptyRam)
+        ));
+
+        let mut mem = Memory::new();
+        mem.write(0x0801, 0x42);
+        assert_eq!(mem.read(0x0001), 0x42);
+    }
+
+    #[test]
+    fn test_joypads() {
+        let mut mem = Memory::new();
+        mem.joypads[0].buttons = 0b1000_0101;
+        mem.joypads[1].buttons = 0b0110_0010;
+
+        // strobe both controllers through $4016
+        mem.write(JOYPAD_1, 1);
+        mem.write(JOYPAD_1, 0);
+
+        let mut player_1 = 0;
+        let mut player_2 = 0;
+        for i in 0..8 {
+            let bit_1 = mem.read(JOYPAD_1);
+            let bit_2 = mem.read(JOYPAD_2);
+            assert_eq!(bit_1 & 0xe0, 0x40, "open bus bits on $4016");
+            assert_eq!(bit_2 & 0xe0, 0x40, "open bus bits on $4017");
+            player_1 |= (bit_1 & 1) << i;
+            player_2 |= (bit_2 & 1) << i;
+        }
+
+        assert_eq!(player_1, 0b1000_0101);
+        assert_eq!(player_2, 0b0110_0010);
+        assert_eq!(mem.read(JOYPAD_1) & 1, 1, "reads past 8 return 1");
+    }
+
+    #[test]
+    fn test_io_registers() {
+        let mut mem = Memory::new();
+        // enable pulse 1 and load its length counter
+        mem.write(APU_STATUS, 0b0000_0001);
+        mem.write(0x4003, 0xf8);
+        assert_eq!(mem.read(APU_STATUS), 0b0000_0001, "status, not the write");
+
+        mem.write(0x4000, 0xff);
+        assert_eq!(mem.read(APU_STATUS), 0b0000_0001);
+        assert_eq!(mem.read(0x4000), 0x40, "write-only reads open bus");
+        assert_eq!(mem.ram[0x4000], 0, "registers aren't RAM");
+
+        // OAM DMA copies a page through OAMDATA
+        for i in 0..0x100 {
+            mem.write(0x0300 + i, i as u8);
+        }
+        mem.write(OAM_DMA, 0x03);
+        assert_eq!(mem.ppu.oam[0x00], 0x00);
+        assert_eq!(mem.ppu.oam[0xff], 0xff);
     }
 }
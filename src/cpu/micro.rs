@@ -0,0 +1,427 @@
+// Cycle at a time execution for `tick`, every micro-op is one bus access.
+// Reads, stores and read-modify-writes fetch their operand address a cycle at
+// a time and finish with the instruction's handler working on the latched
+// address, the handler making that cycle's access. Pushes and pulls end the
+// same way. JSR, RTS, RTI, BRK, JMP, the branches and interrupts have
+// sequences of their own. JAM still runs in full on its first cycle.
+use super::addressing::Mode;
+use super::base::{
+    Processor, Reg, B_FLAG, C_FLAG, I_FLAG, N_FLAG, V_FLAG, Z_FLAG,
+};
+use super::memory::{IRQ_BRK_VECTOR, NMI_VECTOR};
+use super::opcodes::is_jam;
+
+/**
+ * The bus cycle after the opcode fetch
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MicroOp {
+    // Operand bytes after the opcode into the address latch
+    FetchZeroPage,
+    FetchAddressLow,
+    FetchAddressHigh,
+    // Add an index register to the zero page address, reading it unindexed
+    IndexZeroPage(Reg),
+    // Add an index register to the absolute address, reading it before the
+    // carry into the high byte. Reads without a carry skip the cycle.
+    IndexAbsolute(Reg),
+    IndexAbsoluteRead(Reg),
+    // Pointer bytes at the latch, the high byte never carries over a page
+    PointerLow,
+    PointerHigh,
+    // Read-modify-write operand, then the unmodified value written back
+    ReadData,
+    DummyWrite,
+    // The handler makes this cycle's access
+    Execute(u8),
+    // The handler works on registers, the CPU reads the byte after the opcode
+    ExecuteImplied(u8),
+    DummyRead,
+    ReadStack,
+    // Push PC plus an offset, the address to return to
+    PushPcHigh(u8),
+    PushPcLow(u8),
+    // Push the status, with B for BRK
+    PushStatus(bool),
+    PullStatus,
+    PullPcLow,
+    PullPcHigh,
+    // RTI's last pull, jumping to the pulled address
+    PullReturn,
+    // RTS reads the pulled address and returns past it
+    Return,
+    JumpAbsolute,
+    JumpIndirect,
+    // An interrupt reads at PC twice without moving it
+    InterruptRead,
+    VectorLow(usize),
+    VectorHigh,
+    // Branch operand, then the taken branch and its page fix-up
+    Branch(u8),
+    BranchTaken,
+    BranchFix,
+}
+
+use MicroOp::*;
+
+/**
+ * The flag a branch tests and the value it branches on
+ */
+fn branch_taken(opcode: u8, status: u8) -> bool {
+    let flag = [N_FLAG, V_FLAG, C_FLAG, Z_FLAG][(opcode >> 6) as usize];
+    (status & flag != 0) == (opcode & 0x20 != 0)
+}
+
+fn address_ops(mode: Mode, write: bool) -> Vec<MicroOp> {
+    let index = |reg| {
+        if write {
+            IndexAbsolute(reg)
+        } else {
+            IndexAbsoluteRead(reg)
+        }
+    };
+    match mode {
+        Mode::ZeroPage => vec![FetchZeroPage],
+        Mode::ZeroPageX => vec![FetchZeroPage, IndexZeroPage(Reg::X)],
+        Mode::ZeroPageY => vec![FetchZeroPage, IndexZeroPage(Reg::Y)],
+        Mode::Absolute => vec![FetchAddressLow, FetchAddressHigh],
+        Mode::AbsoluteX => {
+            vec![FetchAddressLow, FetchAddressHigh, index(Reg::X)]
+        }
+        Mode::AbsoluteY => {
+            vec![FetchAddressLow, FetchAddressHigh, index(Reg::Y)]
+        }
+        Mode::IndexedX => vec![
+            FetchZeroPage,
+            IndexZeroPage(Reg::X),
+            PointerLow,
+            PointerHigh,
+        ],
+        Mode::IndexedY => {
+            vec![FetchZeroPage, PointerLow, PointerHigh, index(Reg::Y)]
+        }
+        // Immediate operands are read at the latch's starting point
+        _ => Vec::new(),
+    }
+}
+
+impl Processor {
+    /**
+     * The bus cycles following the fetch of `opcode`, None for JAM which runs
+     * in full on its first cycle
+     */
+    pub fn micro_ops(&self, opcode: u8) -> Option<Vec<MicroOp>> {
+        let ops = match opcode {
+            0x00 => vec![
+                DummyRead,
+                PushPcHigh(2),
+                PushPcLow(2),
+                PushStatus(true),
+                VectorLow(IRQ_BRK_VECTOR),
+                VectorHigh,
+            ],
+            0x20 => vec![
+                FetchAddressLow,
+                ReadStack,
+                PushPcHigh(2),
+                PushPcLow(2),
+                JumpAbsolute,
+            ],
+            0x40 => {
+                vec![DummyRead, ReadStack, PullStatus, PullPcLow, PullReturn]
+            }
+            0x60 => vec![DummyRead, ReadStack, PullPcLow, PullPcHigh, Return],
+            0x4c => vec![FetchAddressLow, JumpAbsolute],
+            0x6c => vec![
+                FetchAddressLow,
+                FetchAddressHigh,
+                PointerLow,
+                JumpIndirect,
+            ],
+            0x08 | 0x48 => vec![DummyRead, Execute(opcode)],
+            0x28 | 0x68 => vec![DummyRead, ReadStack, Execute(opcode)],
+            op if op & 0x1f == 0x10 => vec![Branch(op)],
+            // JAM locks up the CPU
+            op if is_jam(op) => return None,
+            _ => {
+                let (_, mode) = self.decode(opcode);
+                // Same layout as decode: STA, STX & STY are row 4, the
+                // shifts, INC, DEC & DCP columns 2 and 3 outside the load and
+                // store rows
+                let a = opcode >> 5;
+                let c = opcode & 0b11;
+                let modify = c >= 2 && a != 4 && a != 5;
+                let mut ops = address_ops(mode, modify || a == 4);
+                match mode {
+                    Mode::Implied | Mode::Accumulator => {
+                        ops.push(ExecuteImplied(opcode))
+                    }
+                    Mode::Immediate => ops.push(Execute(opcode)),
+                    _ if modify => {
+                        ops.extend(&[ReadData, DummyWrite, Execute(opcode)])
+                    }
+                    _ => ops.push(Execute(opcode)),
+                }
+                ops
+            }
+        };
+        Some(ops)
+    }
+
+    /**
+     * Queue the interrupt through `vector` and run its first cycle, in place
+     * of the opcode fetch
+     */
+    pub fn start_interrupt(&mut self, vector: usize) {
+        self.micro_ops.extend(&[
+            InterruptRead,
+            PushPcHigh(0),
+            PushPcLow(0),
+            PushStatus(false),
+            VectorLow(vector),
+            VectorHigh,
+        ]);
+        self.run_micro_op(InterruptRead);
+    }
+
+    // The handler's cycles are counted by the micro-ops leading up to it
+    fn execute_latched(&mut self, opcode: u8) {
+        let (handler, mode) = self.decode(opcode);
+        let cycles = self.cycles;
+        self.latched = true;
+        handler(self, mode);
+        self.latched = false;
+        self.cycles = cycles;
+    }
+
+    /**
+     * Run a single bus cycle
+     */
+    pub fn run_micro_op(&mut self, op: MicroOp) {
+        let pc = self.state.pc;
+        let latch = self.address_latch as usize;
+        match op {
+            FetchZeroPage | FetchAddressLow => {
+                self.address_latch = self.mem.read((pc + 1) & 0xFFFF) as u16;
+            }
+            FetchAddressHigh => {
+                let high = self.mem.read((pc + 2) & 0xFFFF) as u16;
+                self.address_latch |= high << 8;
+            }
+            IndexZeroPage(reg) => {
+                self.mem.read(latch);
+                let index = self.get_reg(reg) as usize;
+                self.address_latch = ((latch + index) & 0xFF) as u16;
+            }
+            IndexAbsolute(reg) | IndexAbsoluteRead(reg) => {
+                let address = (latch + self.get_reg(reg) as usize) & 0xFFFF;
+                self.address_latch = address as u16;
+                if op == IndexAbsoluteRead(reg)
+                    && address & 0xFF00 == latch & 0xFF00
+                {
+                    // no carry, this cycle's read is the operand
+                    if let Some(next) = self.micro_ops.pop_front() {
+                        return self.run_micro_op(next);
+                    }
+                }
+                self.mem.read((latch & 0xFF00) | (address & 0xFF));
+            }
+            PointerLow => self.data_latch = self.mem.read(latch),
+            PointerHigh | JumpIndirect => {
+                let high =
+                    self.mem.read((latch & 0xFF00) | ((latch + 1) & 0xFF));
+                let address = (high as usize) << 8 | self.data_latch as usize;
+                self.address_latch = address as u16;
+                if op == JumpIndirect {
+                    self.jump(address);
+                }
+            }
+            ReadData => self.data_latch = self.mem.read(latch),
+            DummyWrite => self.mem.write(latch, self.data_latch),
+            Execute(opcode) => self.execute_latched(opcode),
+            ExecuteImplied(opcode) => {
+                self.mem.read((pc + 1) & 0xFFFF);
+                self.execute_latched(opcode);
+            }
+            DummyRead => {
+                self.mem.read((pc + 1) & 0xFFFF);
+            }
+            ReadStack => {
+                self.mem.read(self.stack_top());
+            }
+            PushPcHigh(offset) => {
+                self.stack_push((((pc + offset as usize) & 0xFFFF) >> 8) as u8)
+            }
+            PushPcLow(offset) => {
+                self.stack_push(((pc + offset as usize) & 0xFF) as u8)
+            }
+            PushStatus(brk) => {
+                // Hardware interrupts push the status with the B flag clear
+                let status = self.get_status() & !B_FLAG;
+                self.stack_push(if brk { status | B_FLAG } else { status });
+            }
+            PullStatus => {
+                let status = self.stack_pop();
+                self.set_status_from_stack(status);
+            }
+            PullPcLow => self.address_latch = self.stack_pop() as u16,
+            PullPcHigh => self.address_latch |= (self.stack_pop() as u16) << 8,
+            PullReturn => {
+                let high = self.stack_pop() as usize;
+                self.jump(high << 8 | latch);
+            }
+            Return => {
+                self.mem.read(latch);
+                self.jump(latch + 1);
+            }
+            JumpAbsolute => {
+                let high = self.mem.read((pc + 2) & 0xFFFF) as usize;
+                self.jump(high << 8 | latch);
+            }
+            InterruptRead => {
+                self.mem.read(pc);
+            }
+            VectorLow(vector) => {
+                // An NMI landing before the vector fetch hijacks a BRK or an
+                // IRQ, it vectors through $FFFA instead
+                let vector = match self.nmi_at {
+                    Some(at)
+                        if vector == IRQ_BRK_VECTOR
+                            && self.cycles as u64 >= at =>
+                    {
+                        self.nmi_at = None;
+                        NMI_VECTOR
+                    }
+                    _ => vector,
+                };
+                self.data_latch = self.mem.read(vector);
+                self.address_latch = vector as u16;
+                self.state.status |= I_FLAG;
+            }
+            VectorHigh => {
+                let high = self.mem.read(latch + 1) as usize;
+                self.jump(high << 8 | self.data_latch as usize);
+            }
+            Branch(opcode) => {
+                let offset = self.mem.read((pc + 1) & 0xFFFF) as i8;
+                let next = (pc + 2) & 0xFFFF;
+                self.jump(next);
+                if branch_taken(opcode, self.state.status) {
+                    let target = (next as u16).wrapping_add(offset as u16);
+                    self.address_latch = target;
+                    self.micro_ops.push_front(BranchTaken);
+                }
+            }
+            BranchTaken => {
+                self.mem.read(pc);
+                // the low byte lands first, a page crossing fixes up PCH next
+                if latch & 0xFF00 != pc & 0xFF00 {
+                    self.jump((pc & 0xFF00) | (latch & 0xFF));
+                    self.micro_ops.push_front(BranchFix);
+                } else {
+                    self.jump(latch);
+                }
+            }
+            BranchFix => {
+                self.mem.read(pc);
+                self.jump(latch);
+            }
+        }
+        self.cycles += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::RAM_TOP;
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn machine(opcode: u8, operand: u8, x: u8, y: u8, status: u8) -> Processor {
+        let mut cpu = Processor::new(None);
+        // small values keep every pointer in RAM
+        for address in 0..RAM_TOP {
+            cpu.mem.poke(address, (address * 37 + 11) as u8 & 0x07);
+        }
+        cpu.mem.poke(0x10, 0xf0);
+        cpu.mem.poke(0x11, 0x03);
+        cpu.mem.load(0x0200, &[opcode, operand, 0x02]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.state.sp = 0xfd;
+        cpu.state.x = x;
+        cpu.state.y = y;
+        cpu.state.a = 0x5a;
+        cpu.state.status = status;
+        cpu
+    }
+
+    #[test]
+    fn test_branch_taken() {
+        // BPL BMI BVC BVS BCC BCS BNE BEQ
+        let branches = [0x10, 0x30, 0x50, 0x70, 0x90, 0xb0, 0xd0, 0xf0];
+        let flags = [N_FLAG, V_FLAG, C_FLAG, Z_FLAG];
+        for (i, &opcode) in branches.iter().enumerate() {
+            let flag = flags[i / 2];
+            let on_set = i % 2 == 1;
+            assert_eq!(branch_taken(opcode, flag), on_set, "{:#04x}", opcode);
+            assert_eq!(branch_taken(opcode, !flag), !on_set, "{:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_tick_matches_exec() {
+        // operand, X, Y and status: no page crossings, crossings and the
+        // branches going the other way
+        let cases = [
+            (0x10, 0x01, 0x01, 0x24),
+            (0xf0, 0x20, 0x20, 0xef),
+            (0x80, 0xf8, 0xf8, 0xe7),
+        ];
+        for opcode in 0..=0xffu8 {
+            for &(operand, x, y, status) in cases.iter() {
+                let mut cpu = machine(opcode, operand, x, y, status);
+                if cpu.micro_ops(opcode).is_none() {
+                    continue;
+                }
+                let mut expected = machine(opcode, operand, x, y, status);
+                expected.exec();
+
+                let accesses = Rc::new(RefCell::new(0));
+                let count = Rc::clone(&accesses);
+                cpu.set_access_log(Some(Box::new(move |_| {
+                    *count.borrow_mut() += 1
+                })));
+                let case = format!("{:#04x} {:02x?}", opcode, (operand, x, y));
+                loop {
+                    *accesses.borrow_mut() = 0;
+                    cpu.tick();
+                    assert_eq!(*accesses.borrow(), 1, "{} one access", case);
+                    if cpu.micro_ops.is_empty() {
+                        break;
+                    }
+                }
+                assert_eq!(cpu.cycles, expected.cycles, "{} cycles", case);
+                assert_eq!(cpu.state.pc, expected.state.pc, "{} PC", case);
+                assert_eq!(cpu.state.a, expected.state.a, "{} A", case);
+                assert_eq!(cpu.state.x, expected.state.x, "{} X", case);
+                assert_eq!(cpu.state.y, expected.state.y, "{} Y", case);
+                assert_eq!(cpu.state.sp, expected.state.sp, "{} SP", case);
+                assert_eq!(
+                    cpu.state.status, expected.state.status,
+                    "{} status",
+                    case
+                );
+                for address in 0..RAM_TOP {
+                    assert_eq!(
+                        cpu.mem.peek(address),
+                        expected.mem.peek(address),
+                        "{} at {:#06x}",
+                        case,
+                        address
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,54 +1,214 @@
 mod addressing;
 pub mod base;
+mod debug;
+#[cfg(test)]
+pub(crate) mod harte;
 pub mod memory;
+mod micro;
 mod opcodes;
 
-use base::Processor;
-use memory::{RESET_VECTOR, ROM_START};
-use opcodes::encode;
+use crate::error::RustyNesError;
+use addressing::Mode;
+use base::{Processor, B_FLAG, I_FLAG};
+pub use debug::{ExecutedInstruction, NestestTracer, Tracer};
+use memory::{AccessLog, IRQ_BRK_VECTOR, NMI_VECTOR, RESET_VECTOR, ROM_START};
+pub use micro::MicroOp;
+pub use opcodes::{assemble, assemble_with_entry};
+use opcodes::{changes_flow, opcode_len};
+
+// Where run_subroutine leaves PC once the subroutine returns
+pub const SUBROUTINE_SENTINEL: usize = 0xFFFF;
 
 impl Processor {
     pub fn reset(&mut self) {
-        let lower = self.mem.read(RESET_VECTOR) as usize;
-        let upper = self.mem.read(RESET_VECTOR + 1) as usize;
-        self.state.pc = lower | (upper << 8);
+        self.state.pc = self.mem.read_u16(RESET_VECTOR);
 
         self.state.sp = 0xff;
     }
 
     pub fn exec(&mut self) {
+        let elapsed = self.execute();
+        self.step_ppu(elapsed);
+    }
+
+    // Catch the PPU up on `cycles` and take the NMI it raised on the way
+    fn step_ppu(&mut self, cycles: u32) {
+        self.mem.step_ppu(cycles);
+        if self.mem.ppu.take_nmi() {
+            let start = self.cycles;
+            self.nmi();
+            self.mem.step_ppu(self.cycles.wrapping_sub(start));
+        }
+    }
+
+    /**
+     * Advance the CPU a single bus cycle, leaving the PPU to the caller.
+     * Interrupts are taken in place of the next opcode fetch. JAM runs in
+     * full on its first cycle and the CPU idles through the rest of it.
+     */
+    pub fn tick(&mut self) {
+        if self.busy > 0 {
+            self.busy -= 1;
+            return;
+        }
+        if let Some(op) = self.micro_ops.pop_front() {
+            return self.run_micro_op(op);
+        }
+        if let Some(vector) = self.pending_interrupt() {
+            return self.start_interrupt(vector);
+        }
+
+        let start = self.cycles;
+        self.mem.pc = self.state.pc;
+        let value = self.mem.read(self.state.pc);
+        match self.micro_ops(value) {
+            Some(ops) => {
+                self.before_instruction(value);
+                self.micro_ops.extend(ops);
+                // immediate operands are read from here
+                self.address_latch = ((self.state.pc + 1) & 0xFFFF) as u16;
+                self.cycles += 1;
+            }
+            None => {
+                let elapsed = self.execute_opcode(value, start);
+                self.busy = elapsed.max(1) - 1;
+            }
+        }
+    }
+
+    // Run one instruction and any interrupt it raised, returning the cycles
+    fn execute(&mut self) -> u32 {
+        let start = self.cycles;
+        self.mem.pc = self.state.pc;
         let value = self.mem.read(self.state.pc);
+        self.execute_opcode(value, start)
+    }
+
+    // Trace buffer, trace output and the tracer see the CPU before `value`
+    fn before_instruction(&mut self, value: u8) {
+        self.instruction_count += 1;
+        self.record_trace(value);
+        if self.trace {
+            println!("{}", self.trace_line());
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.on_instruction(self, &self.mem);
+            self.tracer = Some(tracer);
+        }
+    }
+
+    // execute once the opcode byte has been fetched
+    fn execute_opcode(&mut self, value: u8, start: u32) -> u32 {
         let (opcode, mode) = self.decode(value);
-        // let start = self.state.pc;
-        // let end = start + opcode_len(mode) as usize;
-        // let full = &self.mem.ram[start..end];
-        println!("{:#04x}: {:#04x}", self.state.pc, value);
+        self.before_instruction(value);
+        let pc = self.state.pc;
         opcode(self, mode);
+        // Catch handlers that step PC by the wrong length for their mode,
+        // debug builds only
+        debug_assert!(
+            self.fault.is_some()
+                || changes_flow(value)
+                || self.state.pc == (pc + opcode_len(mode) as usize) & 0xFFFF,
+            "{:#04x} at {:#06x} left PC at {:#06x}",
+            value,
+            pc,
+            self.state.pc
+        );
+        // Every instruction takes at least two cycles, fewer means one of
+        // lookup/the handler lost its share
+        let elapsed = self.cycles.wrapping_sub(start);
+        debug_assert!(
+            self.fault.is_some() || elapsed >= 2,
+            "{:#04x} at {:#06x} took {} cycles",
+            value,
+            pc,
+            elapsed
+        );
+        if let Some(vector) = self.pending_interrupt() {
+            self.interrupt(vector);
+        }
+        self.cycles.wrapping_sub(start)
+    }
+
+    /**
+     * Install a callback that sees every memory read and write
+     */
+    pub fn set_access_log(&mut self, log: Option<AccessLog>) {
+        self.mem.access_log = log;
+    }
+
+    /**
+     * Install a tracer that sees the CPU before every instruction runs
+     */
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
     }
 
-    pub fn run_program(&mut self, text: &String) {
-        let lines = text.trim().lines();
-        let mut program: Vec<u8> = Vec::new();
-        for line in lines {
-            program.append(&mut encode(&String::from(line.trim())));
+    /**
+     * Schedule a non-maskable interrupt to fire once the cycle counter
+     * reaches `cycle`, it's taken at the end of that instruction
+     */
+    pub fn schedule_nmi_at(&mut self, cycle: u64) {
+        self.nmi_at = Some(cycle);
+    }
+
+    // The interrupt due before the next instruction, NMI first
+    fn pending_interrupt(&mut self) -> Option<usize> {
+        if let Some(at) = self.nmi_at {
+            if self.cycles as u64 >= at {
+                self.nmi_at = None;
+                return Some(NMI_VECTOR);
+            }
         }
+        let pending = match self.mem.mapper.as_ref() {
+            Some(mapper) => mapper.irq_pending(),
+            None => false,
+        };
+        if pending && self.state.status & I_FLAG == 0 {
+            return Some(IRQ_BRK_VECTOR);
+        }
+        None
+    }
 
-        let reset_vector =
-            [(ROM_START & 0xFF) as u8, ((ROM_START & 0xFF00) >> 8) as u8];
+    pub fn nmi(&mut self) {
+        self.interrupt(NMI_VECTOR);
+    }
+
+    pub fn irq(&mut self) {
+        self.interrupt(IRQ_BRK_VECTOR);
+    }
+
+    fn interrupt(&mut self, vector: usize) {
+        let pch = (self.state.pc >> 8) as u8;
+        let pcl = (self.state.pc & 0xFF) as u8;
+        self.stack_push(pch);
+        self.stack_push(pcl);
+        // Hardware interrupts push the status with the B flag clear
+        self.stack_push(self.get_status() & !B_FLAG);
+        self.state.status |= I_FLAG;
+
+        let address = self.mem.read_u16(vector);
+        self.update_cycles(7).jump(address);
+    }
+
+    pub fn run_program(&mut self, text: &str) -> Result<(), RustyNesError> {
+        let (program, entry) = assemble_with_entry(text)?;
 
         // Load the program into memory
-        self.mem.load(ROM_START, &program);
-        // Setup reset vector to start PC at ROM_START
-        self.mem.load(RESET_VECTOR, &reset_vector);
+        self.mem.load(ROM_START, &program)?;
+        // Setup reset vector to start PC at the program's entry point
+        self.mem.set_vector(RESET_VECTOR, entry);
 
         self.reset();
 
         loop {
             let old_pc = self.state.pc;
             let value = self.mem.read(self.state.pc);
-            // 0x00/Zero opcode is the BRK instruction
+            // 0x00/Zero opcode is the BRK instruction, it still pushes
+            // and vectors so the state is what the hardware would leave
             if value == 0x00 {
                 println!("Encountered BRK. Exiting.");
+                self.brk(Mode::Implied);
                 break;
             }
             let (opcode, mode) = self.decode(value);
@@ -68,13 +228,60 @@ impl Processor {
                 break;
             }
         }
+
+        Ok(())
+    }
+
+    /**
+     * Step until control flow leaves the straight line: a taken branch, a
+     * jump, call, return or BRK. Returns the PC it landed on, or where the
+     * CPU faulted.
+     */
+    pub fn step_until_branch(&mut self) -> usize {
+        while self.fault.is_none() {
+            let pc = self.state.pc;
+            let value = self.mem.peek_mapped(pc);
+            let (_, mode) = self.decode(value);
+            self.exec();
+            let fell_through =
+                self.state.pc == (pc + opcode_len(mode) as usize) & 0xFFFF;
+            if changes_flow(value) && !fell_through {
+                break;
+            }
+        }
+        self.state.pc
+    }
+
+    /**
+     * Call the subroutine at `address` like a function. A sentinel return
+     * address is pushed and the CPU runs until the matching RTS pops it,
+     * leaving PC at SUBROUTINE_SENTINEL. Returns early if the CPU faults,
+     * a subroutine that never returns never returns here either.
+     */
+    pub fn run_subroutine(&mut self, address: usize) {
+        let sp = self.state.sp;
+        // RTS resumes one past the pushed address
+        let pushed = SUBROUTINE_SENTINEL - 1;
+        self.stack_push((pushed >> 8) as u8);
+        self.stack_push((pushed & 0xff) as u8);
+        self.jump(address);
+
+        while self.fault.is_none()
+            && !(self.state.pc == SUBROUTINE_SENTINEL && self.state.sp == sp)
+        {
+            self.exec();
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::memory::ROM_START;
+    use super::base::{C_FLAG, D_FLAG, F_FLAG, N_FLAG};
+    use super::memory::{Access, AccessKind, ROM_START};
     use super::*;
+    use crate::nes::mapper::mmc3::Mmc3;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_math() {
@@ -85,7 +292,8 @@ mod test {
             SEC     ; set carry flag
             ADC #$01; add with carry
         ",
-        ));
+        ))
+        .unwrap();
         // a + operand + carry_flag
         assert_eq!(cpu.state.a, 3, "ADC result should be {}", 3);
 
@@ -93,7 +301,8 @@ mod test {
             "
         LDA #$03;
         AND #$02;",
-        ));
+        ))
+        .unwrap();
 
         assert_eq!(cpu.state.a, 0b10, "AND result should be {}", 0b10);
 
@@ -102,22 +311,68 @@ mod test {
         LDA #$02;
         ASL A;
         ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(cpu.state.a, 4, "ASL A result should be {}", 4);
     }
 
+    #[test]
+    fn test_wrapping_arithmetic() {
+        let mut cpu = Processor::new(None);
+        cpu.run_program(
+            "
+            LDA #$FF;
+            SEC     ;
+            ADC #$00; 0xFF + 0x00 + carry wraps to zero
+        ",
+        )
+        .unwrap();
+        assert_eq!(cpu.state.a, 0x00, "ADC should wrap");
+
+        cpu.state.x = 0xff;
+        cpu.inx(Mode::Implied);
+        assert_eq!(cpu.state.x, 0x00, "INX should wrap");
+
+        cpu.state.pc = 0;
+        cpu.update_pc(-1);
+        assert_eq!(cpu.state.pc, 0xFFFF, "PC should wrap to 16 bits");
+        cpu.update_pc(1);
+        assert_eq!(cpu.state.pc, 0x0000, "PC should wrap to 16 bits");
+
+        // (zp,X) base pointer lives in the zero page
+        cpu.state.pc = 0x0200;
+        cpu.state.x = 0x02;
+        cpu.mem.write(0x0201, 0xff);
+        cpu.mem.write(0x0001, 0x34);
+        cpu.mem.write(0x0002, 0x12);
+        assert_eq!(cpu.lookup(Mode::IndexedX), 0x1234);
+
+        // Indexing past $FFFF wraps to the bottom of the address space
+        cpu.state.status = 0;
+        cpu.state.x = 0x01;
+        cpu.mem.write(0x0201, 0xff);
+        cpu.mem.write(0x0202, 0xff);
+        assert_eq!(cpu.lookup(Mode::AbsoluteX), 0x0000);
+
+        // Branching backwards from the bottom of memory, past the branch
+        cpu.state.pc = 0x0000;
+        cpu.mem.write(0x0001, 0xfc);
+        assert_eq!(cpu.lookup(Mode::Relative), 0xFFFE);
+    }
+
     #[test]
     fn test_branches() {
         let mut cpu = Processor::new(None);
         cpu.run_program(&String::from(
             "
         SEC     ; set accumulator
-        BCS !$03; brach foward +3 because accumulator is set
+        BCS !$01; brach foward +1 because accumulator is set
         NOP     ; this should be skipped
         CLC     ; carry clear should cause the next instruction to jump back
-        BCC !$FB; branch to start because accumulator is clear
+        BCC !$F9; branch to start because accumulator is clear
         ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(
             cpu.state.pc, ROM_START,
             "Branch BCS and reverse branch with BCC"
@@ -126,9 +381,10 @@ mod test {
         cpu.run_program(&String::from(
             "
         LDA #$00;
-        BEQ !$FE;
+        BEQ !$FC;
         ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(cpu.state.pc, ROM_START, "Branch via BEQ");
 
         // Testing BIT as well as BMI below
@@ -137,36 +393,255 @@ mod test {
         LDA #$80;
         STA $FF ;
         BIT $FF ; bit test with value using zero-page
-        BMI !$FA; branch -6
+        BMI !$F8; branch -8
        ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(cpu.state.pc, ROM_START, "Branch via BMI");
 
+        cpu.mem.poke(0xff00, 0x80);
         cpu.run_program(&String::from(
             "
         BIT $FF00; $LLHH low & high bytes are swapped in memory
-        BMI !$FD ;
+        BMI !$FB ;
         ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(cpu.state.pc, ROM_START, "Branch via BMI");
 
         cpu.run_program(&String::from(
             "
         LDA #$01;
-        BNE !$FE;
+        BNE !$FC;
         ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(cpu.state.pc, ROM_START, "Branch via BNE");
 
         cpu.run_program(&String::from(
             "
         LDA #$01;
-        BPL !$FE;
+        BPL !$FC;
         ",
-        ));
+        ))
+        .unwrap();
         assert_eq!(cpu.state.pc, ROM_START, "Branch via BPL");
     }
 
+    #[test]
+    fn test_total_instructions() {
+        let mut cpu = Processor::new(None);
+        // LDA, STA, then three implied instructions and a JAM to stop on
+        cpu.mem
+            .load(0x0200, &[0xa9, 0x01, 0x85, 0x10, 0xe8, 0xc8, 0xea, 0x02])
+            .unwrap();
+        cpu.state.pc = 0x0200;
+        for _ in 0..5 {
+            cpu.exec();
+        }
+        assert_eq!(cpu.total_instructions(), 5);
+
+        // a micro-op instruction counts once for all its ticks
+        cpu.mem.load(0x0300, &[0xad, 0x00, 0x02]).unwrap();
+        cpu.state.pc = 0x0300;
+        for _ in 0..4 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.total_instructions(), 6);
+    }
+
+    #[test]
+    fn test_step_until_branch() {
+        let mut cpu = Processor::new(None);
+        #[rustfmt::skip]
+        let program = [
+            0xa2, 0x02, // $0200 LDX #$02
+            0xca, // $0202 DEX
+            0xf0, 0x02, // $0203 BEQ, not taken
+            0xd0, 0x02, // $0205 BNE $0209
+            0xa9, 0xff, // $0207 LDA #$FF, skipped
+            0xea, // $0209 NOP
+        ];
+        cpu.mem.load(0x0200, &program).unwrap();
+        cpu.state.pc = 0x0200;
+
+        assert_eq!(cpu.step_until_branch(), 0x0209);
+        assert_eq!(cpu.state.x, 1);
+        assert_eq!(cpu.state.a, 0, "the branch skipped the LDA");
+    }
+
+    #[test]
+    fn test_fibonacci() {
+        // run_program stops at the first backward jump, the loop needs the
+        // subroutine runner instead
+        let program = assemble(
+            "
+                LDA #$00;
+                STA $00 ;
+                LDA #$01;
+                STA $01 ;
+                LDX #$00;
+            loop:
+                CLC     ;
+                LDA $00,X;
+                ADC $01,X;
+                STA $02,X;
+                INX     ;
+                CPX #$0B;
+                BNE loop;
+                RTS     ;
+            ",
+        )
+        .unwrap();
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(ROM_START, &program).unwrap();
+        cpu.run_subroutine(ROM_START);
+
+        assert_eq!(cpu.fault, None);
+        assert_eq!(
+            cpu.mem.slice(0x00, 13),
+            &[0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144]
+        );
+        assert_eq!(cpu.state.x, 0x0b);
+    }
+
+    #[test]
+    fn test_run_subroutine() {
+        let mut cpu = Processor::new(None);
+        cpu.state.sp = 0xfd;
+        // double: JSR shift / RTS, shift: ASL A / RTS
+        cpu.mem.load(0x0300, &[0x20, 0x10, 0x03, 0x60]).unwrap();
+        cpu.mem.load(0x0310, &[0x0a, 0x60]).unwrap();
+        cpu.state.a = 0x21;
+
+        cpu.run_subroutine(0x0300);
+        assert_eq!(cpu.state.a, 0x42);
+        assert_eq!(cpu.state.pc, SUBROUTINE_SENTINEL);
+        assert_eq!(cpu.state.sp, 0xfd);
+    }
+
+    #[test]
+    fn test_pc_advance() {
+        // execute asserts the PC stepped over each instruction's operand
+        for value in 0..=0xffu8 {
+            if opcodes::changes_flow(value) {
+                continue;
+            }
+            let mut cpu = Processor::new(None);
+            cpu.state.sp = 0xfd;
+            cpu.mem.load(0x0200, &[value, 0x10, 0x02]).unwrap();
+            cpu.state.pc = 0x0200;
+            cpu.exec();
+        }
+    }
+
+    #[test]
+    fn test_cycles_increase() {
+        // every opcode with flags clear then set, so branches go both ways
+        for &status in [0x00, 0xff].iter() {
+            for value in 0..=0xffu8 {
+                let mut cpu = Processor::new(None);
+                cpu.state.sp = 0xfd;
+                cpu.state.status = status;
+                cpu.mem.load(0x0200, &[value, 0x10, 0x02]).unwrap();
+                cpu.state.pc = 0x0200;
+                cpu.cycles = 100;
+                cpu.exec();
+                assert!(
+                    cpu.fault.is_some() || cpu.cycles >= 102,
+                    "{:#04x} with P={:#04x} took {} cycles",
+                    value,
+                    status,
+                    cpu.cycles - 100
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rmw_dummy_write() {
+        let mut cpu = Processor::new(None);
+        // INC $1234 / ROL A
+        cpu.mem.load(0x0200, &[0xee, 0x34, 0x12, 0x2a]).unwrap();
+        cpu.mem.write(0x1234, 0x41);
+        cpu.state.pc = 0x0200;
+
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let log = writes.clone();
+        cpu.set_access_log(Some(Box::new(move |access: Access| {
+            if access.kind == AccessKind::Write {
+                log.borrow_mut().push((access.addr, access.value));
+            }
+        })));
+        cpu.exec();
+        assert_eq!(
+            *writes.borrow(),
+            vec![(0x1234, 0x41), (0x1234, 0x42)],
+            "old value then the new one"
+        );
+
+        cpu.state.a = 0x81;
+        cpu.state.status = C_FLAG;
+        cpu.exec();
+        assert_eq!(cpu.state.a, 0x03, "carry rotates in");
+        assert_eq!(writes.borrow().len(), 2, "accumulator mode doesn't write");
+    }
+
+    #[test]
+    fn test_scheduled_nmi() {
+        let mut cpu = Processor::new(None);
+        // Five two-cycle loads, the fifth one crosses cycle 10
+        let program = [0xa9, 0x01].repeat(5);
+        cpu.mem.load(ROM_START, &program).unwrap();
+        cpu.mem.set_vector(NMI_VECTOR, 0x9000);
+        cpu.mem.set_vector(RESET_VECTOR, ROM_START);
+        cpu.reset();
+        cpu.schedule_nmi_at(10);
+
+        for _ in 0..4 {
+            cpu.exec();
+        }
+        assert_eq!(cpu.state.pc, ROM_START + 8, "NMI fired too early");
+
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0x9000, "NMI should vector to the handler");
+        assert_eq!(cpu.cycles, 10 + 7);
+        assert_eq!(cpu.nmi_at, None);
+        // return address is the instruction following the one that crossed
+        assert_eq!(cpu.mem.read(0x1ff), ((ROM_START + 10) >> 8) as u8);
+        assert_eq!(cpu.mem.read(0x1fe), ((ROM_START + 10) & 0xff) as u8);
+        // then the status, with B clear as it wasn't a BRK
+        let pushed = cpu.mem.read(0x1fd);
+        assert_eq!(pushed & B_FLAG, 0);
+        assert_eq!(pushed & F_FLAG, F_FLAG);
+        assert_eq!(cpu.state.sp, 0xfc);
+        assert_eq!(cpu.state.status & I_FLAG, I_FLAG);
+    }
+
+    #[test]
+    fn test_mapper_irq() {
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(ROM_START, &[0xa9, 0x01]).unwrap();
+        cpu.mem.set_vector(IRQ_BRK_VECTOR, 0x9000);
+        cpu.mem.set_vector(RESET_VECTOR, ROM_START);
+        cpu.reset();
+        cpu.mem.mapper = Some(Box::new(Mmc3::new(&[])));
+
+        // A latch of zero raises the IRQ on the first clock after reload
+        cpu.mem.write(0xC000, 0);
+        cpu.mem.write(0xC001, 0);
+        cpu.mem.write(0xE001, 0);
+        let mapper = cpu.mem.mapper.as_mut().unwrap();
+        for &address in [0x0000, 0x0000, 0x0000, 0x1000].iter() {
+            mapper.on_ppu_address(address);
+        }
+
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0x9000, "IRQ vectors once the CPU polls");
+        assert_eq!(cpu.state.a, 0x01);
+        assert!(cpu.state.status & I_FLAG != 0, "IRQs are masked");
+    }
+
     #[test]
     fn test_cld() {
         let mut cpu = Processor::new(None);
@@ -178,8 +653,171 @@ mod test {
         CLD    ;
         BRK    ; force exit
         ",
-        ));
+        ))
+        .unwrap();
+
+        assert_eq!(cpu.state.status & D_FLAG, 0);
+        assert_eq!(cpu.state.status, I_FLAG, "only BRK's I is left");
+    }
+
+    #[test]
+    fn test_run_program_brk() {
+        let mut cpu = Processor::new(None);
+        cpu.run_program("SEC;\nBRK;").unwrap();
+
+        assert_eq!(cpu.state.status & I_FLAG, I_FLAG);
+        // status on top, then the return address past the padding byte
+        let top = cpu.stack_top();
+        assert_eq!(cpu.mem.read(top + 1), C_FLAG | B_FLAG | F_FLAG);
+        assert_eq!(cpu.mem.read(top + 2), 0x03);
+        assert_eq!(cpu.mem.read(top + 3), 0x80);
+    }
 
-        assert_eq!(cpu.state.status, 0);
+    #[test]
+    fn test_access_log() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&accesses);
+        let mut cpu = Processor::new(None);
+        cpu.set_access_log(Some(Box::new(move |access| {
+            log.borrow_mut().push(access)
+        })));
+
+        // STA $10
+        cpu.mem.load(0x0200, &[0x85, 0x10]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.state.a = 0x42;
+        cpu.exec();
+
+        assert!(accesses.borrow().contains(&Access {
+            kind: AccessKind::Write,
+            addr: 0x10,
+            value: 0x42,
+            pc: 0x0200,
+        }));
+        assert_eq!(
+            accesses.borrow()[0],
+            Access {
+                kind: AccessKind::Read,
+                addr: 0x0200,
+                value: 0x85,
+                pc: 0x0200,
+            }
+        );
+    }
+
+    #[test]
+    fn test_accumulator_operand() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&accesses);
+        let mut cpu = Processor::new(None);
+        cpu.set_access_log(Some(Box::new(move |access| {
+            log.borrow_mut().push(access)
+        })));
+
+        // ASL A with A also a valid zero page address
+        cpu.mem.load(0x0200, &[0x0a]).unwrap();
+        cpu.mem.poke(0x41, 0x0f);
+        accesses.borrow_mut().clear();
+        cpu.state.pc = 0x0200;
+        cpu.state.a = 0x41;
+        cpu.exec();
+
+        assert_eq!(cpu.state.a, 0x82);
+        assert_eq!(cpu.mem.peek(0x41), 0x0f);
+        assert!(
+            accesses.borrow().iter().all(|access| access.addr == 0x0200),
+            "only the opcode fetch touches memory"
+        );
+    }
+
+    #[test]
+    fn test_micro_op_tick() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&accesses);
+        let mut cpu = Processor::new(None);
+        cpu.set_access_log(Some(Box::new(move |access| {
+            log.borrow_mut().push(access)
+        })));
+
+        // LDA $1234
+        cpu.mem.load(0x0200, &[0xad, 0x34, 0x12]).unwrap();
+        cpu.mem.write(0x1234, 0x80);
+        accesses.borrow_mut().clear();
+        cpu.state.pc = 0x0200;
+
+        let read = |addr, value| Access {
+            kind: AccessKind::Read,
+            addr,
+            value,
+            pc: 0x0200,
+        };
+        let expected = [
+            read(0x0200, 0xad),
+            read(0x0201, 0x34),
+            read(0x0202, 0x12),
+            read(0x1234, 0x80),
+        ];
+        for (cycle, access) in expected.iter().enumerate() {
+            assert_eq!(cpu.state.a, 0, "A is loaded on the last cycle");
+            cpu.tick();
+            assert_eq!(accesses.borrow().len(), cycle + 1, "one access a tick");
+            assert_eq!(&accesses.borrow()[cycle], access, "cycle {}", cycle);
+            assert_eq!(cpu.cycles as usize, cycle + 1);
+        }
+        assert_eq!(cpu.state.a, 0x80);
+        assert_eq!(cpu.state.status & N_FLAG, N_FLAG);
+        assert_eq!(cpu.state.pc, 0x0203);
+        assert!(cpu.micro_ops.is_empty());
+
+        // the next tick fetches the next opcode
+        cpu.tick();
+        assert_eq!(accesses.borrow()[4].addr, 0x0203);
+    }
+
+    #[test]
+    fn test_interrupt_tick() {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&accesses);
+        let mut cpu = Processor::new(None);
+        cpu.set_access_log(Some(Box::new(move |access: Access| {
+            log.borrow_mut().push((access.kind, access.addr))
+        })));
+
+        // NOP, with an NMI due once it's done
+        cpu.mem.load(0x0200, &[0xea]).unwrap();
+        cpu.mem.set_vector(NMI_VECTOR, 0x9000);
+        accesses.borrow_mut().clear();
+        cpu.state.pc = 0x0200;
+        cpu.state.sp = 0xfd;
+        cpu.schedule_nmi_at(2);
+
+        let read = |addr| (AccessKind::Read, addr);
+        let write = |addr| (AccessKind::Write, addr);
+        let expected = [
+            read(0x0200),
+            read(0x0201),
+            // the interrupt replaces the next opcode fetch
+            read(0x0201),
+            read(0x0201),
+            write(0x01fd),
+            write(0x01fc),
+            write(0x01fb),
+            read(NMI_VECTOR),
+            read(NMI_VECTOR + 1),
+        ];
+        for (cycle, access) in expected.iter().enumerate() {
+            assert_ne!(cpu.state.pc, 0x9000, "vectored early");
+            cpu.tick();
+            assert_eq!(accesses.borrow().len(), cycle + 1, "one access a tick");
+            assert_eq!(&accesses.borrow()[cycle], access, "cycle {}", cycle);
+        }
+        assert_eq!(cpu.state.pc, 0x9000);
+        assert_eq!(cpu.cycles, 9);
+        assert_eq!(cpu.nmi_at, None);
+        assert_eq!(cpu.mem.peek(0x1fd), 0x02);
+        assert_eq!(cpu.mem.peek(0x1fc), 0x01);
+        assert_eq!(cpu.mem.peek(0x1fb) & B_FLAG, 0);
+        assert_eq!(cpu.state.status & I_FLAG, I_FLAG);
+        assert!(cpu.micro_ops.is_empty());
     }
 }
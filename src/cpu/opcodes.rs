@@ -1,9 +1,12 @@
-use super::addressing::Mode;
+use super::addressing::{Mode, Operand};
 use super::base::{
-    Processor, Reg, B_FLAG, C_FLAG, D_FLAG, F_FLAG, I_FLAG, N_FLAG, V_FLAG,
-    Z_FLAG,
+    Fault, Processor, Reg, B_FLAG, C_FLAG, D_FLAG, I_FLAG, N_FLAG, SIGN_BIT,
+    V_FLAG, Z_FLAG,
 };
-use super::memory::IRQ_BRK_VECTOR;
+use super::memory::{
+    IRQ_BRK_VECTOR, MEMORY_MAX, NMI_VECTOR, RESET_VECTOR, ROM_START,
+};
+use crate::error::AssembleError;
 use regex::Regex;
 use std::collections::HashMap;
 
@@ -34,7 +37,8 @@ pub const NOP: u8 = 0xea;
 #[allow(dead_code)]
 pub const LDA: u8 = 0xa9;
 
-pub const MODE_IML: u8 = 0b0000_0000;
+// Not a bbb pattern, (zp,X) already uses 000
+pub const MODE_IML: u8 = 0xff;
 pub const MODE_ZPG: u8 = 0b0000_0100;
 pub const MODE_IMM: u8 = 0b0000_1000;
 pub const MODE_ACC: u8 = 0b0000_1000;
@@ -66,9 +70,24 @@ lazy_static! {
         m.insert("BVC", 0x50);
         m.insert("BVS", 0x70);
         m.insert("CLC", CLC);
+        m.insert("CLD", 0xd8);
+        m.insert("CMP", 0xc9);
+        m.insert("CPX", 0xe0);
+        m.insert("EOR", 0x49);
+        m.insert("INX", 0xe8);
+        m.insert("JMP", 0x4c);
+        m.insert("JSR", 0x20);
+        m.insert("LDX", 0xa2);
+        m.insert("LDY", 0xa0);
+        m.insert("ORA", 0x09);
+        m.insert("RTI", 0x40);
+        m.insert("RTS", 0x60);
+        m.insert("SBC", 0xe9);
         m.insert("SEC", SEC);
         m.insert("SED", 0xf8);
         m.insert("STA", 0x85);
+        m.insert("STX", 0x86);
+        m.insert("STY", 0x84);
         m.insert("NOP", NOP);
         m.insert("LDA", LDA);
 
@@ -92,6 +111,22 @@ pub fn opcode_len(mode: Mode) -> i32 {
     }
 }
 
+/**
+ * Opcodes that set PC themselves rather than stepping over their operand:
+ * BRK, JSR, RTI, RTS, JMP and the branches
+ */
+pub fn changes_flow(opcode: u8) -> bool {
+    matches!(opcode, 0x00 | 0x20 | 0x40 | 0x60 | 0x4c | 0x6c)
+        || opcode & 0x1f == 0x10
+}
+
+/**
+ * JAM opcodes that lock up the CPU, decoded to `jam`
+ */
+pub fn is_jam(opcode: u8) -> bool {
+    matches!(opcode, 0x02 | 0x22 | 0x42 | 0x62)
+}
+
 pub fn apply_address_mode(opcode: u8, mode: u8) -> u8 {
     // if the mode is implied then leave the raw opcode whatever it might be.
     // There are multiple instructions that use implied mode but do not share
@@ -103,7 +138,7 @@ pub fn apply_address_mode(opcode: u8, mode: u8) -> u8 {
     (opcode & 0b1110_0011) | mode
 }
 
-pub fn encode(line: &String) -> Vec<u8> {
+pub fn encode(line: &str) -> Result<Vec<u8>, AssembleError> {
     lazy_static! {
         static ref IMPLIED: Regex = Regex::new(r"^(?P<name>[A-Z]{3})[ ]*;.*$").unwrap();
         static ref ACCUMULATOR: Regex = Regex::new(r"^(?P<name>[A-Z]{3}) A[ ]*;.*$").unwrap();
@@ -136,20 +171,30 @@ pub fn encode(line: &String) -> Vec<u8> {
     }
 
     let apply_regex = |regex: &Regex, mode: u8| {
-        let captures = regex.captures(line).unwrap();
+        let captures = regex
+            .captures(line)
+            .ok_or_else(|| AssembleError::InvalidSyntax(String::from(line)))?;
         let opcode_value =
-            *OPCODE_HASHMAP.get(&captures["name"]).unwrap_or(&NOP);
-        let opcode = apply_address_mode(opcode_value, mode);
+            *OPCODE_HASHMAP.get(&captures["name"]).ok_or_else(|| {
+                AssembleError::UnknownInstruction(String::from(line))
+            })?;
+        // JSR only has the one mode and its opcode doesn't follow the pattern
+        let opcode = if &captures["name"] == "JSR" {
+            opcode_value
+        } else {
+            apply_address_mode(opcode_value, mode)
+        };
         let mut result: Vec<u8> = Vec::new();
         result.push(opcode);
-        for cap in captures.iter().skip(2) {
-            result
-                .push(u8::from_str_radix(&cap.unwrap().as_str(), 16).unwrap());
+        for cap in captures.iter().skip(2).flatten() {
+            result.push(u8::from_str_radix(cap.as_str(), 16).map_err(
+                |_| AssembleError::InvalidSyntax(String::from(line)),
+            )?);
         }
         if result.len() == 3 {
             result.swap(1, 2);
         }
-        result
+        Ok(result)
     };
 
     if ABSOLUTE.is_match(line) {
@@ -159,15 +204,33 @@ pub fn encode(line: &String) -> Vec<u8> {
     } else if ABSOLUTE_X.is_match(line) {
         apply_regex(&ABSOLUTE_X, MODE_ABX)
     } else if ABSOLUTE_Y.is_match(line) {
-        apply_regex(&ABSOLUTE_Y, MODE_ABY)
+        // LDX $nnnn,Y sits where the other c=2 instructions put absolute,X
+        apply_regex(&ABSOLUTE_Y, MODE_ABY).map(|mut bytes| {
+            if bytes[0] & 0b11 == 2 {
+                bytes[0] |= MODE_ABX;
+            }
+            bytes
+        })
     } else if IMMEDIATE.is_match(line) {
-        apply_regex(&IMMEDIATE, MODE_IMM)
+        // Only group one has immediate at b=2, LDX, LDY, CPX & CPY use b=0
+        apply_regex(&IMMEDIATE, MODE_IMM).map(|mut bytes| {
+            if bytes[0] & 0b11 != 1 {
+                bytes[0] &= 0b1110_0011;
+            }
+            bytes
+        })
     } else if RELATIVE.is_match(line) {
         apply_regex(&RELATIVE, MODE_REL)
     } else if ZERO_PAGE.is_match(line) {
         apply_regex(&ZERO_PAGE, MODE_ZPG)
     } else if INDIRECT.is_match(line) {
-        apply_regex(&INDIRECT, MODE_IND)
+        // JMP is the only indirect instruction and doesn't follow the pattern
+        apply_regex(&INDIRECT, MODE_IND).map(|mut bytes| {
+            if bytes[0] == 0x4c {
+                bytes[0] = 0x6c;
+            }
+            bytes
+        })
     } else if X_INDEX.is_match(line) {
         apply_regex(&X_INDEX, MODE_INX)
     } else if Y_INDEX.is_match(line) {
@@ -181,19 +244,220 @@ pub fn encode(line: &String) -> Vec<u8> {
     }
 }
 
+const BRANCHES: &[&str] =
+    &["BCC", "BCS", "BEQ", "BMI", "BNE", "BPL", "BVC", "BVS"];
+
+lazy_static! {
+    static ref LABEL: Regex =
+        Regex::new(r"^(?P<label>[A-Za-z_][A-Za-z0-9_]*):[ ]*(;.*)?$").unwrap();
+    static ref LABEL_OPERAND: Regex = Regex::new(
+        r"^(?P<name>[A-Z]{3}) (?P<label>[A-Za-z_][A-Za-z0-9_]*)[ ]*;.*$"
+    )
+    .unwrap();
+    static ref DIRECTIVE: Regex =
+        Regex::new(r"^\.(?P<name>[a-z]+) (?P<args>[^;]*?)[ ]*(;.*)?$").unwrap();
+    static ref NAME: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
+
+// An instruction operand naming a label, `A` is the accumulator not a label
+fn label_operand(line: &str) -> Option<(&str, &str)> {
+    let captures = LABEL_OPERAND.captures(line)?;
+    let label = captures.name("label")?.as_str();
+    if label == "A" {
+        return None;
+    }
+    Some((captures.name("name")?.as_str(), label))
+}
+
+// A `$HHHH` hex number, at most `digits` long
+fn hex_value(
+    text: &str,
+    digits: usize,
+    line: &str,
+) -> Result<usize, AssembleError> {
+    text.strip_prefix('$')
+        .filter(|hex| !hex.is_empty() && hex.len() <= digits)
+        .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| AssembleError::InvalidSyntax(String::from(line)))
+}
+
+/**
+ * One pass over the program. The first pass only records where labels land,
+ * unknown labels stand in as ROM_START since sizes never depend on them.
+ * Returns the code along with its entry point.
+ */
+fn assemble_pass<'a>(
+    lines: &[&'a str],
+    labels: &mut HashMap<&'a str, usize>,
+    first: bool,
+) -> Result<(Vec<u8>, usize), AssembleError> {
+    let mut program: Vec<u8> = Vec::new();
+    let mut vectors: Vec<(usize, usize)> = Vec::new();
+    // a leading .org moves where execution starts
+    let mut entry = ROM_START;
+    for &line in lines.iter() {
+        let here = ROM_START + program.len();
+        let resolve = |label: &str| match labels.get(label) {
+            Some(&address) => Ok(address),
+            None if first => Ok(ROM_START),
+            None => Err(AssembleError::UndefinedLabel(String::from(label))),
+        };
+        let invalid = || AssembleError::InvalidSyntax(String::from(line));
+
+        if let Some(captures) = LABEL.captures(line) {
+            if first
+                && labels
+                    .insert(captures.name("label").unwrap().as_str(), here)
+                    .is_some()
+            {
+                return Err(invalid());
+            }
+            continue;
+        }
+
+        if let Some(captures) = DIRECTIVE.captures(line) {
+            let args: Vec<&str> = captures
+                .name("args")
+                .unwrap()
+                .as_str()
+                .split(',')
+                .map(str::trim)
+                .collect();
+            match (&captures["name"], args.as_slice()) {
+                ("resetvector", &[label]) => {
+                    vectors.push((RESET_VECTOR, resolve(label)?))
+                }
+                ("nmivector", &[label]) => {
+                    vectors.push((NMI_VECTOR, resolve(label)?))
+                }
+                ("irqvector", &[label]) => {
+                    vectors.push((IRQ_BRK_VECTOR, resolve(label)?))
+                }
+                ("org", &[address]) => {
+                    let address = hex_value(address, 4, line)?;
+                    if address < here {
+                        return Err(invalid());
+                    }
+                    if program.is_empty() {
+                        entry = address;
+                    }
+                    program.resize(address - ROM_START, 0);
+                }
+                ("byte", values) => {
+                    for value in values {
+                        program.push(hex_value(value, 2, line)? as u8);
+                    }
+                }
+                ("word", values) => {
+                    for &value in values {
+                        let word = if NAME.is_match(value) {
+                            resolve(value)?
+                        } else {
+                            hex_value(value, 4, line)?
+                        };
+                        program.push((word & 0xFF) as u8);
+                        program.push((word >> 8) as u8);
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+            continue;
+        }
+
+        let instruction = match label_operand(line) {
+            Some((name, label)) if BRANCHES.contains(&name) => {
+                // Relative offsets count from the instruction after the branch
+                let offset = resolve(label)? as isize - (here + 2) as isize;
+                if !first
+                    && (offset < i8::MIN as isize || offset > i8::MAX as isize)
+                {
+                    return Err(invalid());
+                }
+                encode(&format!("{} !${:02X};", name, offset as u8))?
+            }
+            Some((name, label)) => {
+                encode(&format!("{} ${:04X};", name, resolve(label)?))?
+            }
+            None => encode(line)?,
+        };
+        program.extend(instruction);
+    }
+
+    if !vectors.is_empty() {
+        program.resize(MEMORY_MAX - ROM_START, 0);
+        for (vector, target) in vectors {
+            if vector == RESET_VECTOR {
+                entry = target;
+            }
+            program[vector - ROM_START] = (target & 0xFF) as u8;
+            program[vector - ROM_START + 1] = (target >> 8) as u8;
+        }
+    }
+    Ok((program, entry))
+}
+
+/**
+ * Assemble a whole program, one instruction per line, into machine code
+ * that starts at ROM_START.
+ *
+ * A line `name:` labels the next instruction and instructions can take a
+ * label in place of their operand. Directives:
+ *
+ * - `.org $HHHH` pads with zeros up to an address
+ * - `.byte $HH, ...` & `.word $HHHH, label, ...` emit data, words are
+ *   little-endian
+ * - `.resetvector name`, `.nmivector name` & `.irqvector name` point an
+ *   interrupt vector at a label, this pads the program to the end of memory
+ */
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    assemble_with_entry(source).map(|(program, _)| program)
+}
+
+/**
+ * assemble, also returning the address execution should start from: the
+ * `.resetvector` label, else a leading `.org`, else ROM_START
+ */
+pub fn assemble_with_entry(
+    source: &str,
+) -> Result<(Vec<u8>, usize), AssembleError> {
+    // blank and comment-only lines produce no code
+    let lines: Vec<&str> = source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .collect();
+
+    let mut labels = HashMap::new();
+    assemble_pass(&lines, &mut labels, true)?;
+    assemble_pass(&lines, &mut labels, false)
+}
+
 impl Processor {
+    /**
+     * The handler and addressing mode of opcode byte `value`, opcodes
+     * without a handler go to `unimplemented`
+     */
     pub fn decode(&self, value: u8) -> (Opcode, Mode) {
+        self.decode_implemented(value)
+            .unwrap_or((Processor::unimplemented, Mode::Implied))
+    }
+
+    /**
+     * decode, None for the opcodes without a handler
+     */
+    pub fn decode_implemented(&self, value: u8) -> Option<(Opcode, Mode)> {
         // https://www.masswerk.at/6502/6502_instruction_set.html#layout
         let a = (value & 0b1110_0000) >> 5;
         let b = (value & 0b0001_1100) >> 2;
         let c = value & 0b0000_0011;
 
-        match (c, b, a) {
+        let entry: (Opcode, Mode) = match (c, b, a) {
             // c0 is a mess and it's easier to decode by hand
             (0, 0, 0) => (Processor::brk, Mode::Implied),
             (0, 0, 1) => (Processor::jsr, Mode::Absolute),
             (0, 0, 2) => (Processor::rti, Mode::Implied),
             (0, 0, 3) => (Processor::rts, Mode::Implied),
+            (0, 0, 4) => (Processor::nop, Mode::Immediate),
             (0, 0, 5) => (Processor::ldy, Mode::Immediate),
             (0, 0, 6) => (Processor::cpy, Mode::Immediate),
             (0, 0, 7) => (Processor::cpx, Mode::Immediate),
@@ -204,7 +468,7 @@ impl Processor {
                     5 => Processor::ldy,
                     6 => Processor::cpy,
                     7 => Processor::cpx,
-                    _ => panic!("Cannot decode instruction {}", value),
+                    _ => Processor::nop,
                 };
                 (instruction, Mode::ZeroPage)
             }
@@ -218,7 +482,7 @@ impl Processor {
                     5 => Processor::tay,
                     6 => Processor::iny,
                     7 => Processor::inx,
-                    _ => panic!("Cannot decode instruction {}", value),
+                    _ => return None,
                 };
 
                 (instruction, Mode::Implied)
@@ -226,6 +490,7 @@ impl Processor {
 
             (0, 3, _) => {
                 let instruction = match a {
+                    0 => Processor::nop,
                     1 => Processor::bit,
                     2 => Processor::jmp,
                     3 => Processor::jmp,
@@ -233,7 +498,7 @@ impl Processor {
                     5 => Processor::ldy,
                     6 => Processor::cpy,
                     7 => Processor::cpx,
-                    _ => panic!("Cannot decode opcode {:#04x}", value),
+                    _ => return None,
                 };
                 let mode = match a {
                     3 => Mode::Indirect,
@@ -253,12 +518,16 @@ impl Processor {
                     5 => Processor::bcs,
                     6 => Processor::bne,
                     7 => Processor::beq,
-                    _ => panic!("Cannot decode opcode {:#04x}", value),
+                    _ => return None,
                 };
                 (instruction, Mode::Relative)
             }
-            (0, 5, 4) => (Processor::sty, Mode::ZeroPage),
-            (0, 5, 5) => (Processor::ldy, Mode::ZeroPage),
+            (0, 5, 4) => (Processor::sty, Mode::ZeroPageX),
+            (0, 5, 5) => (Processor::ldy, Mode::ZeroPageX),
+            // Unofficial NOPs, they still fetch their operand
+            (0, 5, _) => (Processor::nop, Mode::ZeroPageX),
+            (0, 7, 5) => (Processor::ldy, Mode::AbsoluteX),
+            (0, 7, 0..=3) | (0, 7, 6..=7) => (Processor::nop, Mode::AbsoluteX),
             (0, 6, _) => {
                 let instruction = match a {
                     0 => Processor::clc,
@@ -269,29 +538,35 @@ impl Processor {
                     5 => Processor::clv,
                     6 => Processor::cld,
                     7 => Processor::sed,
-                    _ => panic!("Cannot decode opcode {:#04x}", value),
+                    _ => return None,
                 };
                 (instruction, Mode::Implied)
             }
+            // STA immediate would be a store to the operand, it's a NOP
+            (1, 2, 4) => (Processor::nop, Mode::Immediate),
             (1, _, _) => {
                 let mode = match b {
-                    0 => Mode::Indirect,
+                    0 => Mode::IndexedX,
                     1 => Mode::ZeroPage,
                     2 => Mode::Immediate,
                     3 => Mode::Absolute,
-                    4 => Mode::Indirect,
+                    4 => Mode::IndexedY,
                     5 => Mode::ZeroPageX,
-                    6 => Mode::AbsoluteX,
-                    7 => Mode::AbsoluteY,
-                    _ => panic!("Cannot decode opcode {:#04x}", value),
+                    6 => Mode::AbsoluteY,
+                    7 => Mode::AbsoluteX,
+                    _ => return None,
                 };
 
                 let instruction = match a {
-                    4 => Processor::sta,
+                    0 => Processor::ora,
                     1 => Processor::and,
+                    2 => Processor::eor,
                     3 => Processor::adc,
+                    4 => Processor::sta,
                     5 => Processor::lda,
-                    _ => Processor::nop,
+                    6 => Processor::cmp,
+                    7 => Processor::sbc,
+                    _ => return None,
                 };
 
                 (instruction, mode)
@@ -300,16 +575,28 @@ impl Processor {
             (2, 0, 1) => (Processor::jam, Mode::Implied),
             (2, 0, 2) => (Processor::jam, Mode::Implied),
             (2, 0, 3) => (Processor::jam, Mode::Implied),
+            (2, 0, 4) | (2, 0, 6) | (2, 0, 7) => {
+                (Processor::nop, Mode::Immediate)
+            }
             (2, _, _) => {
                 if a == 5 && b == 0 {
-                    return (Processor::ldx, Mode::Immediate);
+                    return Some((Processor::ldx, Mode::Immediate));
+                }
+                // Register transfers & DEX share the accumulator column
+                if b == 2 && a >= 4 {
+                    return Some(match a {
+                        4 => (Processor::txa, Mode::Implied),
+                        5 => (Processor::tax, Mode::Implied),
+                        6 => (Processor::dex, Mode::Implied),
+                        _ => (Processor::nop, Mode::Implied),
+                    });
                 }
                 if b == 6 {
-                    return match a {
+                    return Some(match a {
                         4 => (Processor::txs, Mode::Implied),
                         5 => (Processor::tsx, Mode::Implied),
-                        _ => panic!("Cannot decode opcode {:#04x}", value),
-                    };
+                        _ => (Processor::nop, Mode::Implied),
+                    });
                 }
 
                 let instruction = match a {
@@ -321,21 +608,29 @@ impl Processor {
                     5 => Processor::ldx,
                     6 => Processor::dec,
                     7 => Processor::inc,
-                    _ => panic!("Cannot decode opcode {:#04x}", value),
+                    _ => return None,
                 };
 
                 let mode = match b {
                     1 => Mode::ZeroPage,
-                    2 => Mode::Implied,
+                    // Shifts & rotates operate on the accumulator
+                    2 => Mode::Accumulator,
                     3 => Mode::Absolute,
+                    // STX & LDX index with Y, they can't index by themselves
+                    5 if a == 4 || a == 5 => Mode::ZeroPageY,
                     5 => Mode::ZeroPageX,
+                    7 if a == 5 => Mode::AbsoluteY,
+                    // STX has no absolute indexed form, $9E is SHX $nnnn,Y
+                    7 if a == 4 => return None,
                     7 => Mode::AbsoluteX,
-                    _ => panic!("Cannot decode opcode {:#04x}", value),
+                    _ => return None,
                 };
 
                 (instruction, mode)
             }
             // "Illegal" opcodes
+            // SBC immediate duplicate
+            (3, 2, 7) => (Processor::sbc, Mode::Immediate),
             // DCP
             (3, _, 6) => {
                 let mode = match b {
@@ -346,13 +641,14 @@ impl Processor {
                     5 => Mode::ZeroPageX,
                     6 => Mode::AbsoluteY,
                     7 => Mode::AbsoluteX,
-                    _ => panic!("Unable to decode opcode {}", value),
+                    _ => return None,
                 };
 
                 (Processor::dcp, mode)
             }
-            _ => (Processor::nop, Mode::Implied),
-        }
+            _ => return None,
+        };
+        Some(entry)
     }
 
     pub fn adc(&mut self, mode: Mode) {
@@ -360,8 +656,8 @@ impl Processor {
         let operand = self.mem.read(address);
         let accumulator = self.state.a;
         let carry = self.state.status & 1;
-        let (mut result, ..) = accumulator.overflowing_add(operand);
-        result += carry;
+        // The 2A03 has the decimal adjust circuitry cut out, D is ignored
+        let result = accumulator.wrapping_add(operand).wrapping_add(carry);
         self.set_reg(Reg::A, result)
             .update_pc(opcode_len(mode))
             .update_status(
@@ -374,33 +670,36 @@ impl Processor {
     }
 
     pub fn and(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = self.mem.read(address);
-        let accumulator = self.get_reg(Reg::A);
-        let result = accumulator & operand;
-        self.set_reg(Reg::A, result)
-            .update_pc(opcode_len(mode))
-            .update_status(accumulator, operand, result, N_FLAG | Z_FLAG)
-            .update_cycles(2);
+        self.logical_op(mode, |a, operand| a & operand);
     }
 
-    pub fn asl(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        // FIXME: this isn't ideal when mode is accumulator the logic is altered heavily
-        let operand = match mode {
-            Mode::Accumulator => self.state.a,
-            _ => self.mem.read(address),
-        };
-        let result = operand << 1;
+    pub fn eor(&mut self, mode: Mode) {
+        self.logical_op(mode, |a, operand| a ^ operand);
+    }
 
-        match mode {
-            Mode::Accumulator => {
-                self.set_reg(Reg::A, result);
-            }
-            _ => panic!("Unimplemented ASL addressing mode!"),
-        };
+    pub fn ora(&mut self, mode: Mode) {
+        self.logical_op(mode, |a, operand| a | operand);
+    }
+
+    // Shifts & rotates carry out the bit that fell off, N and Z follow the
+    // byte written back whether that's A or memory
+    fn update_shift_flags(&mut self, carry: bool, result: u8) -> &mut Self {
+        if carry {
+            self.state.status |= C_FLAG;
+        } else {
+            self.state.status &= !C_FLAG;
+        }
+        self.update_n_flag(result).update_z_flag(result)
+    }
+
+    pub fn asl(&mut self, mode: Mode) {
+        if mode == Mode::Immediate {
+            return self.unsupported_mode("ASL", mode);
+        }
+        let (operand, result) =
+            self.read_modify_write(mode, |value| value << 1);
 
-        self.update_status(operand, 1, result, Z_FLAG | C_FLAG | N_FLAG)
+        self.update_shift_flags(operand & SIGN_BIT != 0, result)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
@@ -450,7 +749,8 @@ impl Processor {
             (self.state.status & !(N_FLAG | V_FLAG)) | new_flags;
 
         self.update_status(accumulator, operand, result, Z_FLAG)
-            .update_pc(opcode_len(mode));
+            .update_pc(opcode_len(mode))
+            .update_cycles(2);
     }
 
     pub fn bmi(&mut self, mode: Mode) {
@@ -484,14 +784,24 @@ impl Processor {
     }
 
     pub fn brk(&mut self, _mode: Mode) {
-        let pch = (self.state.pc >> 8) as u8;
-        let pcl = (self.state.pc & 0xFF) as u8;
-        self.stack_push(pcl);
-        self.stack_push(pch);
-        self.stack_push(self.state.status | F_FLAG | B_FLAG);
+        // BRK is followed by a padding byte the return address skips
+        let next = (self.state.pc + 2) & 0xFFFF;
+        self.stack_push((next >> 8) as u8);
+        self.stack_push((next & 0xFF) as u8);
+        self.stack_push(self.get_status() | B_FLAG);
         self.state.status |= I_FLAG;
 
-        self.update_cycles(7).jump(IRQ_BRK_VECTOR);
+        // An NMI landing before the vector fetch on cycle 5 hijacks the BRK,
+        // it vectors through $FFFA with B still set in the pushed status
+        let vector = match self.nmi_at {
+            Some(at) if (self.cycles + 5) as u64 >= at => {
+                self.nmi_at = None;
+                NMI_VECTOR
+            }
+            _ => IRQ_BRK_VECTOR,
+        };
+        let address = self.mem.read_u16(vector);
+        self.update_cycles(7).jump(address);
     }
 
     pub fn bvc(&mut self, mode: Mode) {
@@ -505,7 +815,7 @@ impl Processor {
     }
 
     pub fn bvs(&mut self, mode: Mode) {
-        if self.state.status & V_FLAG == 1 {
+        if self.state.status & V_FLAG != 0 {
             let address = self.lookup(mode);
             self.jump(address);
         } else {
@@ -530,75 +840,88 @@ impl Processor {
     }
 
     pub fn clv(&mut self, mode: Mode) {
-        self.state.status &= V_FLAG;
+        self.state.status &= !V_FLAG;
         self.update_pc(opcode_len(mode)).update_cycles(2);
     }
 
-    pub fn cpx(&mut self, mode: Mode) {
+    /**
+     * Shared by CMP, CPX & CPY: subtract the operand from `reg_value` and
+     * set the flags without keeping the result
+     */
+    pub fn compare(&mut self, reg_value: u8, mode: Mode) {
         let address = self.lookup(mode);
         let operand = self.mem.read(address);
-        let x = self.state.x;
-        let result = x - operand;
-
-        self.update_status(operand, x, result, N_FLAG | Z_FLAG | C_FLAG)
+        self.compare_flags(reg_value, operand)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
 
-    pub fn cpy(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = self.mem.read(address);
-        let y = self.state.x;
-        let result = y - operand;
+    // C when reg >= operand unsigned, so equal values set it. Z and N come
+    // from the difference.
+    fn compare_flags(&mut self, reg_value: u8, operand: u8) -> &mut Self {
+        let result = reg_value.wrapping_sub(operand);
+        if reg_value >= operand {
+            self.state.status |= C_FLAG;
+        } else {
+            self.state.status &= !C_FLAG;
+        }
+        self.update_z_flag(result).update_n_flag(result)
+    }
 
-        self.update_status(operand, y, result, N_FLAG | Z_FLAG | C_FLAG)
-            .update_pc(opcode_len(mode))
-            .update_cycles(2);
+    pub fn cmp(&mut self, mode: Mode) {
+        self.compare(self.state.a, mode);
+    }
+
+    pub fn cpx(&mut self, mode: Mode) {
+        self.compare(self.state.x, mode);
+    }
+
+    pub fn cpy(&mut self, mode: Mode) {
+        self.compare(self.state.y, mode);
     }
 
     pub fn dcp(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = self.mem.read(address);
-        println!("operand {}", operand);
-        self.mem.write(address, operand.wrapping_sub(1));
-        let result = self.state.a.wrapping_sub(operand.wrapping_sub(1));
+        let (_, result) =
+            self.read_modify_write(mode, |value| value.wrapping_sub(1));
 
-        self.update_status(
-            operand,
-            self.state.a,
-            result,
-            N_FLAG | Z_FLAG | C_FLAG,
-        )
-        .update_pc(opcode_len(mode))
-        .update_cycles(4);
+        self.compare_flags(self.state.a, result)
+            .update_pc(opcode_len(mode))
+            .update_cycles(2);
     }
 
     pub fn dec(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = self.mem.read(address);
-        let result = operand - 1;
-        self.mem.write(address, result);
+        let (_, result) =
+            self.read_modify_write(mode, |value| value.wrapping_sub(1));
         self.update_n_flag(result)
             .update_z_flag(result)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
 
+    pub fn dex(&mut self, mode: Mode) {
+        let result = self.state.x.wrapping_sub(1);
+        self.state.x = result;
+
+        self.update_z_flag(result)
+            .update_n_flag(result)
+            .update_pc(opcode_len(mode))
+            .update_cycles(2);
+    }
+
     pub fn dey(&mut self, mode: Mode) {
         let y = self.state.y;
-        let result = y - 1;
+        let result = y.wrapping_sub(1);
+        self.state.y = result;
 
         self.update_z_flag(result)
             .update_n_flag(result)
             .update_pc(opcode_len(mode))
-            .update_pc(1);
+            .update_cycles(2);
     }
 
     pub fn inc(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = self.mem.read(address);
-        let result = operand + 1;
-        self.mem.write(address, result);
+        let (_, result) =
+            self.read_modify_write(mode, |value| value.wrapping_add(1));
         self.update_n_flag(result)
             .update_z_flag(result)
             .update_pc(opcode_len(mode))
@@ -606,7 +929,7 @@ impl Processor {
     }
 
     pub fn inx(&mut self, _mode: Mode) {
-        let result = self.state.x + 1;
+        let result = self.state.x.wrapping_add(1);
         self.state.x = result;
 
         self.update_z_flag(result)
@@ -631,22 +954,29 @@ impl Processor {
             self.mem.read(0x02),
             self.mem.read(0x03)
         );
-        panic!("JAM instruction encountered.");
+        // The CPU locks up, PC stays put until a reset
+        self.fault = Some(Fault::Jam);
     }
 
     pub fn jmp(&mut self, mode: Mode) {
+        let start = self.cycles;
         let address = self.lookup(mode);
 
+        // Opcode and operand, plus the two target bytes through a pointer
+        self.cycles = start + if mode == Mode::Indirect { 5 } else { 3 };
         self.jump(address);
     }
 
     pub fn jsr(&mut self, mode: Mode) {
         let address = self.lookup(mode);
-        let pch = self.state.pc >> 8;
-        let pcl = self.state.pc & 0xff;
+        // The pushed return address is the last byte of the JSR, RTS adds 1.
+        // PCH goes first so the address sits little-endian on the stack.
+        let last = (self.state.pc + 2) & 0xFFFF;
+        let pch = last >> 8;
+        let pcl = last & 0xff;
 
-        self.stack_push(pcl as u8);
         self.stack_push(pch as u8);
+        self.stack_push(pcl as u8);
 
         self.jump(address).update_cycles(4);
     }
@@ -673,14 +1003,10 @@ impl Processor {
     }
 
     pub fn lsr(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = match mode {
-            Mode::Accumulator => self.state.a,
-            _ => self.mem.read(address),
-        };
-        let result = operand >> 1;
+        let (operand, result) =
+            self.read_modify_write(mode, |value| value >> 1);
 
-        self.update_status(operand, operand, result, N_FLAG | C_FLAG | Z_FLAG)
+        self.update_shift_flags(operand & 1 != 0, result)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
@@ -688,71 +1014,100 @@ impl Processor {
     pub fn pha(&mut self, mode: Mode) {
         self.stack_push(self.state.a);
 
-        self.update_pc(opcode_len(mode)).update_cycles(2);
+        self.update_pc(opcode_len(mode)).update_cycles(3);
     }
 
     pub fn php(&mut self, mode: Mode) {
         // https://wiki.nesdev.org/w/index.php?title=Status_flags
         // bit 5 & 4 of the status byte pushed onto the stack must be set
         // without having a side-effect on the contents of status itself
-        self.stack_push(self.state.status | B_FLAG | F_FLAG);
-        self.update_pc(opcode_len(mode)).update_cycles(2);
+        self.stack_push(self.get_status() | B_FLAG);
+        self.update_pc(opcode_len(mode)).update_cycles(3);
     }
 
     pub fn pla(&mut self, mode: Mode) {
-        self.state.a = self.stack_pop();
-        self.update_pc(opcode_len(mode)).update_cycles(3);
+        let value = self.stack_pop();
+        self.set_reg(Reg::A, value)
+            .update_status(value, value, value, N_FLAG | Z_FLAG)
+            .update_pc(opcode_len(mode))
+            .update_cycles(4);
     }
 
     pub fn plp(&mut self, mode: Mode) {
-        self.state.status = self.stack_pop();
-        self.update_pc(opcode_len(mode)).update_cycles(3);
+        let status = self.stack_pop();
+        self.set_status_from_stack(status)
+            .update_pc(opcode_len(mode))
+            .update_cycles(4);
     }
 
     pub fn rol(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = match mode {
-            Mode::Accumulator => self.state.a,
-            _ => self.mem.read(address),
-        };
-        let result = operand.rotate_left(1);
+        let carry = self.state.status & C_FLAG;
+        let (operand, result) =
+            self.read_modify_write(mode, |value| (value << 1) | carry);
 
-        self.update_status(operand, operand, result, N_FLAG | C_FLAG | Z_FLAG)
+        self.update_shift_flags(operand & SIGN_BIT != 0, result)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
 
     pub fn ror(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
-        let operand = match mode {
-            Mode::Accumulator => self.state.a,
-            _ => self.mem.read(address),
-        };
-        let result = operand.rotate_right(1);
+        let carry = self.state.status & C_FLAG;
+        let (operand, result) =
+            self.read_modify_write(mode, |value| (value >> 1) | (carry << 7));
 
-        self.update_status(operand, operand, result, N_FLAG | C_FLAG | Z_FLAG)
+        self.update_shift_flags(operand & 1 != 0, result)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
 
     pub fn rti(&mut self, _mode: Mode) {
-        // break flag & bit 5 should be ignored from the pop-ed status
-        let status = self.stack_pop() & (!F_FLAG | !B_FLAG);
-        let pch = self.stack_pop() as usize;
+        let status = self.stack_pop();
         let pcl = self.stack_pop() as usize;
+        let pch = self.stack_pop() as usize;
         let new_pc = pcl | (pch << 8);
 
-        self.state.status = status;
-        self.jump(new_pc).update_cycles(6);
+        self.set_status_from_stack(status)
+            .jump(new_pc)
+            .update_cycles(6);
     }
 
     pub fn rts(&mut self, _mode: Mode) {
-        let pch = self.stack_pop() as usize;
         let pcl = self.stack_pop() as usize;
-        println!("PCH {:#04x} PCL {:#04x}", pch, pcl);
-        let new_pc = pcl | (pch << 8);
+        let pch = self.stack_pop() as usize;
+        // JSR pushed the address of its last byte, $FFFF returns to $0000
+        let new_pc = (pcl | (pch << 8)) + 1;
+
+        self.jump(new_pc).update_cycles(6);
+    }
+
+    pub fn sbc(&mut self, mode: Mode) {
+        let address = self.lookup(mode);
+        let operand = self.mem.read(address);
+        let accumulator = self.state.a;
+        let carry = self.state.status & C_FLAG;
+        // A - M - (1 - C) is the same as A + !M + C
+        let complement = !operand;
+        let sum = accumulator as u16 + complement as u16 + carry as u16;
+        let result = sum as u8;
+
+        // The overflow check in update_status is written for addition, the
+        // complement makes it hold for the subtraction too
+        self.set_reg(Reg::A, result)
+            .update_pc(opcode_len(mode))
+            .update_status(
+                accumulator,
+                complement,
+                result,
+                N_FLAG | Z_FLAG | V_FLAG,
+            )
+            .update_cycles(2);
 
-        self.jump(new_pc).update_cycles(6).update_pc(1);
+        // Carry is the inverted borrow
+        if sum > 0xFF {
+            self.state.status |= C_FLAG;
+        } else {
+            self.state.status &= !C_FLAG;
+        }
     }
 
     pub fn sec(&mut self, mode: Mode) {
@@ -771,13 +1126,13 @@ impl Processor {
     }
 
     pub fn sta(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
+        let address = self.lookup_write(mode);
         self.mem.write(address, self.get_reg(Reg::A));
         self.update_pc(opcode_len(mode)).update_cycles(2);
     }
 
     pub fn sty(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
+        let address = self.lookup_write(mode);
         let y = self.state.y;
 
         self.mem.write(address, y);
@@ -786,7 +1141,7 @@ impl Processor {
     }
 
     pub fn stx(&mut self, mode: Mode) {
-        let address = self.lookup(mode);
+        let address = self.lookup_write(mode);
         let x = self.state.x;
         self.mem.write(address, x);
         self.update_pc(opcode_len(mode)).update_cycles(2);
@@ -807,26 +1162,46 @@ impl Processor {
     pub fn ldy(&mut self, mode: Mode) {
         let address = self.lookup(mode);
         let operand = self.mem.read(address);
+        self.set_reg(Reg::Y, operand)
+            .update_n_flag(operand)
+            .update_z_flag(operand)
+            .update_pc(opcode_len(mode))
+            .update_cycles(2);
+    }
 
-        self.state.y = operand;
-
-        self.update_pc(opcode_len(mode))
-            .update_cycles(2)
-            .update_status(operand, operand, operand, N_FLAG | Z_FLAG);
+    pub fn tax(&mut self, mode: Mode) {
+        let a = self.state.a;
+        self.set_reg(Reg::X, a)
+            .update_n_flag(a)
+            .update_z_flag(a)
+            .update_pc(opcode_len(mode))
+            .update_cycles(2);
     }
 
     pub fn tsx(&mut self, mode: Mode) {
-        self.set_reg(Reg::X, self.state.sp)
+        let sp = self.state.sp;
+        self.set_reg(Reg::X, sp)
+            .update_n_flag(sp)
+            .update_z_flag(sp)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
 
-    pub fn txs(&mut self, mode: Mode) {
-        self.set_reg(Reg::S, self.state.x)
+    pub fn txa(&mut self, mode: Mode) {
+        let x = self.state.x;
+        self.set_reg(Reg::A, x)
+            .update_n_flag(x)
+            .update_z_flag(x)
             .update_pc(opcode_len(mode))
             .update_cycles(2);
     }
 
+    // The only transfer that leaves the flags alone
+    pub fn txs(&mut self, mode: Mode) {
+        self.state.sp = self.state.x;
+        self.update_pc(opcode_len(mode)).update_cycles(2);
+    }
+
     pub fn tya(&mut self, mode: Mode) {
         let y = self.state.y;
         self.state.a = y;
@@ -837,74 +1212,1097 @@ impl Processor {
             .update_cycles(2);
     }
 
+    /**
+     * AND, EOR & ORA: combine the operand into A and set N and Z
+     */
+    pub fn logical_op(&mut self, mode: Mode, combine: fn(u8, u8) -> u8) {
+        let address = self.lookup(mode);
+        let operand = self.mem.read(address);
+        let accumulator = self.get_reg(Reg::A);
+        let result = combine(accumulator, operand);
+        self.set_reg(Reg::A, result)
+            .update_pc(opcode_len(mode))
+            .update_status(accumulator, operand, result, N_FLAG | Z_FLAG)
+            .update_cycles(2);
+    }
+
+    /**
+     * Shared by the shifts, INC, DEC & the illegal RMWs. Memory modes write
+     * the unmodified value back before the result, the same dummy write as
+     * the hardware, which mapper registers like MMC3's can see. The read
+     * and the dummy write cost a cycle each, indexed modes always spend the
+     * page crossing cycle like stores do. Returns the operand and the result.
+     */
+    pub fn read_modify_write(
+        &mut self,
+        mode: Mode,
+        modify: impl FnOnce(u8) -> u8,
+    ) -> (u8, u8) {
+        match self.lookup_operand(mode) {
+            Operand::Value(operand) => {
+                let result = modify(operand);
+                self.state.a = result;
+                (operand, result)
+            }
+            Operand::Address(address) if self.latched => {
+                // the micro-ops already read the operand and wrote it back
+                let operand = self.data_latch;
+                let result = modify(operand);
+                self.mem.write(address, result);
+                (operand, result)
+            }
+            Operand::Address(address) => {
+                let operand = self.mem.read(address);
+                let result = modify(operand);
+                self.mem.write(address, operand);
+                self.mem.write(address, result);
+                self.cycles += 2;
+                (operand, result)
+            }
+        }
+    }
+
+    /**
+     * Fallback for an operation invoked with an addressing mode it does not
+     * implement. Records a fault and skips over the instruction instead of
+     * bringing down the whole emulator.
+     */
+    pub fn unsupported_mode(&mut self, op: &'static str, mode: Mode) {
+        eprintln!(
+            "Unsupported addressing mode {:?} for {} at {:#06x}",
+            mode, op, self.state.pc
+        );
+        self.fault = Some(Fault::UnsupportedMode(op, mode));
+        self.update_pc(opcode_len(mode)).update_cycles(2);
+    }
+
+    /**
+     * Fallback for opcodes without a handler. Runs as a NOP unless the
+     * processor is strict, in which case it faults without executing.
+     */
+    pub fn unimplemented(&mut self, mode: Mode) {
+        if !self.strict {
+            return self.nop(mode);
+        }
+        let pc = self.state.pc;
+        let opcode = self.mem.peek_mapped(pc);
+        eprintln!("Unimplemented opcode {:#04x} at {:#06x}", opcode, pc);
+        self.fault = Some(Fault::UnimplementedOpcode(opcode, pc));
+    }
+
     pub fn nop(&mut self, mode: Mode) {
-        println!("NOP");
-        self.update_pc(opcode_len(mode)).update_cycles(1);
+        if mode == Mode::Implied {
+            self.update_pc(1).update_cycles(2);
+            return;
+        }
+        // The unofficial variants read their operand and throw it away,
+        // page crossing included
+        let address = self.lookup(mode);
+        self.mem.read(address);
+        self.update_pc(opcode_len(mode)).update_cycles(2);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cpu::base::F_FLAG;
 
     #[test]
     fn test_encode() {
-        let program = encode(&String::from("ADC;"));
+        let program = encode(&String::from("ADC;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_IML));
 
         // test comments
-        let program = encode(&String::from("ADC; this is a comment"));
+        let program = encode(&String::from("ADC; this is a comment")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_IML));
 
         let program = encode(&String::from(
             "ADC     ;semi-colon can be spaced however needed",
-        ));
+        ))
+        .unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_IML));
 
-        let program = encode(&String::from("ADC #$A0;"));
+        let program = encode(&String::from("ADC #$A0;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_IMM));
         assert_eq!(program[1], 0xa0);
 
-        let program = encode(&String::from("ADC $A0;"));
+        let program = encode(&String::from("ADC $A0;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_ZPG));
         assert_eq!(program[1], 0xa0);
 
-        let program = encode(&String::from("ADC $A0,X;"));
+        let program = encode(&String::from("ADC $A0,X;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_ZPX));
         assert_eq!(program[1], 0xa0);
 
-        let program = encode(&String::from("ADC $A0,Y;"));
+        let program = encode(&String::from("ADC $A0,Y;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_ZPY));
         assert_eq!(program[1], 0xa0);
 
-        let program = encode(&String::from("ADC $A0FF;"));
+        let program = encode(&String::from("ADC $A0FF;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_ABS));
         assert_eq!(program[1], 0xff);
         assert_eq!(program[2], 0xa0);
 
-        let program = encode(&String::from("ADC $A0FF,X;"));
+        let program = encode(&String::from("ADC $A0FF,X;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_ABX));
         assert_eq!(program[1], 0xff);
         assert_eq!(program[2], 0xa0);
 
-        let program = encode(&String::from("ADC $A0FF,Y;"));
+        let program = encode(&String::from("ADC $A0FF,Y;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_ABY));
         assert_eq!(program[1], 0xff);
         assert_eq!(program[2], 0xa0);
 
         // indirect instruction encoding. Note that ADC does not actually have an indirect
         // version on the real cpu. This is for testing purposes only.
-        let program = encode(&String::from("ADC ($AABB);"));
+        let program = encode(&String::from("ADC ($AABB);")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_IND));
         assert_eq!(program[1], 0xbb);
         assert_eq!(program[2], 0xaa);
 
-        let program = encode(&String::from("ADC ($AA,X);"));
+        let program = encode(&String::from("ADC ($AA,X);")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_INX));
         assert_eq!(program[1], 0xaa);
 
-        let program = encode(&String::from("ADC ($BB),Y;"));
+        let program = encode(&String::from("ADC ($BB),Y;")).unwrap();
         assert_eq!(program[0], apply_address_mode(ADC, MODE_INY));
         assert_eq!(program[1], 0xbb);
+
+        assert_eq!(
+            encode("XYZ #$01;"),
+            Err(AssembleError::UnknownInstruction(String::from("XYZ #$01;")))
+        );
+        assert_eq!(
+            encode("LDA #$1;"),
+            Err(AssembleError::InvalidSyntax(String::from("LDA #$1;")))
+        );
+    }
+
+    #[test]
+    fn test_jmp_indirect() {
+        assert_eq!(encode("JMP ($0210);"), Ok(vec![0x6c, 0x10, 0x02]));
+        assert_eq!(encode("JMP $0210;"), Ok(vec![0x4c, 0x10, 0x02]));
+
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x8000, &[0x6c, 0x10, 0x02]).unwrap();
+        cpu.mem.load(0x0210, &[0x34, 0x12]).unwrap();
+        cpu.state.pc = 0x8000;
+        cpu.exec();
+
+        assert_eq!(cpu.state.pc, 0x1234);
+        assert_eq!(cpu.cycles, 5, "JMP (ind) takes exactly 5 cycles");
+
+        // pointer high byte does not cross the page
+        cpu.mem.load(0x8000, &[0x6c, 0xff, 0x02]).unwrap();
+        cpu.mem.load(0x02ff, &[0x78]).unwrap();
+        cpu.mem.load(0x0200, &[0x56]).unwrap();
+        cpu.state.pc = 0x8000;
+        cpu.cycles = 0;
+        cpu.exec();
+
+        assert_eq!(cpu.state.pc, 0x5678);
+        assert_eq!(cpu.cycles, 5);
+    }
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(assemble("LDA #$01;\nBRK;"), Ok(vec![0xa9, 0x01, 0x00]));
+        assert_eq!(
+            assemble(
+                "
+                ; comments and blank lines are skipped
+
+                SEC     ;
+                ADC #$01;
+                "
+            ),
+            Ok(vec![SEC, ADC, 0x01])
+        );
+        assert_eq!(
+            assemble("LDA #$01;\nFOO;"),
+            Err(AssembleError::UnknownInstruction(String::from("FOO;")))
+        );
+    }
+
+    #[test]
+    fn test_index_registers() {
+        // LDX & STX index with Y, LDY & STY with X
+        assert_eq!(encode("LDX $10,Y;"), Ok(vec![0xb6, 0x10]));
+        assert_eq!(encode("STX $10,Y;"), Ok(vec![0x96, 0x10]));
+        assert_eq!(encode("LDY $10,X;"), Ok(vec![0xb4, 0x10]));
+        assert_eq!(encode("STY $10,X;"), Ok(vec![0x94, 0x10]));
+        assert_eq!(encode("LDX #$10;"), Ok(vec![0xa2, 0x10]));
+        assert_eq!(encode("LDY #$10;"), Ok(vec![0xa0, 0x10]));
+        assert_eq!(encode("INX;"), Ok(vec![0xe8]));
+        assert_eq!(encode("CPX #$10;"), Ok(vec![0xe0, 0x10]));
+        assert_eq!(encode("CPX $10;"), Ok(vec![0xe4, 0x10]));
+
+        let cpu = Processor::new(None);
+        assert_eq!(cpu.decode(0xb6).1, Mode::ZeroPageY);
+        assert_eq!(cpu.decode(0x96).1, Mode::ZeroPageY);
+        assert_eq!(cpu.decode(0xbe).1, Mode::AbsoluteY);
+        assert_eq!(cpu.decode(0xb4).1, Mode::ZeroPageX);
+        assert_eq!(cpu.decode(0x94).1, Mode::ZeroPageX);
+        assert_eq!(cpu.decode(0xb5).1, Mode::ZeroPageX, "LDA $nn,X");
+        assert!(cpu.decode_implemented(0x9e).is_none(), "$9E isn't STX");
+        let (_, mode) = cpu.decode(0x9e);
+        assert_eq!(mode, Mode::Implied);
+    }
+
+    #[test]
+    fn test_load_index_registers() {
+        // LDX then LDY, every mode
+        let cases = [
+            ("LDX #$00;", 0xa2, 0x00, Z_FLAG),
+            ("LDX $11;", 0xa6, 0x80, N_FLAG),
+            ("LDX $10,Y;", 0xb6, 0x80, N_FLAG),
+            ("LDX $0400;", 0xae, 0x7f, 0),
+            ("LDX $0400,Y;", 0xbe, 0x80, N_FLAG),
+            ("LDY #$81;", 0xa0, 0x81, N_FLAG),
+            ("LDY $10;", 0xa4, 0x00, Z_FLAG),
+            ("LDY $10,X;", 0xb4, 0x80, N_FLAG),
+            ("LDY $0400;", 0xac, 0x7f, 0),
+            ("LDY $0400,X;", 0xbc, 0x80, N_FLAG),
+        ];
+        for &(line, opcode, value, flags) in cases.iter() {
+            let program = encode(line).unwrap();
+            assert_eq!(program[0], opcode, "{}", line);
+
+            let mut cpu = Processor::new(None);
+            cpu.mem.load(0x0200, &program).unwrap();
+            cpu.mem.load(0x10, &[0x00, 0x80]).unwrap();
+            cpu.mem.load(0x0400, &[0x7f, 0x80]).unwrap();
+            cpu.state.pc = 0x0200;
+            // the index register not being loaded points one byte on
+            cpu.state.x = 1;
+            cpu.state.y = 1;
+            cpu.exec();
+
+            let loaded = if line.starts_with("LDX") {
+                cpu.state.x
+            } else {
+                cpu.state.y
+            };
+            assert_eq!(loaded, value, "{}", line);
+            assert_eq!(cpu.state.status & (N_FLAG | Z_FLAG), flags, "{}", line);
+            assert_eq!(cpu.state.pc, 0x0200 + program.len(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = assemble(
+            "
+            start:
+                LDA #$01;
+                JSR sub;
+                BNE start;
+            sub:
+                RTS     ;
+            handler:
+                RTI     ;
+            .nmivector handler
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(&program[0..2], &[0xa9, 0x01]);
+        assert_eq!(&program[2..5], &[0x20, 0x07, 0x80], "JSR $8007");
+        assert_eq!(&program[5..7], &[BNE, 0xf9], "BNE back 7 to $8000");
+        // the offset counts from the end of the branch, a branch to the
+        // next instruction is 0
+        assert_eq!(assemble("BEQ next;\nnext:\nNOP;").unwrap()[1], 0x00);
+        assert_eq!(&program[7..9], &[0x60, 0x40]);
+
+        let nmi = NMI_VECTOR - ROM_START;
+        assert_eq!(program.len(), MEMORY_MAX - ROM_START);
+        assert_eq!(&program[nmi..nmi + 2], &[0x08, 0x80], "handler at $8008");
+
+        assert_eq!(
+            assemble("JMP nowhere;"),
+            Err(AssembleError::UndefinedLabel(String::from("nowhere")))
+        );
+    }
+
+    #[test]
+    fn test_cycles() {
+        // lookup charges for the memory access, the opcode for itself
+        let modes = [
+            (Mode::Immediate, 2),
+            (Mode::ZeroPage, 3),
+            (Mode::ZeroPageX, 4),
+            (Mode::Absolute, 4),
+            (Mode::AbsoluteX, 4),
+            (Mode::AbsoluteY, 4),
+            (Mode::IndexedX, 6),
+            (Mode::IndexedY, 5),
+        ];
+        let ops: [(&str, Opcode); 3] = [
+            ("LDA", Processor::lda),
+            ("AND", Processor::and),
+            ("ADC", Processor::adc),
+        ];
+        let mut cpu = Processor::new(None);
+        for &(name, op) in ops.iter() {
+            for &(mode, cycles) in modes.iter() {
+                cpu.mem.load(0x0200, &[0x00, 0x10, 0x02]).unwrap();
+                cpu.state.pc = 0x0200;
+                cpu.state.status = 0;
+                cpu.cycles = 0;
+                op(&mut cpu, mode);
+                assert_eq!(cpu.cycles, cycles, "{} {:?}", name, mode);
+            }
+        }
+
+        // crossing a page costs one more
+        cpu.mem.load(0x0200, &[0xbd, 0xff, 0x10]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.state.x = 1;
+        cpu.cycles = 0;
+        cpu.lda(Mode::AbsoluteX);
+        assert_eq!(cpu.cycles, 5);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        // program at $0208, final PC, cycles
+        let cases: [(&str, &[u8], usize, u32); 10] = [
+            ("JMP", &[0x4c, 0x34, 0x12], 0x1234, 3),
+            ("JMP ()", &[0x6c, 0x10, 0x00], 0x4321, 5),
+            ("JSR", &[0x20, 0x34, 0x12], 0x1234, 6),
+            ("RTS", &[0x60], 0x0000, 6),
+            ("RTI", &[0x40], 0xffff, 6),
+            ("BRK", &[0x00], 0x8000, 7),
+            ("BNE not taken", &[0xd0, 0x10], 0x020a, 2),
+            ("BEQ", &[0xf0, 0x10], 0x021a, 3),
+            ("BEQ back a page", &[0xf0, 0xf0], 0x01fa, 4),
+            ("BEQ to the end of the page", &[0xf0, 0x7f], 0x0289, 3),
+        ];
+        for &(name, program, pc, cycles) in cases.iter() {
+            let mut cpu = Processor::new(None);
+            cpu.mem.load(0x0208, program).unwrap();
+            cpu.mem.write(0x10, 0x21);
+            cpu.mem.write(0x11, 0x43);
+            cpu.mem.set_vector(IRQ_BRK_VECTOR, 0x8000);
+            // RTS returns past $FFFF, RTI to it
+            for _ in 0..3 {
+                cpu.stack_push(0xff);
+            }
+            cpu.state.pc = 0x0208;
+            cpu.state.status = Z_FLAG;
+            cpu.exec();
+            assert_eq!(cpu.state.pc, pc, "{} PC", name);
+            assert_eq!(cpu.cycles, cycles, "{} cycles", name);
+        }
+
+        // $0208 can't reach past $0289, BEQ to the next page from $02F0
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x02f0, &[0xf0, 0x7f]).unwrap();
+        cpu.state.pc = 0x02f0;
+        cpu.state.status = Z_FLAG;
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0x0371, "BEQ to the next page PC");
+        assert_eq!(cpu.cycles, 4, "BEQ to the next page cycles");
+
+        // the page is that of the next instruction, not the branch: from
+        // $02FE the branch ends at $0300, so $0310 is no crossing and
+        // $02F0 is
+        for &(offset, pc, cycles) in
+            [(0x10, 0x0310, 3), (0xf0, 0x02f0, 4)].iter()
+        {
+            let mut cpu = Processor::new(None);
+            cpu.mem.load(0x02fe, &[0xf0, offset]).unwrap();
+            cpu.state.pc = 0x02fe;
+            cpu.state.status = Z_FLAG;
+            cpu.exec();
+            assert_eq!(cpu.state.pc, pc);
+            assert_eq!(cpu.cycles, cycles, "BEQ to {:#06x}", pc);
+        }
+    }
+
+    #[test]
+    fn test_store_cycles() {
+        let mut cpu = Processor::new(None);
+        // $10FF,X with X = 0 stays on the page, X = 1 crosses
+        cpu.mem.load(0x0200, &[0x9d, 0xff, 0x10]).unwrap();
+        for &(x, load) in [(0, 4), (1, 5)].iter() {
+            cpu.state.x = x;
+            cpu.state.pc = 0x0200;
+            cpu.cycles = 0;
+            cpu.sta(Mode::AbsoluteX);
+            assert_eq!(cpu.cycles, 5, "STA abs,X with X = {}", x);
+
+            cpu.state.pc = 0x0200;
+            cpu.cycles = 0;
+            cpu.lda(Mode::AbsoluteX);
+            assert_eq!(cpu.cycles, load, "LDA abs,X with X = {}", x);
+        }
+
+        cpu.state.y = 0;
+        cpu.state.pc = 0x0200;
+        cpu.cycles = 0;
+        cpu.sta(Mode::AbsoluteY);
+        assert_eq!(cpu.cycles, 5, "STA abs,Y");
+        cpu.state.pc = 0x0200;
+        cpu.cycles = 0;
+        cpu.sta(Mode::IndexedY);
+        assert_eq!(cpu.cycles, 6, "STA (zp),Y");
+    }
+
+    #[test]
+    fn test_data_directives() {
+        assert_eq!(assemble(".word $1234"), Ok(vec![0x34, 0x12]));
+        assert_eq!(
+            assemble(".byte $01, $02;\n.word $1234, $05;"),
+            Ok(vec![0x01, 0x02, 0x34, 0x12, 0x05, 0x00])
+        );
+
+        let program = assemble(
+            "
+            .word table
+            .org $C000
+            SEC;
+            LDA #$01;
+            CLC;
+            NOP;
+            table:
+            .word table, $BEEF
+            ",
+        )
+        .unwrap();
+        assert_eq!(&program[0..2], &[0x05, 0xc0], "table at $C005");
+        assert_eq!(program.len(), 0x4000 + 9);
+        assert_eq!(&program[0x4005..], &[0x05, 0xc0, 0xef, 0xbe]);
+
+        assert_eq!(
+            assemble("NOP;\n.org $7000"),
+            Err(AssembleError::InvalidSyntax(String::from(".org $7000")))
+        );
+        assert_eq!(
+            assemble(".byte $123"),
+            Err(AssembleError::InvalidSyntax(String::from(".byte $123")))
+        );
+    }
+
+    #[test]
+    fn test_entry_point() {
+        let (_, entry) = assemble_with_entry("NOP;").unwrap();
+        assert_eq!(entry, ROM_START);
+
+        let (program, entry) = assemble_with_entry(".org $C000\nNOP;").unwrap();
+        assert_eq!(entry, 0xC000);
+        assert_eq!(program[0x4000], NOP);
+
+        let (_, entry) = assemble_with_entry(
+            "
+            .org $C000
+            .byte $01, $02
+            start:
+            SEC;
+            .resetvector start
+            ",
+        )
+        .unwrap();
+        assert_eq!(entry, 0xC002);
+
+        let mut cpu = Processor::new(None);
+        cpu.run_program(".org $C000\nSEC;\nCLC;").unwrap();
+        assert_eq!(cpu.state.pc, 0xC002, "starts at the .org");
+    }
+
+    #[test]
+    fn test_jsr_rts_stack_wrap() {
+        // SP, where PCH and PCL land, SP after the JSR
+        let cases = [
+            (0xfd, 0x01fd, 0x01fc, 0xfb),
+            (0x01, 0x0101, 0x0100, 0xff),
+            (0x00, 0x0100, 0x01ff, 0xfe),
+            (0xff, 0x01ff, 0x01fe, 0xfd),
+        ];
+        for &(sp, pch_at, pcl_at, pushed_sp) in cases.iter() {
+            let mut cpu = Processor::new(None);
+            // 02FE: JSR $0310, 0310: RTS
+            cpu.mem.load(0x02fe, &[0x20, 0x10, 0x03]).unwrap();
+            cpu.mem.load(0x0310, &[0x60]).unwrap();
+            cpu.state.pc = 0x02fe;
+            cpu.state.sp = sp;
+            cpu.exec();
+            assert_eq!(cpu.state.pc, 0x0310);
+            assert_eq!(cpu.state.sp, pushed_sp, "SP {:#04x}", sp);
+            // the last byte of the JSR, $0300 with the carry from $02FE
+            assert_eq!(cpu.mem.peek(pch_at), 0x03, "PCH SP {:#04x}", sp);
+            assert_eq!(cpu.mem.peek(pcl_at), 0x00, "PCL SP {:#04x}", sp);
+
+            cpu.exec();
+            assert_eq!(cpu.state.pc, 0x0301, "returns past the JSR");
+            assert_eq!(cpu.state.sp, sp);
+        }
+    }
+
+    #[test]
+    fn test_pull_flags() {
+        let mut cpu = Processor::new(None);
+        cpu.state.sp = 0xfd;
+        cpu.mem.load(0x0200, &[0x68, 0x68, 0x28]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.stack_push(B_FLAG | C_FLAG);
+        cpu.stack_push(0x80);
+        cpu.stack_push(0x00);
+
+        cpu.exec();
+        assert_eq!(cpu.state.a, 0x00);
+        assert_eq!(cpu.state.status & (N_FLAG | Z_FLAG), Z_FLAG, "PLA $00");
+        cpu.exec();
+        assert_eq!(cpu.state.a, 0x80);
+        assert_eq!(cpu.state.status & (N_FLAG | Z_FLAG), N_FLAG, "PLA $80");
+        cpu.exec();
+        assert_eq!(cpu.state.status & B_FLAG, 0, "PLP drops B");
+        assert_eq!(cpu.state.status & C_FLAG, C_FLAG);
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut cpu = Processor::new(None);
+        cpu.mem.set_vector(IRQ_BRK_VECTOR, 0x9000);
+        cpu.mem.set_vector(NMI_VECTOR, 0xA000);
+        cpu.mem.load(0x0200, &[0x00, 0xff]).unwrap();
+
+        cpu.state.sp = 0xfd;
+        cpu.state.pc = 0x0200;
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0x9000);
+        assert_eq!(cpu.stack_pop() & B_FLAG, B_FLAG);
+        assert_eq!((cpu.stack_pop(), cpu.stack_pop()), (0x02, 0x02));
+
+        // NMI due mid BRK takes the NMI vector, the pushed B stays set
+        cpu.state.sp = 0xfd;
+        cpu.state.pc = 0x0200;
+        cpu.cycles = 0;
+        cpu.schedule_nmi_at(3);
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0xA000, "hijacked through $FFFA");
+        assert_eq!(cpu.nmi_at, None, "the NMI is consumed");
+        assert_eq!(cpu.state.sp, 0xfa, "one set of pushes");
+        assert_eq!(cpu.stack_pop() & B_FLAG, B_FLAG);
+
+        // too late for the vector fetch, it's taken after the BRK
+        cpu.state.sp = 0xfd;
+        cpu.state.pc = 0x0200;
+        cpu.cycles = 0;
+        cpu.schedule_nmi_at(6);
+        cpu.exec();
+        assert_eq!(cpu.state.pc, 0xA000);
+        assert_eq!(cpu.state.sp, 0xf7, "BRK then NMI pushes");
+        assert_eq!(cpu.mem.peek(0x01fb) & B_FLAG, B_FLAG, "BRK's status");
+        assert_eq!(cpu.mem.peek(0x01f8) & B_FLAG, 0, "NMI's status");
+    }
+
+    #[test]
+    fn test_unused_status_bit() {
+        let mut cpu = Processor::new(None);
+        cpu.state.sp = 0xfd;
+        // PLP a status with bit 5 clear & B set
+        cpu.stack_push(N_FLAG | B_FLAG | C_FLAG);
+        cpu.mem.load(0x0200, &[0x28, 0x08]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.exec();
+        assert_eq!(cpu.state.status, N_FLAG | F_FLAG | C_FLAG);
+
+        // reads and pushes always have it set
+        cpu.state.status = 0;
+        assert_eq!(cpu.get_status(), F_FLAG);
+        cpu.exec(); // PHP
+        assert_eq!(cpu.stack_pop(), F_FLAG | B_FLAG);
+    }
+
+    #[test]
+    fn test_unofficial_nops() {
+        let mut cpu = Processor::new(None);
+        // NOP abs,X / NOP zp / NOP #imm / NOP (1 byte)
+        let program = [0x1c, 0xff, 0x02, 0x04, 0x10, 0x80, 0x10, 0x1a];
+        cpu.mem.load(0x0200, &program).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.state.x = 0x01;
+
+        let expected = [(0x0203, 5), (0x0205, 3), (0x0207, 2), (0x0208, 2)];
+        for &(pc, cycles) in expected.iter() {
+            cpu.cycles = 0;
+            cpu.exec();
+            assert_eq!(cpu.state.pc, pc);
+            assert_eq!(cpu.cycles, cycles, "at {:#06x}", pc);
+        }
+        assert_eq!(cpu.state.x, 0x01);
+        assert_eq!(cpu.state.a, 0x00);
+    }
+
+    #[test]
+    fn test_group_one_decode() {
+        // $0010 holds the operand and, indexed by X, the pointer to $0300
+        let machine = |status| {
+            let mut cpu = Processor::new(None);
+            cpu.mem.load(0x0010, &[0x0a, 0x00, 0x00, 0x03]).unwrap();
+            cpu.mem.load(0x0200, &[0x00, 0x10, 0x03]).unwrap();
+            cpu.mem.load(0x0300, &[0x0a]).unwrap();
+            cpu.mem.load(0x0310, &[0x0a]).unwrap();
+            cpu.state.pc = 0x0200;
+            cpu.state.a = 0x0c;
+            cpu.state.x = 0x02;
+            cpu.state.status = status;
+            cpu
+        };
+        let cases: [(u8, Opcode, Mode); 8] = [
+            (0x01, Processor::ora, Mode::IndexedX),
+            (0x25, Processor::and, Mode::ZeroPage),
+            (0x49, Processor::eor, Mode::Immediate),
+            (0x6d, Processor::adc, Mode::Absolute),
+            (0x85, Processor::sta, Mode::ZeroPage),
+            (0xa1, Processor::lda, Mode::IndexedX),
+            (0xc9, Processor::cmp, Mode::Immediate),
+            (0xe5, Processor::sbc, Mode::ZeroPage),
+        ];
+        // Compare what the handlers do rather than their addresses, see
+        // test_implied_decode
+        for &(value, instruction, mode) in cases.iter() {
+            for &status in [F_FLAG, !B_FLAG].iter() {
+                let mut cpu = machine(status);
+                let (decoded, decoded_mode) = cpu.decode(value);
+                assert_eq!(decoded_mode, mode, "{:#04x} mode", value);
+                decoded(&mut cpu, mode);
+                let mut expected = machine(status);
+                instruction(&mut expected, mode);
+                assert_eq!(
+                    format!("{:?}", cpu.state),
+                    format!("{:?}", expected.state),
+                    "{:#04x} handler",
+                    value
+                );
+                assert_eq!(cpu.cycles, expected.cycles, "{:#04x}", value);
+                for address in 0x0000..0x0400 {
+                    assert_eq!(
+                        cpu.mem.peek(address),
+                        expected.mem.peek(address),
+                        "{:#04x} memory",
+                        value
+                    );
+                }
+            }
+        }
+
+        // A = 0b1100 against #0b1010
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x0200, &[0x09, 0x0a, 0x49, 0x0a]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.state.a = 0x0c;
+        cpu.exec();
+        assert_eq!(cpu.state.a, 0x0e, "ORA");
+        cpu.exec();
+        assert_eq!(cpu.state.a, 0x04, "EOR");
+    }
+
+    #[test]
+    fn test_group_one_modes() {
+        let cpu = Processor::new(None);
+        let cases = [
+            (0xa1, Mode::IndexedX),
+            (0xa5, Mode::ZeroPage),
+            (0xa9, Mode::Immediate),
+            (0xad, Mode::Absolute),
+            (0xb1, Mode::IndexedY),
+            (0xb5, Mode::ZeroPageX),
+            (0xb9, Mode::AbsoluteY),
+            (0xbd, Mode::AbsoluteX),
+        ];
+        for &(value, mode) in cases.iter() {
+            assert_eq!(cpu.decode(value).1, mode, "LDA {:#04x}", value);
+        }
+    }
+
+    #[test]
+    fn test_implied_decode() {
+        let machine = |status| {
+            let mut cpu = Processor::new(None);
+            cpu.state.pc = 0x0200;
+            cpu.state.sp = 0xfd;
+            cpu.state.a = 0x81;
+            cpu.state.x = 0x42;
+            cpu.state.y = 0xc3;
+            cpu.state.status = status;
+            cpu
+        };
+        let cases: [(u8, Opcode); 25] = [
+            (0x08, Processor::php),
+            (0x18, Processor::clc),
+            (0x28, Processor::plp),
+            (0x38, Processor::sec),
+            (0x48, Processor::pha),
+            (0x58, Processor::cli),
+            (0x68, Processor::pla),
+            (0x78, Processor::sei),
+            (0x88, Processor::dey),
+            (0x8a, Processor::txa),
+            (0x98, Processor::tya),
+            (0x9a, Processor::txs),
+            (0xa8, Processor::tay),
+            (0xaa, Processor::tax),
+            (0xb8, Processor::clv),
+            (0xba, Processor::tsx),
+            (0xc8, Processor::iny),
+            (0xca, Processor::dex),
+            (0xd8, Processor::cld),
+            (0xe8, Processor::inx),
+            (0xea, Processor::nop),
+            (0xf8, Processor::sed),
+            (0x00, Processor::brk),
+            (0x40, Processor::rti),
+            (0x60, Processor::rts),
+        ];
+        // Handler addresses can differ between references in optimised
+        // builds, so compare what the handlers do. One status with every
+        // flag clear and one with them set tells the flag ops apart.
+        for &(value, instruction) in cases.iter() {
+            for &status in [F_FLAG, !B_FLAG].iter() {
+                let mut cpu = machine(status);
+                let (decoded, mode) = cpu.decode(value);
+                assert_eq!(mode, Mode::Implied, "{:#04x} mode", value);
+                decoded(&mut cpu, mode);
+                let mut expected = machine(status);
+                instruction(&mut expected, Mode::Implied);
+                assert_eq!(
+                    format!("{:?}", cpu.state),
+                    format!("{:?}", expected.state),
+                    "{:#04x} handler",
+                    value
+                );
+                assert_eq!(cpu.cycles, expected.cycles, "{:#04x}", value);
+                for address in 0x1fb..=0x1ff {
+                    assert_eq!(
+                        cpu.mem.peek(address),
+                        expected.mem.peek(address),
+                        "{:#04x} stack",
+                        value
+                    );
+                }
+            }
+        }
+
+        // TAX, DEX, TXA, TXS, CLV
+        let mut cpu = Processor::new(None);
+        cpu.mem
+            .load(0x0200, &[0xaa, 0xca, 0x8a, 0x9a, 0xb8])
+            .unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.state.a = 0x01;
+        cpu.state.status = V_FLAG;
+        cpu.exec();
+        assert_eq!(cpu.state.x, 0x01);
+        cpu.exec();
+        assert_eq!(cpu.state.x, 0x00);
+        assert_eq!(cpu.state.status & Z_FLAG, Z_FLAG);
+        cpu.exec();
+        assert_eq!(cpu.state.a, 0x00);
+        cpu.exec();
+        assert_eq!(cpu.state.sp, 0x00);
+        assert_eq!(cpu.state.status, V_FLAG | Z_FLAG, "TXS leaves flags");
+        cpu.exec();
+        assert_eq!(cpu.state.status, Z_FLAG);
+        assert_eq!(cpu.state.pc, 0x0205);
+    }
+
+    #[test]
+    fn test_compare() {
+        // register, operand, expected C, Z & N
+        let cases = [
+            (0x10, 0x01, C_FLAG),
+            (0x10, 0x10, C_FLAG | Z_FLAG),
+            (0x01, 0x10, N_FLAG),
+        ];
+        let mut cpu = Processor::new(None);
+
+        for &opcode in [0xc9, 0xe0, 0xc0].iter() {
+            for &(register, operand, expected) in cases.iter() {
+                cpu.mem.load(0x8000, &[opcode, operand]).unwrap();
+                cpu.state.pc = 0x8000;
+                cpu.state.status = 0;
+                match opcode {
+                    0xc9 => cpu.state.a = register,
+                    0xe0 => cpu.state.x = register,
+                    _ => cpu.state.y = register,
+                }
+                cpu.exec();
+
+                assert_eq!(
+                    cpu.state.status, expected,
+                    "{:#04x} register: {:#04x} operand: {:#04x}",
+                    opcode, register, operand
+                );
+                assert_eq!(cpu.state.pc, 0x8002);
+            }
+        }
+    }
+
+    #[test]
+    fn test_illegal_sbc() {
+        let mut cpu = Processor::new(None);
+        for &opcode in [0xe9, 0xeb].iter() {
+            cpu.mem.load(0x8000, &[opcode, 0x01]).unwrap();
+            cpu.state.pc = 0x8000;
+            cpu.state.a = 0x05;
+            cpu.state.status = C_FLAG;
+            cpu.exec();
+
+            assert_eq!(cpu.state.a, 0x04, "SBC {:#04x}", opcode);
+            assert_eq!(cpu.state.status, C_FLAG, "no borrow {:#04x}", opcode);
+            assert_eq!(cpu.state.pc, 0x8002);
+        }
+    }
+
+    #[test]
+    fn test_compare_flags() {
+        let cases = [
+            // register, operand, N, Z, C
+            (0x10, 0x20, true, false, false),
+            (0x20, 0x20, false, true, true),
+            (0x30, 0x20, false, false, true),
+            (0x00, 0x00, false, true, true),
+            // unsigned, 0xFF is greater and the difference is negative
+            (0xff, 0x01, true, false, true),
+            (0x01, 0xff, false, false, false),
+            (0x90, 0x10, true, false, true),
+        ];
+        // CMP, CPX & CPY immediate
+        for &opcode in [0xc9, 0xe0, 0xc0].iter() {
+            for &(register, operand, n, z, c) in cases.iter() {
+                let mut cpu = Processor::new(None);
+                cpu.mem.load(0x0200, &[opcode, operand]).unwrap();
+                cpu.state.pc = 0x0200;
+                cpu.state.a = register;
+                cpu.state.x = register;
+                cpu.state.y = register;
+                cpu.exec();
+
+                let status = cpu.state.status;
+                let case = format!(
+                    "{:#04x} {:#04x} vs {:#04x}",
+                    opcode, register, operand
+                );
+                assert_eq!(status & N_FLAG != 0, n, "N {}", case);
+                assert_eq!(status & Z_FLAG != 0, z, "Z {}", case);
+                assert_eq!(status & C_FLAG != 0, c, "C {}", case);
+            }
+        }
+
+        // DCP compares A with the decremented byte
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x0200, &[0xc7, 0x10]).unwrap();
+        cpu.mem.write(0x10, 0x21);
+        cpu.state.pc = 0x0200;
+        cpu.state.a = 0x20;
+        cpu.exec();
+        assert_eq!(cpu.mem.peek(0x10), 0x20);
+        assert_eq!(
+            cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG),
+            Z_FLAG | C_FLAG
+        );
+    }
+
+    #[test]
+    fn test_logical_ops() {
+        // opcodes for immediate & zero page, result of 0b1100 with 0b1010
+        let cases = [
+            ("AND", 0x29, 0x25, 0b1000),
+            ("ORA", 0x09, 0x05, 0b1110),
+            ("EOR", 0x49, 0x45, 0b0110),
+        ];
+        let mut cycles = Vec::new();
+        for &(name, immediate, zero_page, result) in cases.iter() {
+            let mut cpu = Processor::new(None);
+            cpu.mem
+                .load(0x0200, &[immediate, 0b1010, zero_page, 0x10])
+                .unwrap();
+            cpu.mem.write(0x10, 0b1010);
+            cpu.state.pc = 0x0200;
+            let mut elapsed = Vec::new();
+            for _ in 0..2 {
+                let start = cpu.cycles;
+                cpu.state.a = 0b1100;
+                cpu.exec();
+                assert_eq!(cpu.state.a, result, "{}", name);
+                assert_eq!(cpu.state.status & (N_FLAG | Z_FLAG), 0, "{}", name);
+                elapsed.push(cpu.cycles - start);
+            }
+            assert_eq!(cpu.state.pc, 0x0204, "{}", name);
+            cycles.push(elapsed);
+        }
+        assert_eq!(cycles[0], vec![2, 3]);
+        assert!(cycles.iter().all(|c| *c == cycles[0]));
+    }
+
+    #[test]
+    fn test_shift_flags() {
+        fn shift(program: &[u8], a: u8, memory: u8, status: u8) -> Processor {
+            let mut cpu = Processor::new(None);
+            cpu.mem.load(0x8000, program).unwrap();
+            cpu.mem.write(0x10, memory);
+            cpu.state.pc = 0x8000;
+            cpu.state.a = a;
+            cpu.state.status = status;
+            cpu.exec();
+            cpu
+        }
+
+        // ASL $10, flags follow memory and not A
+        let cpu = shift(&[0x06, 0x10], 0x00, 0x40, 0);
+        assert_eq!(cpu.mem.peek(0x10), 0x80);
+        assert_eq!(cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG), N_FLAG);
+
+        // ASL A
+        let cpu = shift(&[0x0a], 0x80, 0x00, 0);
+        assert_eq!(cpu.state.a, 0x00);
+        assert_eq!(
+            cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG),
+            Z_FLAG | C_FLAG
+        );
+
+        // LSR $10 carries out bit 0
+        let cpu = shift(&[0x46, 0x10], 0xff, 0x01, N_FLAG);
+        assert_eq!(cpu.mem.peek(0x10), 0x00);
+        assert_eq!(
+            cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG),
+            Z_FLAG | C_FLAG
+        );
+
+        // ROR A moves the carry into bit 7
+        let cpu = shift(&[0x6a], 0x02, 0x00, C_FLAG);
+        assert_eq!(cpu.state.a, 0x81);
+        assert_eq!(cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG), N_FLAG);
+
+        // ROL $10 carries out bit 7
+        let cpu = shift(&[0x26, 0x10], 0x00, 0x80, 0);
+        assert_eq!(cpu.mem.peek(0x10), 0x00);
+        assert_eq!(
+            cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG),
+            Z_FLAG | C_FLAG
+        );
+    }
+
+    #[test]
+    fn test_sbc_overflow() {
+        let cases = [
+            // a, operand, carry in, result, V, carry out
+            (0x50, 0xf0, true, 0x60, false, false),
+            (0x50, 0xb0, true, 0xa0, true, false),
+            (0x50, 0x70, true, 0xe0, false, false),
+            (0x50, 0x30, true, 0x20, false, true),
+            (0xd0, 0xf0, true, 0xe0, false, false),
+            (0xd0, 0xb0, true, 0x20, false, true),
+            (0xd0, 0x70, true, 0x60, true, true),
+            (0xd0, 0x30, true, 0xa0, false, true),
+            // the borrow alone can overflow
+            (0x80, 0x00, false, 0x7f, true, true),
+        ];
+        let mut cpu = Processor::new(None);
+        for &(a, operand, carry, result, overflow, carry_out) in cases.iter() {
+            cpu.mem.load(0x8000, &[0xe9, operand]).unwrap();
+            cpu.state.pc = 0x8000;
+            cpu.state.a = a;
+            cpu.state.status = if carry { C_FLAG } else { 0 };
+            cpu.exec();
+
+            let case = format!("{:#04x} - {:#04x} carry {}", a, operand, carry);
+            assert_eq!(cpu.state.a, result, "{}", case);
+            assert_eq!(cpu.state.status & V_FLAG != 0, overflow, "V {}", case);
+            assert_eq!(cpu.state.status & C_FLAG != 0, carry_out, "C {}", case);
+        }
+    }
+
+    #[test]
+    fn test_assemble_sbc() {
+        let modes = [
+            ("SBC #$10;", 0xe9),
+            ("SBC $10;", 0xe5),
+            ("SBC $10,X;", 0xf5),
+            ("SBC $1000;", 0xed),
+            ("SBC $1000,X;", 0xfd),
+            ("SBC $1000,Y;", 0xf9),
+            ("SBC ($10,X);", 0xe1),
+            ("SBC ($10),Y;", 0xf1),
+        ];
+        for &(line, opcode) in modes.iter() {
+            assert_eq!(encode(line).unwrap()[0], opcode, "{}", line);
+        }
+
+        // A, flags after SEC; LDA; SBC
+        let cases = [
+            ("#$50", "#$F0", 0x60, 0),
+            ("#$10", "#$10", 0x00, Z_FLAG | C_FLAG),
+            ("#$10", "#$20", 0xf0, N_FLAG),
+            ("#$90", "#$10", 0x80, N_FLAG | C_FLAG),
+        ];
+        for &(a, operand, result, flags) in cases.iter() {
+            let mut cpu = Processor::new(None);
+            let program = format!("SEC;\nLDA {};\nSBC {};", a, operand);
+            cpu.run_program(&program).unwrap();
+            assert_eq!(cpu.state.a, result, "{}", program);
+            assert_eq!(
+                cpu.state.status & (N_FLAG | Z_FLAG | C_FLAG),
+                flags,
+                "{}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn test_decimal_flag_ignored() {
+        // Invalid BCD digits that a 6502 would decimal adjust
+        let cases = [
+            // a, operand, result, flags
+            (0x0f, 0x01, 0x10, 0),
+            (0x09, 0x0b, 0x14, 0),
+            (0x7f, 0x01, 0x80, N_FLAG | V_FLAG),
+            (0x9a, 0x66, 0x00, Z_FLAG | C_FLAG),
+        ];
+        let mut cpu = Processor::new(None);
+        for &(a, operand, result, flags) in cases.iter() {
+            cpu.mem.load(0x8000, &[ADC, operand]).unwrap();
+            cpu.state.pc = 0x8000;
+            cpu.state.a = a;
+            cpu.state.status = D_FLAG;
+            cpu.exec();
+
+            assert_eq!(cpu.state.a, result, "{:#04x} + {:#04x}", a, operand);
+            assert_eq!(cpu.state.status, D_FLAG | flags);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_mode() {
+        let mut cpu = Processor::new(None);
+        cpu.state.pc = 0x8000;
+        cpu.asl(Mode::Immediate);
+
+        assert_eq!(
+            cpu.fault,
+            Some(Fault::UnsupportedMode("ASL", Mode::Immediate))
+        );
+        assert_eq!(cpu.state.pc, 0x8002, "instruction should be skipped");
+    }
+
+    #[test]
+    fn test_strict() {
+        // SLO ($nn,X) has no handler
+        let mut cpu = Processor::new(None);
+        cpu.mem.load(0x0200, &[0x03, 0x10]).unwrap();
+        cpu.state.pc = 0x0200;
+        cpu.exec();
+        assert_eq!(cpu.fault, None);
+        assert_eq!(cpu.state.pc, 0x0201, "runs as a NOP by default");
+
+        cpu.strict = true;
+        cpu.state.pc = 0x0200;
+        cpu.exec();
+        assert_eq!(cpu.fault, Some(Fault::UnimplementedOpcode(0x03, 0x0200)));
+        assert_eq!(cpu.state.pc, 0x0200);
     }
 }
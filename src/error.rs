@@ -0,0 +1,183 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    // Smaller than the 16 byte iNES header
+    TooShort(usize),
+    InvalidMagic,
+    // gzip stream, and the gzip feature is off
+    Compressed,
+    // gzip stream that doesn't decompress
+    InvalidGzip(std::io::Error),
+    // Recognised image format without a loader, UNIF or FDS
+    UnsupportedFormat(&'static str),
+    // PRG ROM runs past the end of the file
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    // Without a mapper the PRG ROM has to be one or two 16K banks
+    PrgRomSize(usize),
+    // Header doesn't match what the caller asked for
+    HeaderMismatch {
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+    // IPS patch that doesn't parse
+    InvalidPatch(&'static str),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AssembleError {
+    UnknownInstruction(String),
+    InvalidSyntax(String),
+    UndefinedLabel(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryError {
+    OutOfRange { address: usize, len: usize },
+    // MemoryConfig with no RAM to mirror
+    EmptyRam,
+}
+
+#[derive(Debug)]
+pub enum RustyNesError {
+    Cartridge(CartridgeError),
+    Assemble(AssembleError),
+    Memory(MemoryError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => {
+                write!(f, "unable to read cartridge: {}", e)
+            }
+            CartridgeError::TooShort(len) => write!(
+                f,
+                "cartridge is {} bytes, too short for an iNES header",
+                len
+            ),
+            CartridgeError::InvalidMagic => {
+                write!(f, "cartridge is missing the iNES \"NES\\x1A\" magic")
+            }
+            CartridgeError::Compressed => write!(
+                f,
+                "cartridge is gzip-compressed, decompress it before loading"
+            ),
+            CartridgeError::InvalidGzip(error) => {
+                write!(f, "invalid gzip stream, {}", error)
+            }
+            CartridgeError::UnsupportedFormat(format) => write!(
+                f,
+                "cartridge is a {} image, only iNES and NES 2.0 are supported",
+                format
+            ),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "cartridge is truncated, expected {} bytes but got {}",
+                expected, actual
+            ),
+            CartridgeError::PrgRomSize(len) => write!(
+                f,
+                "{} bytes of PRG ROM, boards without a mapper hold 16KB or 32KB",
+                len
+            ),
+            CartridgeError::HeaderMismatch {
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "cartridge {} is {}, expected {}",
+                field, actual, expected
+            ),
+            CartridgeError::InvalidPatch(reason) => {
+                write!(f, "invalid IPS patch, {}", reason)
+            }
+        }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleError::UnknownInstruction(line) => {
+                write!(f, "unknown instruction: {}", line)
+            }
+            AssembleError::InvalidSyntax(line) => {
+                write!(f, "invalid syntax: {}", line)
+            }
+            AssembleError::UndefinedLabel(label) => {
+                write!(f, "undefined label: {}", label)
+            }
+        }
+    }
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::OutOfRange { address, len } => write!(
+                f,
+                "{} bytes at {:#06x} do not fit in the address space",
+                len, address
+            ),
+            MemoryError::EmptyRam => write!(f, "RAM size can't be zero"),
+        }
+    }
+}
+
+impl fmt::Display for RustyNesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RustyNesError::Cartridge(e) => write!(f, "Cartridge error: {}", e),
+            RustyNesError::Assemble(e) => write!(f, "Assembler error: {}", e),
+            RustyNesError::Memory(e) => write!(f, "Memory error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) | CartridgeError::InvalidGzip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+impl std::error::Error for MemoryError {}
+
+impl std::error::Error for RustyNesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RustyNesError::Cartridge(e) => Some(e),
+            RustyNesError::Assemble(e) => Some(e),
+            RustyNesError::Memory(e) => Some(e),
+        }
+    }
+}
+
+impl From<CartridgeError> for RustyNesError {
+    fn from(e: CartridgeError) -> Self {
+        RustyNesError::Cartridge(e)
+    }
+}
+
+impl From<AssembleError> for RustyNesError {
+    fn from(e: AssembleError) -> Self {
+        RustyNesError::Assemble(e)
+    }
+}
+
+impl From<MemoryError> for RustyNesError {
+    fn from(e: MemoryError) -> Self {
+        RustyNesError::Memory(e)
+    }
+}
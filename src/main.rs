@@ -4,22 +4,47 @@ extern crate lazy_static;
 use std::env;
 
 pub mod cpu;
+pub mod error;
 pub mod nes;
 
 use cpu::base::Processor;
+use nes::ppu::{frame_to_ppm, SCREEN_HEIGHT, SCREEN_WIDTH};
 use nes::Nes;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let filepath = &args[1];
+    // --screenshot <path> writes the last frame as a PPM once the CPU stops
+    let screenshot = args
+        .iter()
+        .position(|arg| arg == "--screenshot")
+        .and_then(|i| args.get(i + 1));
 
-    let cpu = Processor::new(None);
+    let mut cpu = Processor::new(None);
+    cpu.trace = true;
     let mut nes = Nes::new(cpu);
-    nes.load_cartridge(filepath);
+    if let Err(e) = nes.load_cartridge(filepath) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    println!("iNES Header {:?}", nes.cartridge.header);
-    println!("ROM size {:?}", nes.cartridge.rom.len());
+    println!("{}", nes.cartridge);
 
     // it's possible to run the nestest.nes w/o any GFX by starting execution at 0x0C000
-    nes.run(Some(0x0C000));
+    if let Err(e) = nes.run(Some(0x0C000)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(path) = screenshot {
+        let ppm = frame_to_ppm(
+            &nes.cpu.mem.ppu.frame_buffer,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+        );
+        if let Err(e) = std::fs::write(path, ppm) {
+            eprintln!("unable to write screenshot {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,94 @@
+// $4000-$4013 are the write-only channel registers, $4015 enables the
+// channels and reads back their status, $4017 sets the frame counter
+pub const APU_REGISTERS_START: usize = 0x4000;
+pub const APU_STATUS: usize = 0x4015;
+pub const APU_FRAME_COUNTER: usize = 0x4017;
+
+// Length counter loads, indexed by bits 3-7 of the channel's fourth register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24,
+    18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const FRAME_IRQ_FLAG: u8 = 0b0100_0000;
+
+/**
+ * Audio processing unit, only the register state $4015 reports for now:
+ * the pulse, triangle and noise length counters and the frame IRQ
+ */
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Apu {
+    // $4015 channel enables, pulse 1, pulse 2, triangle, noise, DMC
+    enabled: u8,
+    length: [u8; 4],
+    frame_irq: bool,
+    pub frame_counter: u8,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu::default()
+    }
+
+    pub fn write_register(&mut self, address: usize, value: u8) {
+        match address {
+            APU_STATUS => {
+                self.enabled = value & 0b0001_1111;
+                // a disabled channel's length counter is cleared
+                for (channel, length) in self.length.iter_mut().enumerate() {
+                    if self.enabled & (1 << channel) == 0 {
+                        *length = 0;
+                    }
+                }
+            }
+            APU_FRAME_COUNTER => self.frame_counter = value,
+            // $4003, $4007, $400B & $400F load the length counter
+            0x4003 | 0x4007 | 0x400b | 0x400f => {
+                let channel = (address - APU_REGISTERS_START) / 4;
+                if self.enabled & (1 << channel) != 0 {
+                    self.length[channel] = LENGTH_TABLE[value as usize >> 3];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /**
+     * $4015 read, a bit per channel still sounding and the frame IRQ, which
+     * the read acknowledges
+     */
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        for (channel, &length) in self.length.iter().enumerate() {
+            if length > 0 {
+                status |= 1 << channel;
+            }
+        }
+        if self.frame_irq {
+            status |= FRAME_IRQ_FLAG;
+        }
+        self.frame_irq = false;
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counters() {
+        let mut apu = Apu::new();
+        // disabled channels ignore the load
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.read_status(), 0);
+
+        apu.write_register(APU_STATUS, 0b0000_0101);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x400b, 0x08);
+        assert_eq!(apu.read_status(), 0b0000_0101);
+
+        apu.write_register(APU_STATUS, 0b0000_0100);
+        assert_eq!(apu.read_status(), 0b0000_0100, "pulse 1 was cleared");
+    }
+}
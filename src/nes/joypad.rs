@@ -0,0 +1,168 @@
+// Bits 7-5 of a controller read are not driven by the joypad and float to
+// whatever was last on the data bus, which is the high byte of $4016/$4017
+pub const OPEN_BUS_BITS: u8 = 0x40;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/**
+ * The order the controller shifts its buttons out, the first read of
+ * $4016/$4017 after a strobe returns A. `Button::bit_index` is the position
+ * in here and in `Joypad::buttons`.
+ */
+pub const BUTTON_ORDER: [Button; 8] = [
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+    Button::Up,
+    Button::Down,
+    Button::Left,
+    Button::Right,
+];
+
+impl Button {
+    pub fn bit_index(self) -> u8 {
+        match self {
+            Button::A => 0,
+            Button::B => 1,
+            Button::Select => 2,
+            Button::Start => 3,
+            Button::Up => 4,
+            Button::Down => 5,
+            Button::Left => 6,
+            Button::Right => 7,
+        }
+    }
+}
+
+/**
+ * Which buttons are held, for frontends that don't want to pack bits
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ButtonState {
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+impl ButtonState {
+    pub fn is_held(&self, button: Button) -> bool {
+        match button {
+            Button::A => self.a,
+            Button::B => self.b,
+            Button::Select => self.select,
+            Button::Start => self.start,
+            Button::Up => self.up,
+            Button::Down => self.down,
+            Button::Left => self.left,
+            Button::Right => self.right,
+        }
+    }
+}
+
+/**
+ * Standard NES controller. Button state is latched into a shift register
+ * while the strobe is high and shifted out one bit per read once it drops.
+ * Bit order is BUTTON_ORDER.
+ */
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Joypad {
+    pub buttons: u8,
+    strobe: bool,
+    index: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad::default()
+    }
+
+    pub fn set_button(&mut self, button: Button, down: bool) {
+        let mask = 1 << button.bit_index();
+        if down {
+            self.buttons |= mask;
+        } else {
+            self.buttons &= !mask;
+        }
+    }
+
+    pub fn set_state(&mut self, state: &ButtonState) {
+        for &button in BUTTON_ORDER.iter() {
+            self.set_button(button, state.is_held(button));
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        // Official controllers report 1s after all eight buttons are read
+        if self.index > 7 {
+            return OPEN_BUS_BITS | 1;
+        }
+
+        let bit = (self.buttons >> self.index) & 1;
+        if !self.strobe {
+            self.index += 1;
+        }
+
+        OPEN_BUS_BITS | bit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_state() {
+        let mut joypad = Joypad::new();
+        joypad.set_state(&ButtonState {
+            a: true,
+            start: true,
+            ..ButtonState::default()
+        });
+
+        joypad.write(1);
+        joypad.write(0);
+        let bits: Vec<u8> = (0..8).map(|_| joypad.read() & 1).collect();
+        // A, B, Select, Start, Up, Down, Left, Right
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_button_order() {
+        assert_eq!(Button::A.bit_index(), 0);
+        assert_eq!(Button::Right.bit_index(), 7);
+        for (index, button) in BUTTON_ORDER.iter().enumerate() {
+            assert_eq!(button.bit_index() as usize, index);
+        }
+
+        let mut joypad = Joypad::new();
+        for &button in BUTTON_ORDER.iter() {
+            joypad.set_button(button, true);
+        }
+        assert_eq!(joypad.buttons, 0xff);
+        joypad.set_button(Button::Start, false);
+        assert_eq!(joypad.buttons, 0xf7);
+    }
+}
@@ -0,0 +1,221 @@
+use super::{BankLayout, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+// Bank select bit 6 swaps the $8000 and $C000 windows
+const PRG_MODE: u8 = 0b0100_0000;
+const BANK_REGISTER: u8 = 0b0000_0111;
+const R6: usize = 6;
+const R7: usize = 7;
+const A12: usize = 0x1000;
+// The real chip ignores A12 rises that follow a short low period, which
+// hides the sprite fetches' $2xxx dummy reads. Counting fetches instead of
+// CPU cycles gives the same result while the PPU reports every fetch.
+const A12_LOW_FETCHES: u8 = 3;
+
+/**
+ * MMC3 (mapper 4), PRG banking and the scanline IRQ counter. CHR banking,
+ * mirroring & PRG RAM protect are not implemented.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Mmc3 {
+    prg: Vec<u8>,
+    bank_select: u8,
+    registers: [u8; 8],
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12_low_count: u8,
+}
+
+impl Mmc3 {
+    pub fn new(prg: &[u8]) -> Mmc3 {
+        Mmc3 {
+            prg: prg.to_vec(),
+            ..Mmc3::default()
+        }
+    }
+
+    fn banks(&self) -> usize {
+        (self.prg.len() / PRG_BANK_SIZE).max(1)
+    }
+
+    fn bank_offset(&self, bank: usize) -> usize {
+        (bank % self.banks()) * PRG_BANK_SIZE
+    }
+
+    /**
+     * ROM offsets of the four 8K windows from $8000. R7 and the last bank
+     * never move, the PRG mode picks whether R6 or the second-last bank
+     * sits at $8000 with the other one at $C000.
+     */
+    fn prg_offsets(&self) -> [usize; 4] {
+        let r6 = self.registers[R6] as usize;
+        let r7 = self.registers[R7] as usize;
+        let second_last = self.banks().saturating_sub(2);
+        let last = self.banks() - 1;
+        let (low, high) = if self.bank_select & PRG_MODE == 0 {
+            (r6, second_last)
+        } else {
+            (second_last, r6)
+        };
+        [low, r7, high, last].map(|bank| self.bank_offset(bank))
+    }
+
+    fn clock_scanline(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_write(&mut self, address: usize, value: u8) {
+        let even = address & 1 == 0;
+        match address {
+            0x8000..=0x9FFF if even => self.bank_select = value,
+            0x8000..=0x9FFF => {
+                let register = self.bank_select & BANK_REGISTER;
+                self.registers[register as usize] = value;
+            }
+            0xC000..=0xDFFF if even => self.irq_latch = value,
+            0xC000..=0xDFFF => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xE000..=0xFFFF if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            0xE000..=0xFFFF => self.irq_enabled = true,
+            // mirroring & PRG RAM protect are not implemented
+            _ => {}
+        }
+    }
+
+    fn cpu_read(&self, address: usize) -> Option<u8> {
+        if !(0x8000..=0xFFFF).contains(&address) {
+            return None;
+        }
+        let window = (address - 0x8000) / PRG_BANK_SIZE;
+        let offset = self.prg_offsets()[window];
+        self.prg
+            .get(offset + (address & (PRG_BANK_SIZE - 1)))
+            .copied()
+    }
+
+    fn on_ppu_address(&mut self, address: usize) {
+        if address & A12 == 0 {
+            self.a12_low_count = self.a12_low_count.saturating_add(1);
+            return;
+        }
+
+        if self.a12_low_count >= A12_LOW_FETCHES {
+            self.clock_scanline();
+        }
+        self.a12_low_count = 0;
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn current_banks(&self) -> BankLayout {
+        let windows = [0x8000, 0xA000, 0xC000, 0xE000];
+        BankLayout {
+            prg: windows.iter().copied().zip(self.prg_offsets()).collect(),
+            chr: Vec::new(),
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Pattern fetches of one rendered scanline with sprites at $1000
+    fn render_scanline(mapper: &mut Mmc3) {
+        for tile in 0..32 {
+            mapper.on_ppu_address(0x2000 + tile);
+            mapper.on_ppu_address(0x23C0);
+            mapper.on_ppu_address(tile * 16);
+            mapper.on_ppu_address(tile * 16 + 8);
+        }
+        for sprite in 0..8 {
+            mapper.on_ppu_address(0x2000);
+            mapper.on_ppu_address(0x2000);
+            mapper.on_ppu_address(0x1000 + sprite * 16);
+            mapper.on_ppu_address(0x1000 + sprite * 16 + 8);
+        }
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = Mmc3::new(&[]);
+        mapper.cpu_write(0xC000, 3); // latch
+        mapper.cpu_write(0xC001, 0); // reload
+        mapper.cpu_write(0xE001, 0); // enable
+
+        // reload to 3 and count down 2, 1, 0
+        for scanline in 0..3 {
+            render_scanline(&mut mapper);
+            assert!(!mapper.irq_pending(), "early IRQ on {}", scanline);
+        }
+        render_scanline(&mut mapper);
+        assert!(mapper.irq_pending(), "IRQ on the fourth scanline");
+
+        // acknowledge
+        mapper.cpu_write(0xE000, 0);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        // 64K, eight 8K banks each filled with its own number
+        let prg: Vec<u8> = (0..8u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        let mut mapper = Mmc3::new(&prg);
+        let banks = |mapper: &Mmc3| {
+            [0x8000, 0xA000, 0xC000, 0xFFFF]
+                .map(|a| mapper.cpu_read(a).unwrap())
+        };
+        assert_eq!(banks(&mapper), [0, 0, 6, 7]);
+
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        mapper.cpu_write(0x8000, 7);
+        mapper.cpu_write(0x8001, 4);
+        assert_eq!(banks(&mapper), [3, 4, 6, 7]);
+
+        // PRG mode 1 swaps $8000 and $C000
+        mapper.cpu_write(0x8000, PRG_MODE | 6);
+        assert_eq!(banks(&mapper), [6, 4, 3, 7]);
+        assert_eq!(
+            mapper.current_banks().prg,
+            vec![
+                (0x8000, 6 * PRG_BANK_SIZE),
+                (0xA000, 4 * PRG_BANK_SIZE),
+                (0xC000, 3 * PRG_BANK_SIZE),
+                (0xE000, 7 * PRG_BANK_SIZE),
+            ]
+        );
+
+        // Bank numbers past the end of the ROM wrap
+        mapper.cpu_write(0x8001, 11);
+        assert_eq!(mapper.cpu_read(0xC000), Some(3));
+        assert_eq!(mapper.cpu_read(0x6000), None);
+    }
+}
@@ -0,0 +1,59 @@
+pub mod mmc3;
+pub mod uxrom;
+
+use super::Cartridge;
+use mmc3::Mmc3;
+use std::fmt::Debug;
+use uxrom::Uxrom;
+
+/**
+ * Which part of the cartridge ROM each window is currently showing, as
+ * (bus address, offset into the ROM) pairs
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BankLayout {
+    pub prg: Vec<(usize, usize)>,
+    pub chr: Vec<(usize, usize)>,
+}
+
+/**
+ * Cartridge hardware sitting on the CPU & PPU buses. Writes to the ROM area
+ * are routed here so boards can implement their registers.
+ */
+pub trait Mapper: Debug {
+    fn cpu_write(&mut self, address: usize, value: u8);
+
+    // Reads from the ROM area, None leaves them to the flat memory
+    fn cpu_read(&self, _address: usize) -> Option<u8> {
+        None
+    }
+
+    // Called with every address rendering puts on the PPU bus, see
+    // Memory::step_ppu
+    fn on_ppu_address(&mut self, _address: usize) {}
+
+    // Level triggered, stays set until the game acknowledges it
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Debugging view of the bank registers
+    fn current_banks(&self) -> BankLayout {
+        BankLayout::default()
+    }
+
+    // Copy of the board state for rewind snapshots
+    fn box_clone(&self) -> Box<dyn Mapper>;
+}
+
+/**
+ * Mapper hardware for the board in the cartridge header, None for NROM and
+ * boards that aren't implemented
+ */
+pub fn for_cartridge(cartridge: &Cartridge) -> Option<Box<dyn Mapper>> {
+    match cartridge.info.mapper {
+        2 => Some(Box::new(Uxrom::new(&cartridge.rom))),
+        4 => Some(Box::new(Mmc3::new(&cartridge.rom))),
+        _ => None,
+    }
+}
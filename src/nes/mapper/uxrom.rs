@@ -0,0 +1,61 @@
+use super::{BankLayout, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/**
+ * UxROM (mapper 2), a switchable 16K bank at $8000 and the last bank fixed
+ * at $C000. CHR is 8K of RAM.
+ */
+#[derive(Clone, Debug)]
+pub struct Uxrom {
+    prg: Vec<u8>,
+    bank: usize,
+}
+
+impl Uxrom {
+    pub fn new(prg: &[u8]) -> Uxrom {
+        Uxrom {
+            prg: prg.to_vec(),
+            bank: 0,
+        }
+    }
+
+    fn banks(&self) -> usize {
+        (self.prg.len() / PRG_BANK_SIZE).max(1)
+    }
+
+    fn last_bank_offset(&self) -> usize {
+        (self.banks() - 1) * PRG_BANK_SIZE
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_write(&mut self, _address: usize, value: u8) {
+        self.bank = value as usize % self.banks();
+    }
+
+    fn cpu_read(&self, address: usize) -> Option<u8> {
+        let offset = match address {
+            0x8000..=0xBFFF => self.bank * PRG_BANK_SIZE,
+            0xC000..=0xFFFF => self.last_bank_offset(),
+            _ => return None,
+        };
+        self.prg
+            .get(offset + (address & (PRG_BANK_SIZE - 1)))
+            .copied()
+    }
+
+    fn current_banks(&self) -> BankLayout {
+        BankLayout {
+            prg: vec![
+                (0x8000, self.bank * PRG_BANK_SIZE),
+                (0xC000, self.last_bank_offset()),
+            ],
+            chr: vec![(0x0000, 0)],
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
@@ -1,96 +1,1588 @@
-use super::cpu::base::Processor;
-use super::cpu::memory::{RESET_VECTOR, ROM_START};
+pub mod apu;
+pub mod joypad;
+pub mod mapper;
+pub mod ppu;
+pub mod rewind;
+
+use super::cpu::assemble_with_entry;
+use super::cpu::base::{
+    Processor, C_FLAG, D_FLAG, I_FLAG, N_FLAG, RESET_SP, RESET_STATUS, V_FLAG,
+    Z_FLAG,
+};
+use super::cpu::memory::{
+    AccessProfile, IRQ_BRK_VECTOR, MEMORY_MAX, NMI_VECTOR, RESET_VECTOR,
+    ROM_START,
+};
+use crate::error::{AssembleError, CartridgeError, RustyNesError};
+use joypad::{ButtonState, Joypad};
+use mapper::BankLayout;
+use ppu::{Ppu, CHR_SIZE, FRAME_SCANLINES, PAL_FRAME_SCANLINES, SCANLINE_DOTS};
+use rewind::{Rewind, RewindConfig};
+use std::fmt;
+#[cfg(feature = "gzip")]
+use std::io::Read;
 use std::iter::FromIterator;
+use std::thread;
+use std::time::{Duration, Instant};
 
 const KILOBYTE_BYTE_SIZE: usize = 1024;
 const PRG_ROM_UNIT_SIZE: usize = KILOBYTE_BYTE_SIZE * 16;
 const HEADER_BYTE_SIZE: usize = 16;
 const TRAINER_BYTE_SIZE: usize = KILOBYTE_BYTE_SIZE / 2;
+const CHR_ROM_UNIT_SIZE: usize = KILOBYTE_BYTE_SIZE * 8;
+// NTSC runs at 60.0988 frames a second, PAL at 50.007
+const FRAME_PERIOD: Duration = Duration::from_nanos(16_639_267);
+const PAL_FRAME_PERIOD: Duration = Duration::from_nanos(19_997_200);
+// Frames to emulate without sleeping before giving up on catching up
+const MAX_FRAME_SKIP: usize = 4;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const INES_MAGIC: &[u8] = b"NES\x1a";
+const UNIF_MAGIC: &[u8] = b"UNIF";
+const FDS_MAGIC: &[u8] = b"FDS\x1a";
+const IPS_MAGIC: &[u8] = b"PATCH";
+// "EOF" read as a record offset ends the patch
+const IPS_EOF: usize = 0x454f46;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    // the cartridge carries the VRAM for all four nametables
+    FourScreen,
+}
+
+/**
+ * TV system the cartridge was made for. It sets the frame length and how
+ * fast the PPU runs against the CPU.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => FRAME_SCANLINES,
+            Region::Pal => PAL_FRAME_SCANLINES,
+        }
+    }
+
+    // PPU dots for every so many CPU cycles, 3 on NTSC and 3.2 on PAL
+    pub fn clock_ratio(self) -> (u32, u32) {
+        match self {
+            Region::Ntsc => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    // CPU cycles in a full length frame
+    pub fn frame_cycles(self) -> f64 {
+        let (dots, cycles) = self.clock_ratio();
+        let frame_dots = self.scanlines() as u32 * SCANLINE_DOTS as u32;
+        frame_dots as f64 * cycles as f64 / dots as f64
+    }
+
+    pub fn frame_period(self) -> Duration {
+        match self {
+            Region::Ntsc => FRAME_PERIOD,
+            Region::Pal => PAL_FRAME_PERIOD,
+        }
+    }
+}
+
+/**
+ * What the iNES header says about the board
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub nes2: bool,
+    pub mapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+}
+
+impl Default for RomInfo {
+    fn default() -> Self {
+        RomInfo {
+            nes2: false,
+            mapper: 0,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Region::Ntsc,
+        }
+    }
+}
+
+impl RomInfo {
+    pub fn from_header(header: &[u8]) -> RomInfo {
+        let control_byte_1 = header[6];
+        let control_byte_2 = header[7];
+        let mirroring = if control_byte_1 & 0b0000_1000 != 0 {
+            Mirroring::FourScreen
+        } else if control_byte_1 & 0b0000_0001 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let nes2 = control_byte_2 & 0b0000_1100 == 0b0000_1000;
+        // NES 2.0 has a timing byte, Dendy (3) is closest to PAL. iNES only
+        // has the rarely set TV system bit.
+        let pal = if nes2 {
+            matches!(header[12] & 0b11, 1 | 3)
+        } else {
+            header[9] & 1 != 0
+        };
+
+        RomInfo {
+            nes2,
+            mapper: (control_byte_2 & 0xF0) | (control_byte_1 >> 4),
+            prg_rom_size: header[4] as usize * PRG_ROM_UNIT_SIZE,
+            chr_rom_size: header[5] as usize * CHR_ROM_UNIT_SIZE,
+            mirroring,
+            battery: control_byte_1 & 0b0000_0010 != 0,
+            region: if pal { Region::Pal } else { Region::Ntsc },
+        }
+    }
+
+    pub fn mapper_name(&self) -> &'static str {
+        mapper_name(self.mapper)
+    }
+}
+
+fn mapper_name(mapper: u8) -> &'static str {
+    match mapper {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        7 => "AxROM",
+        _ => "unknown",
+    }
+}
+
+/**
+ * Header fields a caller insists on, None accepts anything
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ExpectedHeader {
+    pub mapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub prg_rom_size: Option<usize>,
+    pub chr_rom_size: Option<usize>,
+}
+
+impl ExpectedHeader {
+    /**
+     * First field of `info` that doesn't match
+     */
+    pub fn check(&self, info: &RomInfo) -> Result<(), CartridgeError> {
+        fn mismatch<T: PartialEq>(
+            field: &'static str,
+            expected: Option<T>,
+            actual: T,
+            describe: impl Fn(T) -> String,
+        ) -> Result<(), CartridgeError> {
+            match expected {
+                Some(expected) if expected != actual => {
+                    Err(CartridgeError::HeaderMismatch {
+                        field,
+                        expected: describe(expected),
+                        actual: describe(actual),
+                    })
+                }
+                _ => Ok(()),
+            }
+        }
+        let mapper = |m: u8| format!("{} ({})", m, mapper_name(m));
+        let size = |bytes: usize| format!("{}KB", bytes / KILOBYTE_BYTE_SIZE);
+
+        mismatch("mapper", self.mapper, info.mapper, mapper)?;
+        mismatch("mirroring", self.mirroring, info.mirroring, |m| {
+            format!("{:?}", m)
+        })?;
+        mismatch("PRG ROM size", self.prg_rom_size, info.prg_rom_size, size)?;
+        mismatch("CHR ROM size", self.chr_rom_size, info.chr_rom_size, size)
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mirroring = match self.mirroring {
+            Mirroring::Horizontal => "horizontal",
+            Mirroring::Vertical => "vertical",
+            Mirroring::FourScreen => "four-screen",
+        };
+        write!(
+            f,
+            "{}, mapper {} ({}), {}KB PRG, {}KB CHR, {} mirroring, {}",
+            if self.nes2 { "NES 2.0" } else { "iNES" },
+            self.mapper,
+            self.mapper_name(),
+            self.prg_rom_size / KILOBYTE_BYTE_SIZE,
+            self.chr_rom_size / KILOBYTE_BYTE_SIZE,
+            mirroring,
+            if self.battery {
+                "battery"
+            } else {
+                "no battery"
+            }
+        )
+    }
+}
+
+/**
+ * Image formats told apart by their magic, only iNES and NES 2.0 load
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CartridgeFormat {
+    INes,
+    Nes2,
+    Unif,
+    Fds,
+}
+
+impl CartridgeFormat {
+    pub fn detect(data: &[u8]) -> Result<CartridgeFormat, CartridgeError> {
+        if data.starts_with(GZIP_MAGIC) {
+            return Err(CartridgeError::Compressed);
+        }
+        if data.starts_with(UNIF_MAGIC) {
+            return Ok(CartridgeFormat::Unif);
+        }
+        if data.starts_with(FDS_MAGIC) {
+            return Ok(CartridgeFormat::Fds);
+        }
+        if data.len() < HEADER_BYTE_SIZE {
+            return Err(CartridgeError::TooShort(data.len()));
+        }
+        if !data.starts_with(INES_MAGIC) {
+            return Err(CartridgeError::InvalidMagic);
+        }
+        if RomInfo::from_header(&data[0..HEADER_BYTE_SIZE]).nes2 {
+            Ok(CartridgeFormat::Nes2)
+        } else {
+            Ok(CartridgeFormat::INes)
+        }
+    }
+}
 
 pub struct Cartridge {
     pub header: String,
+    pub info: RomInfo,
     pub rom: Vec<u8>,
+    // Empty when the board has CHR RAM
+    pub chr: Vec<u8>,
+}
+
+impl fmt::Display for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.info)
+    }
+}
+
+impl Default for Cartridge {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cartridge {
     pub fn new() -> Cartridge {
         Cartridge {
             header: String::from("empty"),
+            info: RomInfo::default(),
             rom: Vec::new(),
+            chr: Vec::new(),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let mut cartridge = Cartridge::new();
+        cartridge.load(data)?;
+        Ok(cartridge)
+    }
+
+    pub fn load(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        #[cfg(feature = "gzip")]
+        if data.starts_with(GZIP_MAGIC) {
+            let mut plain = Vec::new();
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut plain)
+                .map_err(CartridgeError::InvalidGzip)?;
+            return self.load(&plain);
+        }
+        match CartridgeFormat::detect(data)? {
+            CartridgeFormat::INes | CartridgeFormat::Nes2 => {
+                self.load_ines(data)
+            }
+            CartridgeFormat::Unif => {
+                Err(CartridgeError::UnsupportedFormat("UNIF"))
+            }
+            CartridgeFormat::Fds => {
+                Err(CartridgeError::UnsupportedFormat("FDS"))
+            }
         }
     }
 
-    pub fn load(&mut self, data: &Vec<u8>) {
+    // iNES and NES 2.0 share a header layout, RomInfo reads the differences
+    fn load_ines(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
         self.header = String::from_utf8_lossy(&data[0..3]).into_owned();
+        let info = RomInfo::from_header(&data[0..HEADER_BYTE_SIZE]);
         let control_byte_1 = data[6];
-        //  let vrom_size = data[5] as usize * KILOBYTE_BYTE_SIZE * 8;
         let rom_start = HEADER_BYTE_SIZE
             + ((control_byte_1 as usize & 0b0000_0100) / 0b0000_0100
                 * TRAINER_BYTE_SIZE);
-        let rom_size = data[4] as usize * PRG_ROM_UNIT_SIZE;
-        let rom_end = rom_start + rom_size;
+        let rom_end = rom_start + info.prg_rom_size;
+        let chr_end = rom_end + info.chr_rom_size;
+        if chr_end > data.len() {
+            return Err(CartridgeError::Truncated {
+                expected: chr_end,
+                actual: data.len(),
+            });
+        }
 
         self.rom = Vec::from_iter(data[rom_start..rom_end].iter().cloned());
+        self.chr = data[rom_end..chr_end].to_vec();
+        self.info = info;
+        Ok(())
+    }
+
+    /**
+     * Load `data` and check its header against what the caller expects
+     */
+    pub fn load_expecting(
+        data: &[u8],
+        expected: ExpectedHeader,
+    ) -> Result<Cartridge, CartridgeError> {
+        let cartridge = Cartridge::from_bytes(data)?;
+        expected.check(&cartridge.info)?;
+        Ok(cartridge)
+    }
+}
+
+/**
+ * Assemble `source` into a loadable iNES image for `mapper`, without CHR.
+ * The PRG is one 16K bank, mirrored to $C000, unless the program needs two.
+ * Without a `.resetvector` the reset vector points at the entry point.
+ */
+pub fn assemble_to_ines(
+    source: &str,
+    mapper: u8,
+) -> Result<Vec<u8>, AssembleError> {
+    let (mut prg, entry) = assemble_with_entry(source)?;
+    let banks = if prg.len() <= PRG_ROM_UNIT_SIZE { 1 } else { 2 };
+    prg.resize(banks * PRG_ROM_UNIT_SIZE, 0);
+
+    // the vectors sit at the end of the last bank either way
+    let reset = prg.len() - (MEMORY_MAX - RESET_VECTOR);
+    if prg[reset..reset + 2] == [0, 0] {
+        prg[reset] = (entry & 0xFF) as u8;
+        prg[reset + 1] = (entry >> 8) as u8;
+    }
+
+    let mut data = INES_MAGIC.to_vec();
+    data.extend_from_slice(&[banks as u8, 0, mapper << 4, mapper & 0xf0]);
+    data.resize(HEADER_BYTE_SIZE, 0);
+    data.append(&mut prg);
+    Ok(data)
+}
+
+/**
+ * Apply an IPS patch to a ROM image, header included, before it's loaded.
+ *
+ * Records are a 3 byte offset and a 2 byte length followed by that many
+ * bytes, a zero length marks an RLE record of a 2 byte count and the byte to
+ * repeat. Records past the end grow the image, and the optional 3 byte length
+ * after EOF truncates it. A patch that fails leaves the image untouched.
+ */
+pub fn apply_ips(
+    data: &mut Vec<u8>,
+    patch: &[u8],
+) -> Result<(), CartridgeError> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(CartridgeError::InvalidPatch("missing the PATCH magic"));
+    }
+    let field = |at: usize, len: usize| -> Result<usize, CartridgeError> {
+        let bytes = patch
+            .get(at..at + len)
+            .ok_or(CartridgeError::InvalidPatch("record runs past the end"))?;
+        Ok(bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    };
+
+    // Records apply to a copy so a bad one doesn't leave the image half done
+    let mut patched = data.clone();
+    let mut at = IPS_MAGIC.len();
+    loop {
+        let offset = field(at, 3)?;
+        at += 3;
+        if offset == IPS_EOF {
+            break;
+        }
+        let len = field(at, 2)?;
+        at += 2;
+        let (len, value) = if len == 0 {
+            let count = field(at, 2)?;
+            let value = field(at + 2, 1)? as u8;
+            at += 3;
+            (count, Some(value))
+        } else {
+            (len, None)
+        };
+        // The whole record has to be there before the image grows
+        if value.is_none() && patch.get(at..at + len).is_none() {
+            return Err(CartridgeError::InvalidPatch(
+                "record runs past the end",
+            ));
+        }
+
+        if patched.len() < offset + len {
+            patched.resize(offset + len, 0);
+        }
+        let target = &mut patched[offset..offset + len];
+        match value {
+            Some(value) => target.iter_mut().for_each(|b| *b = value),
+            None => {
+                target.copy_from_slice(&patch[at..at + len]);
+                at += len;
+            }
+        }
+    }
+
+    if patch.len() >= at + 3 {
+        patched.truncate(field(at, 3)?);
     }
+    *data = patched;
+    Ok(())
 }
+// Called with the frame count every time the PPU finishes a frame
+pub type FrameObserver = Box<dyn FnMut(u64)>;
+// Checked after every instruction, run and run_frame stop when one is true
+pub type Breakpoint = Box<dyn Fn(&Processor) -> bool>;
+
 pub struct Nes {
     pub cartridge: Cartridge,
     pub cpu: Processor,
+    // master clock ticks, one per PPU dot
+    pub clock: u64,
+    // when the next paced frame is due
+    pub frame_deadline: Option<Instant>,
+    // frames finished since the Nes was made, power_on doesn't reset it
+    frame_count: u64,
+    on_frame: Option<FrameObserver>,
+    breakpoints: Vec<Breakpoint>,
+    // snapshots for rewind, None until enable_rewind
+    rewind: Option<Rewind>,
 }
 
 impl Nes {
     pub fn new(cpu: Processor) -> Nes {
         Nes {
-            cpu: cpu,
+            cpu,
             cartridge: Cartridge::new(),
+            clock: 0,
+            frame_deadline: None,
+            frame_count: 0,
+            on_frame: None,
+            breakpoints: Vec::new(),
+            rewind: None,
         }
     }
-    pub fn load_cartridge(&mut self, filename: &String) {
-        let data = match std::fs::read(filename) {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::PermissionDenied {
-                    eprintln!(
-                        "Permission denied while attmepting to read .nes file."
-                    );
-                    return;
-                }
 
-                panic!("{}", e);
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /**
+     * Install a callback that runs at the end of every frame
+     */
+    pub fn set_frame_observer(&mut self, observer: Option<FrameObserver>) {
+        self.on_frame = observer;
+    }
+
+    /**
+     * Stop running when `condition` holds after an instruction, for example
+     * `cpu.state.pc == 0xC100 && cpu.state.a == 0x42`
+     */
+    pub fn add_conditional_breakpoint(&mut self, condition: Breakpoint) {
+        self.breakpoints.push(condition);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn at_breakpoint(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|condition| condition(&self.cpu))
+    }
+
+    // Count the frame and tell the observer if the PPU moved past `frame`
+    fn end_frame(&mut self, frame: u64) {
+        if self.cpu.mem.ppu.frame == frame {
+            return;
+        }
+        self.frame_count += 1;
+        if let Some(observer) = self.on_frame.as_mut() {
+            observer(self.frame_count);
+        }
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.end_frame(self);
+            self.rewind = Some(rewind);
+        }
+    }
+
+    /**
+     * Start keeping snapshots for rewind, None stops and drops them
+     */
+    pub fn enable_rewind(&mut self, config: Option<RewindConfig>) {
+        self.rewind = config.map(Rewind::new);
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /**
+     * Go back to the newest snapshot at least `frames` frames old. Returns
+     * false, leaving the machine alone, when there is none that old.
+     */
+    pub fn rewind(&mut self, frames: u64) -> bool {
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return false,
+        };
+        let frame = self.frame_count.checked_sub(frames);
+        let restored = match frame.and_then(|frame| rewind.rewind_to(frame)) {
+            Some(snapshot) => {
+                snapshot.restore(self);
+                true
             }
+            None => false,
+        };
+        self.rewind = Some(rewind);
+        restored
+    }
+
+    /**
+     * Advance the system by one tick of the master clock, the PPU steps
+     * every tick and the CPU every third, or 3.2 on PAL
+     */
+    pub fn tick(&mut self) {
+        let frame = self.cpu.mem.ppu.frame;
+        self.cpu.mem.step_ppu_dots(1);
+        self.end_frame(frame);
+        // Taken once the instruction in flight finishes
+        if self.cpu.mem.ppu.take_nmi() {
+            let now = self.cpu.cycles as u64;
+            self.cpu.schedule_nmi_at(now);
+        }
+        let (dots, cycles) = self.cpu.mem.ppu.region.clock_ratio();
+        let before = self.clock * cycles as u64 / dots as u64;
+        self.clock += 1;
+        if self.clock * cycles as u64 / dots as u64 > before {
+            self.cpu.tick();
+        }
+    }
+    pub fn joypad(&mut self, port: usize) -> &mut Joypad {
+        &mut self.cpu.mem.joypads[port]
+    }
+
+    pub fn set_controller(&mut self, port: u8, state: &ButtonState) {
+        self.joypad(port as usize).set_state(state);
+    }
+
+    /**
+     * Disassembly around the CPU's PC for a debugger view, see
+     * `Processor::disassembly_window`
+     */
+    pub fn disassembly_window(
+        &self,
+        radius: usize,
+    ) -> Vec<(usize, String, bool)> {
+        self.cpu.disassembly_window(radius)
+    }
+
+    /**
+     * Point the reset, NMI and IRQ/BRK vectors at their handlers
+     */
+    pub fn set_vectors(&mut self, reset: usize, nmi: usize, irq: usize) {
+        self.cpu.mem.set_vector(RESET_VECTOR, reset);
+        self.cpu.mem.set_vector(NMI_VECTOR, nmi);
+        self.cpu.mem.set_vector(IRQ_BRK_VECTOR, irq);
+    }
+
+    /**
+     * CPU registers and flags, the cycle count, PPU position and the next
+     * instruction as a JSON object for external debuggers
+     */
+    pub fn debug_json(&self) -> String {
+        let state = &self.cpu.state;
+        let flag = |mask: u8| state.status & mask != 0;
+        let ppu = &self.cpu.mem.ppu;
+        let instruction = self
+            .cpu
+            .trace_line()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        format!(
+            concat!(
+                "{{\"pc\":{},\"a\":{},\"x\":{},\"y\":{},\"sp\":{},",
+                "\"status\":{},\"flags\":{{\"negative\":{},\"overflow\":{},",
+                "\"decimal\":{},\"interrupt\":{},\"zero\":{},\"carry\":{}}},",
+                "\"cycles\":{},\"scanline\":{},\"dot\":{},",
+                "\"instruction\":\"{}\"}}"
+            ),
+            state.pc,
+            state.a,
+            state.x,
+            state.y,
+            state.sp,
+            self.cpu.get_status(),
+            flag(N_FLAG),
+            flag(V_FLAG),
+            flag(D_FLAG),
+            flag(I_FLAG),
+            flag(Z_FLAG),
+            flag(C_FLAG),
+            self.cpu.cycles,
+            ppu.scanline,
+            ppu.dot,
+            instruction
+        )
+    }
+
+    /**
+     * Fast-forward switch, with rendering off the PPU keeps its counters,
+     * flags and NMIs going but leaves the frame buffer alone
+     */
+    pub fn set_render_enabled(&mut self, enabled: bool) {
+        self.cpu.mem.ppu.render_enabled = enabled;
+    }
+
+    /**
+     * Start counting reads and writes per address, or stop and drop the
+     * counts
+     */
+    pub fn enable_access_profile(&mut self, enable: bool) {
+        self.cpu.mem.profile = if enable {
+            Some(AccessProfile::new())
+        } else {
+            None
         };
+    }
 
-        self.cartridge.load(&data);
+    /**
+     * `(address, reads, writes)` hottest first, empty unless profiling
+     */
+    pub fn access_profile(&self) -> Vec<(usize, u64, u64)> {
+        match self.cpu.mem.profile.as_ref() {
+            Some(profile) => profile.hottest(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn bank_layout(&self) -> BankLayout {
+        match self.cpu.mem.mapper.as_ref() {
+            Some(mapper) => mapper.current_banks(),
+            None => BankLayout::default(),
+        }
     }
 
-    pub fn run(&mut self, reset_pc: Option<usize>) {
+    pub fn load_cartridge(
+        &mut self,
+        filename: &str,
+    ) -> Result<(), RustyNesError> {
+        let data = std::fs::read(filename).map_err(CartridgeError::Io)?;
+        self.load_cartridge_data(&data)
+    }
+
+    /**
+     * Load a cartridge with an IPS patch applied over it
+     */
+    pub fn load_cartridge_with_patch(
+        &mut self,
+        filename: &str,
+        patch_filename: &str,
+    ) -> Result<(), RustyNesError> {
+        let mut data = std::fs::read(filename).map_err(CartridgeError::Io)?;
+        let patch =
+            std::fs::read(patch_filename).map_err(CartridgeError::Io)?;
+        apply_ips(&mut data, &patch)?;
+        self.load_cartridge_data(&data)
+    }
+
+    pub fn load_cartridge_data(
+        &mut self,
+        data: &[u8],
+    ) -> Result<(), RustyNesError> {
+        self.insert_cartridge(Cartridge::from_bytes(data)?)
+    }
+
+    /**
+     * Swap in a parsed cartridge and map it
+     */
+    pub fn insert_cartridge(
+        &mut self,
+        cartridge: Cartridge,
+    ) -> Result<(), RustyNesError> {
+        self.cartridge = cartridge;
+        self.map_cartridge()
+    }
+
+    /**
+     * Map the cartridge into memory, once per cartridge. Resets leave the
+     * mapping alone.
+     */
+    pub fn map_cartridge(&mut self) -> Result<(), RustyNesError> {
         let rom = &self.cartridge.rom;
-        // User interaction here :)
-        println!("START NES");
-        let reset_vector = [
-            (reset_pc.unwrap_or(ROM_START) & 0xFF) as u8,
-            ((reset_pc.unwrap_or(ROM_START) & 0xFF00) >> 8) as u8,
-        ];
+        self.cpu.mem.mapper = mapper::for_cartridge(&self.cartridge);
+        self.cpu.mem.ppu.region = self.cartridge.info.region;
+        self.cpu.mem.ppu.mirroring = self.cartridge.info.mirroring;
+        let chr = &self.cartridge.chr;
+        self.cpu.mem.ppu.chr_ram = chr.is_empty();
+        self.cpu.mem.ppu.chr = if chr.is_empty() {
+            vec![0; CHR_SIZE]
+        } else {
+            chr.clone()
+        };
+
+        // A single 16K bank shows up at both 0x8000 and 0xC000, boards
+        // without a mapper have no other way to fill the 32K window
+        let mirror = match rom.len() {
+            PRG_ROM_UNIT_SIZE => true,
+            len if len == PRG_ROM_UNIT_SIZE * 2 => false,
+            len if self.cpu.mem.mapper.is_none() => {
+                return Err(CartridgeError::PrgRomSize(len).into());
+            }
+            _ => false,
+        };
 
-        // Load the program into memory
-        self.cpu.mem.load(ROM_START, &rom);
-        if rom.len() <= PRG_ROM_UNIT_SIZE {
-            // Any cartridge with under 16K ROM should load both into 0x8000 and 0xC000
-            self.cpu.mem.load(ROM_START + PRG_ROM_UNIT_SIZE, &rom);
+        // Load the program into memory, boards with more than 32K bank it
+        // in through the mapper instead
+        if rom.len() <= PRG_ROM_UNIT_SIZE * 2 {
+            self.cpu.mem.load(ROM_START, rom)?;
+        }
+        if mirror {
+            self.cpu.mem.load(ROM_START + PRG_ROM_UNIT_SIZE, rom)?;
         }
-        // Setup reset vector to start PC at ROM_START
-        self.cpu.mem.load(RESET_VECTOR, &reset_vector);
+        Ok(())
+    }
 
+    /**
+     * Bring the CPU up at `reset_pc`, or the reset vector, with the register
+     * state nestest.log expects. The cartridge has to be mapped already.
+     */
+    pub fn reset(&mut self, reset_pc: Option<usize>) {
+        // Start at the cartridge's reset vector unless told otherwise
         self.cpu.reset();
+        if let Some(pc) = reset_pc {
+            self.cpu.state.pc = pc;
+        }
+        self.cpu.state.a = 0;
+        self.cpu.state.x = 0;
+        self.cpu.state.y = 0;
+        self.cpu.state.sp = RESET_SP;
+        self.cpu.state.status = RESET_STATUS;
+    }
+
+    /**
+     * Cold boot, internal RAM is cleared and the PPU starts over before the
+     * full reset
+     */
+    pub fn power_on(&mut self) {
+        let ram_size = self.cpu.mem.config.ram_size;
+        for byte in self.cpu.mem.ram[..ram_size].iter_mut() {
+            *byte = 0;
+        }
+        self.cpu.mem.ppu = Ppu::new();
+        self.cpu.cycles = 0;
+        self.cpu.busy = 0;
+        self.cpu.micro_ops.clear();
+        self.cpu.fault = None;
+        self.reset(None);
+    }
+
+    /**
+     * Warm reset, as from the console's reset button. RAM and the ROM stay as
+     * they are, the CPU runs its reset sequence: PC from the reset vector, I
+     * set and SP moved down three by the suppressed pushes. The PPU drops
+     * PPUCTRL & PPUMASK.
+     */
+    pub fn soft_reset(&mut self) {
+        self.cpu.fault = None;
+        self.cpu.busy = 0;
+        self.cpu.micro_ops.clear();
+        self.cpu.state.pc = self.cpu.mem.read_u16(RESET_VECTOR);
+        self.cpu.state.sp = self.cpu.state.sp.wrapping_sub(3);
+        self.cpu.state.status |= I_FLAG;
+        self.cpu.mem.ppu.ctrl = 0;
+        self.cpu.mem.ppu.mask = 0;
+    }
+
+    /**
+     * Run the CPU until the PPU finishes the current frame, the CPU faults or
+     * a breakpoint is hit
+     */
+    pub fn run_frame(&mut self) {
+        let frame = self.cpu.mem.ppu.frame;
+        while self.cpu.mem.ppu.frame == frame && self.cpu.fault.is_none() {
+            self.cpu.exec();
+            if self.at_breakpoint() {
+                break;
+            }
+        }
+        self.end_frame(frame);
+    }
 
-        let mut limit = 10000;
+    /**
+     * run_frame at the NTSC frame rate, sleeping off whatever is left of the
+     * frame. When running behind frames are emulated back to back, up to
+     * MAX_FRAME_SKIP, then the schedule restarts from now. Returns the number
+     * of frames emulated.
+     */
+    pub fn run_frame_paced(&mut self) -> usize {
+        let deadline = self.frame_deadline.unwrap_or_else(Instant::now);
+        let mut frames = 0;
+        let mut next = deadline;
         loop {
+            self.run_frame();
+            frames += 1;
+            next += self.cpu.mem.ppu.region.frame_period();
+            if Instant::now() < next || frames > MAX_FRAME_SKIP {
+                break;
+            }
+        }
+
+        let now = Instant::now();
+        if now < next {
+            thread::sleep(next - now);
+        } else {
+            next = now;
+        }
+        self.frame_deadline = Some(next);
+        frames
+    }
+
+    pub fn run(
+        &mut self,
+        reset_pc: Option<usize>,
+    ) -> Result<(), RustyNesError> {
+        // User interaction here :)
+        println!("START NES");
+
+        self.reset(reset_pc);
+
+        // Run until the CPU halts or spins on a single instruction, which
+        // is how test ROMs signal that they're done, or a breakpoint is hit
+        while self.cpu.fault.is_none() {
+            let old_pc = self.cpu.state.pc;
             self.cpu.exec();
-            limit -= 1;
-            if limit < 0 {
+            if self.cpu.state.pc == old_pc || self.at_breakpoint() {
                 break;
             }
         }
         println!("STOP NES");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::base::Fault;
+    use crate::cpu::harte::{parse, Json};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // iNES image with enough 16K PRG banks to hold `prg` and no CHR
+    fn nrom_image(prg: &[u8]) -> Vec<u8> {
+        let banks = prg.len().div_ceil(PRG_ROM_UNIT_SIZE);
+        let mut data = vec![0x4e, 0x45, 0x53, 0x1a, banks as u8, 0, 0, 0];
+        data.resize(HEADER_BYTE_SIZE, 0);
+        let mut rom = prg.to_vec();
+        rom.resize(banks * PRG_ROM_UNIT_SIZE, 0);
+        data.append(&mut rom);
+        data
+    }
+
+    #[test]
+    fn test_nestest_reset_state() {
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&nrom_image(&[0x4c, 0xf5, 0xc5]))
+            .unwrap();
+        nes.reset(Some(0xC000));
+
+        let state = nes.cpu.state;
+        assert_eq!(state.pc, 0xC000);
+        assert_eq!(state.status, 0x24);
+        assert_eq!(state.sp, 0xFD);
+        assert_eq!((state.a, state.x, state.y), (0, 0, 0));
+        assert_eq!(nes.cpu.trace_line(), "C000: 4C F5 C5 JMP $C5F5");
+    }
+
+    #[test]
+    fn test_run_from_reset_pc() {
+        // JAM at 0x8000, LDA #$42 then JAM at 0xC000
+        let mut prg = vec![0x02];
+        prg.resize(PRG_ROM_UNIT_SIZE, 0);
+        prg.extend_from_slice(&[0xa9, 0x42, 0x02]);
+
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&nrom_image(&prg)).unwrap();
+        nes.run(Some(0xC000)).unwrap();
+
+        assert_eq!(nes.cpu.state.a, 0x42, "execution should start at 0xC000");
+        assert_eq!(nes.cpu.state.pc, 0xC002);
+        assert_eq!(nes.cpu.fault, Some(Fault::Jam));
+    }
+
+    #[test]
+    fn test_prg_rom_sizes() {
+        let mut nes = Nes::new(Processor::new(None));
+        let mut prg = vec![0x11; PRG_ROM_UNIT_SIZE];
+        nes.load_cartridge_data(&nrom_image(&prg)).unwrap();
+        assert_eq!(nes.cpu.mem.read(0x8000), 0x11);
+        assert_eq!(nes.cpu.mem.read(0xC000), 0x11, "16K is mirrored");
+
+        prg.extend(vec![0x22; PRG_ROM_UNIT_SIZE]);
+        nes.load_cartridge_data(&nrom_image(&prg)).unwrap();
+        assert_eq!(nes.cpu.mem.read(0x8000), 0x11);
+        assert_eq!(nes.cpu.mem.read(0xC000), 0x22, "second bank, no mirror");
+
+        nes.cartridge.rom = vec![0; PRG_ROM_UNIT_SIZE + 0x2000];
+        assert!(matches!(
+            nes.map_cartridge(),
+            Err(RustyNesError::Cartridge(CartridgeError::PrgRomSize(0x6000)))
+        ));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_cartridge() {
+        // gzip -9 of nrom_image with LDA #$42, JAM and the reset vector $8000
+        let gzip = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xed,
+            0xc1, 0xb1, 0x11, 0x00, 0x10, 0x00, 0x04, 0xb0, 0x67, 0x0c, 0xe3,
+            0xb8, 0xd3, 0x6a, 0x2c, 0x64, 0x1d, 0x5b, 0x2a, 0x8d, 0xa0, 0x49,
+            0x32, 0xc7, 0x6a, 0x25, 0xcf, 0xe9, 0x35, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xc0, 0x47, 0x3b, 0xb9, 0x2f, 0x89, 0xbf, 0xde, 0x10, 0x40, 0x00,
+            0x00,
+        ];
+        let mut prg = vec![0xa9, 0x42, 0x02];
+        prg.resize(PRG_ROM_UNIT_SIZE, 0);
+        prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+        let plain = Cartridge::from_bytes(&nrom_image(&prg)).unwrap();
+
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&gzip).unwrap();
+        assert_eq!(nes.cartridge.rom.len(), plain.rom.len());
+        assert_eq!(nes.cartridge.rom, plain.rom);
+        nes.run(None).unwrap();
+        assert_eq!(nes.cpu.state.a, 0x42);
+    }
+
+    #[test]
+    fn test_mmc3_cartridge() {
+        // 128K of 8K banks, each filled with its own number
+        let prg: Vec<u8> =
+            (0..16u8).flat_map(|bank| vec![bank; 0x2000]).collect();
+        let mut data = nrom_image(&prg);
+        data[6] = 0x40;
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&data).unwrap();
+        assert_eq!(nes.cpu.mem.read(0xC000), 14, "second-last bank fixed");
+        assert_eq!(nes.cpu.mem.read(0xE000), 15, "last bank fixed");
+
+        nes.cpu.mem.write(0x8000, 6);
+        nes.cpu.mem.write(0x8001, 9);
+        nes.cpu.mem.write(0x8000, 7);
+        nes.cpu.mem.write(0x8001, 12);
+        assert_eq!(nes.cpu.mem.read(0x8000), 9);
+        assert_eq!(nes.cpu.mem.read(0xA000), 12);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x08,             // LDA #$08, sprites from $1000
+            0x8d, 0x00, 0x20,       // STA $2000
+            0xa9, 0x00,             // LDA #$00, rendering on or off
+            0x8d, 0x01, 0x20,       // STA $2001
+            0xa9, 0x04,             // LDA #$04
+            0x8d, 0x00, 0xc0,       // STA $C000, IRQ latch
+            0x8d, 0x01, 0xc0,       // STA $C001, reload
+            0x8d, 0x01, 0xe0,       // STA $E001, enable
+            0x58,                   // CLI
+            0x4c, 0x16, 0xe0,       // JMP $E016
+            // IRQ handler
+            0xe6, 0x10,             // INC $10
+            0x8d, 0x00, 0xe0,       // STA $E000, acknowledge and disable
+            0x40,                   // RTI
+        ];
+        for &(mask, irqs) in [(0x18, 1), (0x00, 0)].iter() {
+            // 128K, the code in the last bank fixed at $E000
+            let mut prg = vec![0; 0x20000];
+            prg[0x1e000..0x1e000 + program.len()].copy_from_slice(&program);
+            prg[0x1e006] = mask;
+            prg[0x1fffc..].copy_from_slice(&[0x00, 0xe0, 0x19, 0xe0]);
+            let mut data = nrom_image(&prg);
+            data[6] = 0x40;
+            let mut nes = Nes::new(Processor::new(None));
+            nes.load_cartridge_data(&data).unwrap();
+            nes.reset(None);
+
+            nes.run_frame();
+            nes.run_frame();
+            assert_eq!(nes.cpu.mem.read(0x10), irqs, "PPUMASK {:#04x}", mask);
+            let mapper = nes.cpu.mem.mapper.as_ref().unwrap();
+            assert!(!mapper.irq_pending(), "the handler acknowledged it");
+        }
+    }
+
+    #[test]
+    fn test_debug_json() {
+        let mut nes = Nes::new(Processor::new(None));
+        // SEC, LDA #$42, JMP $0205
+        nes.cpu
+            .mem
+            .load(0x0200, &[0x38, 0xa9, 0x42, 0x4c, 0x05, 0x02])
+            .unwrap();
+        nes.cpu.state.pc = 0x0200;
+        nes.cpu.exec();
+        nes.cpu.exec();
+
+        let json = parse(nes.debug_json().as_bytes());
+        assert_eq!(json.get("pc").number(), 0x0203);
+        assert_eq!(json.get("a").number(), 0x42);
+        assert!(json.get("flags").get("carry").boolean());
+        assert!(!json.get("flags").get("zero").boolean());
+        assert_eq!(json.get("cycles").number(), nes.cpu.cycles as u64);
+        match json.get("instruction") {
+            Json::Text(text) => assert_eq!(text, "0203: 4C 05 02 JMP $0205"),
+            other => panic!("instruction is {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_set_vectors() {
+        let mut nes = Nes::new(Processor::new(None));
+        nes.set_vectors(0x8000, 0x9abc, 0xc0de);
+        assert_eq!(nes.cpu.mem.read_u16(RESET_VECTOR), 0x8000);
+        assert_eq!(nes.cpu.mem.read_u16(NMI_VECTOR), 0x9abc);
+        assert_eq!(nes.cpu.mem.read_u16(IRQ_BRK_VECTOR), 0xc0de);
+        // little endian, NMI at $FFFA comes first
+        assert_eq!(
+            nes.cpu.mem.slice(NMI_VECTOR, 6),
+            &[0xbc, 0x9a, 0x00, 0x80, 0xde, 0xc0]
+        );
+    }
+
+    #[test]
+    fn test_insert_cartridge() {
+        // LDA #$42, JAM
+        let data = nrom_image(&[0xa9, 0x42, 0x02]);
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+
+        let mut nes = Nes::new(Processor::new(None));
+        nes.insert_cartridge(cartridge).unwrap();
+        // run resets to the given PC before it starts
+        nes.run(Some(0x8000)).unwrap();
+        assert_eq!(nes.cpu.state.a, 0x42);
+        assert_eq!(nes.cpu.fault, Some(Fault::Jam));
+    }
+
+    #[test]
+    fn test_reset_keeps_mapping() {
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&nrom_image(&[0xea])).unwrap();
+        nes.reset(None);
+        // a sentinel over the ROM survives only if it isn't copied again
+        nes.cpu.mem.ram[ROM_START] = 0x5a;
+        nes.reset(None);
+        assert_eq!(nes.cpu.mem.read(ROM_START), 0x5a);
+        assert_eq!(nes.cpu.state.sp, RESET_SP);
+
+        nes.map_cartridge().unwrap();
+        assert_eq!(nes.cpu.mem.read(ROM_START), 0xea);
+    }
+
+    #[test]
+    fn test_power_on_and_soft_reset() {
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&nrom_image(&[0xea])).unwrap();
+        nes.power_on();
+        assert_eq!(nes.cpu.state.sp, RESET_SP);
+
+        nes.cpu.mem.write(0x0300, 0x5a);
+        nes.cpu.state.status = 0;
+        nes.cpu.state.pc = 0x1234;
+        nes.soft_reset();
+        assert_eq!(nes.cpu.mem.read(0x0300), 0x5a, "warm reset keeps RAM");
+        assert_eq!(nes.cpu.state.pc, nes.cpu.mem.read_u16(RESET_VECTOR));
+        assert_eq!(nes.cpu.state.sp, RESET_SP - 3);
+        assert_eq!(nes.cpu.state.status & I_FLAG, I_FLAG);
+
+        nes.power_on();
+        assert_eq!(nes.cpu.mem.read(0x0300), 0x00, "cold boot clears RAM");
+        assert_eq!(nes.cpu.state.sp, RESET_SP);
+        assert_eq!(nes.cpu.mem.read(0x8000), 0xea, "ROM is still mapped");
+    }
+
+    #[test]
+    fn test_four_screen_cartridge() {
+        // flag 6 bit 3 wins over the vertical bit
+        let mut data = nrom_image(&[0xea]);
+        data[6] = 0b0000_1001;
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&data).unwrap();
+        assert_eq!(nes.cartridge.info.mirroring, Mirroring::FourScreen);
+        assert_eq!(nes.cpu.mem.ppu.mirroring, Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_region() {
+        // cycles the CPU spends on the second frame, JMP $8000 loops
+        fn frame_cycles(data: &[u8]) -> (Region, f64) {
+            let mut nes = Nes::new(Processor::new(None));
+            nes.load_cartridge_data(data).unwrap();
+            nes.reset(Some(0x8000));
+            nes.run_frame();
+            let start = nes.cpu.cycles;
+            nes.run_frame();
+            (nes.cartridge.info.region, (nes.cpu.cycles - start) as f64)
+        }
+
+        let data = nrom_image(&[0x4c, 0x00, 0x80]);
+        let (region, cycles) = frame_cycles(&data);
+        assert_eq!(region, Region::Ntsc);
+        assert!((cycles - Region::Ntsc.frame_cycles()).abs() <= 3.0);
+
+        // NES 2.0 with PAL timing
+        let mut pal = data.clone();
+        pal[7] = 0x08;
+        pal[12] = 1;
+        let (region, cycles) = frame_cycles(&pal);
+        assert_eq!(region, Region::Pal);
+        assert!((cycles - Region::Pal.frame_cycles()).abs() <= 3.0);
+        assert!(
+            Region::Pal.frame_cycles() - Region::Ntsc.frame_cycles() > 3000.0
+        );
+    }
+
+    #[test]
+    fn test_cartridge_display() {
+        let mut data = nrom_image(&[0xde, 0xad, 0xbe, 0xef]);
+        // MMC1, one CHR bank, vertical mirroring & battery backed RAM
+        data[5] = 1;
+        data[6] = 0x13;
+        data.resize(data.len() + CHR_ROM_UNIT_SIZE, 0);
+
+        let mut cartridge = Cartridge::new();
+        cartridge.load(&data).unwrap();
+        let summary = cartridge.to_string();
+        assert_eq!(
+            summary,
+            "iNES, mapper 1 (MMC1), 16KB PRG, 8KB CHR, vertical mirroring, battery"
+        );
+        assert_eq!(cartridge.info.mapper, 1);
+        assert_eq!(cartridge.info.prg_rom_size, PRG_ROM_UNIT_SIZE);
+        assert_eq!(cartridge.info.chr_rom_size, CHR_ROM_UNIT_SIZE);
+        assert_eq!(cartridge.info.mirroring, Mirroring::Vertical);
+        assert!(cartridge.info.battery);
+        assert_eq!(&cartridge.rom[..4], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(cartridge.chr.len(), CHR_ROM_UNIT_SIZE);
+    }
+
+    #[test]
+    fn test_load_expecting() {
+        let data = nrom_image(&[0xea]);
+        let nrom = ExpectedHeader {
+            mapper: Some(0),
+            prg_rom_size: Some(PRG_ROM_UNIT_SIZE),
+            ..ExpectedHeader::default()
+        };
+        let cartridge = Cartridge::load_expecting(&data, nrom).unwrap();
+        assert_eq!(cartridge.rom[0], 0xea);
+
+        let mmc1 = ExpectedHeader {
+            mapper: Some(1),
+            ..ExpectedHeader::default()
+        };
+        let error = Cartridge::load_expecting(&data, mmc1).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "cartridge mapper is 0 (NROM), expected 1 (MMC1)"
+        );
+    }
+
+    #[test]
+    fn test_bank_layout() {
+        // four UxROM banks, each filled with its own number
+        let mut prg = Vec::new();
+        for bank in 0..4 {
+            prg.extend(vec![bank as u8; PRG_ROM_UNIT_SIZE]);
+        }
+        let mut data = nrom_image(&prg);
+        data[6] = 0x20;
+
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&data).unwrap();
+        assert_eq!(
+            nes.bank_layout().prg,
+            vec![(0x8000, 0), (0xC000, 3 * PRG_ROM_UNIT_SIZE)]
+        );
+
+        nes.cpu.mem.write(0x8000, 2);
+        assert_eq!(
+            nes.bank_layout().prg,
+            vec![
+                (0x8000, 2 * PRG_ROM_UNIT_SIZE),
+                (0xC000, 3 * PRG_ROM_UNIT_SIZE)
+            ]
+        );
+        assert_eq!(nes.cpu.mem.read(0x8000), 2);
+        assert_eq!(nes.cpu.mem.read(0xC000), 3);
+    }
+
+    #[test]
+    fn test_run_frame_paced() {
+        let mut nes = Nes::new(Processor::new(None));
+        // JMP $0200
+        nes.cpu.mem.load(0x0200, &[0x4c, 0x00, 0x02]).unwrap();
+        nes.cpu.state.pc = 0x0200;
+
+        nes.run_frame();
+        nes.run_frame();
+        assert_eq!(nes.cpu.mem.ppu.frame, 2);
+        assert_eq!(nes.frame_deadline, None, "run_frame doesn't pace");
+
+        let start = Instant::now();
+        let first = nes.run_frame_paced();
+        let deadline = nes.frame_deadline.unwrap();
+        assert!(deadline >= start + FRAME_PERIOD);
+        let second = nes.run_frame_paced();
+        assert!(start.elapsed() >= FRAME_PERIOD);
+        // Frames skipped to catch up still count as emulated
+        assert_eq!(nes.cpu.mem.ppu.frame as usize, 2 + first + second);
+        assert!(nes.frame_deadline.unwrap() >= deadline + FRAME_PERIOD);
+    }
+
+    #[test]
+    fn test_render_disabled() {
+        let mut nes = Nes::new(Processor::new(None));
+        // LDA #$80, STA $2000 enables NMIs, then JMP $0205
+        nes.cpu
+            .mem
+            .load(0x0200, &[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x02])
+            .unwrap();
+        // INC $10, RTI
+        nes.cpu.mem.load(0x0300, &[0xe6, 0x10, 0x40]).unwrap();
+        nes.cpu.mem.set_vector(NMI_VECTOR, 0x0300);
+        nes.cpu.state.pc = 0x0200;
+        // a white backdrop
+        nes.cpu.mem.write(0x2006, 0x3f);
+        nes.cpu.mem.write(0x2006, 0x00);
+        nes.cpu.mem.write(0x2007, 0x30);
+
+        nes.set_render_enabled(false);
+        for _ in 0..3 {
+            nes.run_frame();
+        }
+        assert_eq!(nes.cpu.mem.read(0x10), 3, "one NMI a frame");
+        assert_eq!(nes.cpu.mem.ppu.frame, 3);
+        assert_eq!(nes.cpu.state.pc & 0xfff0, 0x0200, "back in the loop");
+        assert!(nes.cpu.cycles as usize > 3 * 29_000);
+        assert!(nes.cpu.mem.ppu.frame_buffer.iter().all(|&byte| byte == 0));
+
+        nes.set_render_enabled(true);
+        nes.run_frame();
+        assert_eq!(nes.cpu.mem.read(0x10), 4);
+        assert_eq!(
+            &nes.cpu.mem.ppu.frame_buffer[..4],
+            &[0xfc, 0xfc, 0xfc, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_frame_count() {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&frames);
+        let mut nes = Nes::new(Processor::new(None));
+        nes.set_frame_observer(Some(Box::new(move |count| {
+            seen.borrow_mut().push(count)
+        })));
+        // JMP $0200
+        nes.cpu.mem.load(0x0200, &[0x4c, 0x00, 0x02]).unwrap();
+        nes.cpu.state.pc = 0x0200;
+
+        for _ in 0..3 {
+            nes.run_frame();
+        }
+        assert_eq!(nes.frame_count(), 3);
+        assert_eq!(*frames.borrow(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut nes = Nes::new(Processor::new(None));
+        // two cycle SECs
+        nes.cpu.mem.load(0x0200, &[0x38, 0x38]).unwrap();
+        nes.cpu.state.pc = 0x0200;
+
+        for _ in 0..3 {
+            nes.tick();
+        }
+        assert_eq!(nes.cpu.mem.ppu.dot, 3);
+        assert_eq!(nes.cpu.state.pc, 0x0200, "first cycle fetches the SEC");
+
+        for _ in 0..3 {
+            nes.tick();
+        }
+        assert_eq!(nes.cpu.mem.ppu.dot, 6);
+        assert_eq!(nes.cpu.state.pc, 0x0201, "second cycle runs it");
+        assert_eq!(nes.cpu.state.status & C_FLAG, C_FLAG);
+
+        for _ in 0..6 {
+            nes.tick();
+        }
+        assert_eq!(nes.cpu.state.pc, 0x0202);
+    }
+
+    #[test]
+    fn test_cartridge_format() {
+        let mut unif = b"UNIF".to_vec();
+        unif.resize(0x40, 0);
+        assert!(matches!(
+            Cartridge::from_bytes(&unif),
+            Err(CartridgeError::UnsupportedFormat("UNIF"))
+        ));
+        assert!(matches!(
+            Cartridge::from_bytes(b"FDS\x1a\x01"),
+            Err(CartridgeError::UnsupportedFormat("FDS"))
+        ));
+
+        let mut data = nrom_image(&[0xea]);
+        assert_eq!(
+            CartridgeFormat::detect(&data).unwrap(),
+            CartridgeFormat::INes
+        );
+        data[7] = 0x08;
+        assert_eq!(
+            CartridgeFormat::detect(&data).unwrap(),
+            CartridgeFormat::Nes2
+        );
+    }
+
+    #[test]
+    fn test_assemble_to_ines() {
+        let source = "
+            start:
+                LDA #$42;
+                INX;
+                JMP start;
+        ";
+        let data = assemble_to_ines(source, 0).unwrap();
+        assert_eq!(data.len(), HEADER_BYTE_SIZE + PRG_ROM_UNIT_SIZE);
+
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.info.mapper, 0);
+        assert_eq!(cartridge.info.prg_rom_size, PRG_ROM_UNIT_SIZE);
+        assert_eq!(cartridge.info.chr_rom_size, 0);
+        assert_eq!(cartridge.rom[..6], [0xa9, 0x42, 0xe8, 0x4c, 0x00, 0x80]);
+
+        // boots into the program through the mirrored reset vector
+        let mut nes = Nes::new(Processor::new(None));
+        nes.insert_cartridge(cartridge).unwrap();
+        nes.reset(None);
+        assert_eq!(nes.cpu.state.pc, 0x8000);
+        nes.cpu.exec();
+        assert_eq!(nes.cpu.state.a, 0x42);
+
+        // vectors from the directives fill out both banks
+        let data = assemble_to_ines(
+            ".org $C000\nstart:\nNOP;\n.resetvector start",
+            0x12,
+        )
+        .unwrap();
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.info.mapper, 0x12);
+        assert_eq!(cartridge.info.prg_rom_size, PRG_ROM_UNIT_SIZE * 2);
+        assert_eq!(cartridge.rom[0x7ffc..0x7ffe], [0x00, 0xc0]);
+    }
+
+    #[test]
+    fn test_access_profile() {
+        let source = "
+            poll:
+                LDA $10;
+                AND $10;
+                BEQ poll;
+        ";
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&assemble_to_ines(source, 0).unwrap())
+            .unwrap();
+        nes.reset(None);
+        assert!(nes.access_profile().is_empty(), "off by default");
+
+        nes.enable_access_profile(true);
+        for _ in 0..300 {
+            nes.cpu.exec();
+        }
+        let profile = nes.access_profile();
+        assert_eq!(profile[0], (0x0010, 200, 0), "read twice a loop");
+        assert_eq!(profile[1].1, 100, "each code byte once a loop");
+
+        nes.enable_access_profile(false);
+        assert!(nes.access_profile().is_empty());
+    }
+
+    #[test]
+    fn test_rewind() {
+        // INX, JMP $8000
+        let program = [0xe8, 0x4c, 0x00, 0x80];
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&nrom_image(&program)).unwrap();
+        nes.reset(Some(0x8000));
+        nes.enable_rewind(Some(RewindConfig {
+            interval: 2,
+            capacity: 4,
+        }));
+
+        for _ in 0..4 {
+            nes.run_frame();
+        }
+        let x = nes.cpu.state.x;
+        let hash = nes.cpu.state_hash();
+        for _ in 0..3 {
+            nes.run_frame();
+        }
+        assert_ne!(nes.cpu.state_hash(), hash);
+
+        // frame 7, the newest snapshot at least 2 frames back is frame 4
+        assert!(nes.rewind(2));
+        assert_eq!(nes.frame_count(), 4);
+        assert_eq!(nes.cpu.state.x, x);
+        assert_eq!(nes.cpu.state_hash(), hash);
+        assert_eq!(nes.rewind_buffer().unwrap().len(), 2);
+
+        // capacity keeps the last 4, frames 14 to 20
+        for _ in 0..16 {
+            nes.run_frame();
+        }
+        assert_eq!(nes.rewind_buffer().unwrap().len(), 4);
+        assert!(!nes.rewind(10), "frame 10 was dropped");
+        assert_eq!(nes.frame_count(), 20);
+        assert!(nes.rewind(6));
+        assert_eq!(nes.frame_count(), 14);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        // LDA #$10, LDX #$42, TXA, LDA #$00, JMP *
+        let program =
+            [0xa9, 0x10, 0xa2, 0x42, 0x8a, 0xa9, 0x00, 0x4c, 0x07, 0x80];
+        let mut nes = Nes::new(Processor::new(None));
+        nes.load_cartridge_data(&nrom_image(&program)).unwrap();
+        nes.add_conditional_breakpoint(Box::new(|cpu| cpu.state.a == 0x42));
+        nes.run(Some(0x8000)).unwrap();
+        assert_eq!(nes.cpu.state.pc, 0x8005, "stopped after TXA");
+        assert_eq!(nes.cpu.state.a, 0x42);
+
+        // resuming steps off the breakpoint, A stops matching
+        nes.run_frame();
+        assert_eq!(nes.cpu.state.pc, 0x8007);
+        assert_eq!(nes.cpu.mem.ppu.scanline, 0, "ran the whole frame");
+    }
+
+    #[test]
+    fn test_apply_ips() {
+        let mut data = nrom_image(&[0xea, 0xea, 0xea, 0xea]);
+        let mut patch = b"PATCH".to_vec();
+        // two bytes over the first PRG bytes
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x02, 0xa9, 0x42]);
+        // RLE record, three 0xff from $13
+        patch.extend_from_slice(&[0x00, 0x00, 0x13, 0x00, 0x00, 0x00, 0x03]);
+        patch.push(0xff);
+        patch.extend_from_slice(b"EOF");
+        apply_ips(&mut data, &patch).unwrap();
+
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.rom[0..6], [0xa9, 0x42, 0xea, 0xff, 0xff, 0xff]);
+
+        // growing past the end, then truncating back
+        let mut data = vec![0; 4];
+        let mut patch = b"PATCH\x00\x00\x06\x00\x01\x07EOF".to_vec();
+        apply_ips(&mut data, &patch).unwrap();
+        assert_eq!(data, [0, 0, 0, 0, 0, 0, 7]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x02]);
+        apply_ips(&mut data, &patch).unwrap();
+        assert_eq!(data, [0, 0]);
+
+        assert!(matches!(
+            apply_ips(&mut data, b"PATCH\x00\x00\x01\x00\x04\x01"),
+            Err(CartridgeError::InvalidPatch(_))
+        ));
+        // a good record then a truncated one leaves the image as it was
+        let patch = b"PATCH\x00\x00\x00\x00\x01\x09\x00\x00\x08\x00\x04\x01";
+        assert!(apply_ips(&mut data, patch).is_err());
+        assert_eq!(data, [0, 0], "not patched or grown");
+        assert!(matches!(
+            apply_ips(&mut data, b"EOF"),
+            Err(CartridgeError::InvalidPatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_bad_cartridge() {
+        let path = std::env::temp_dir().join("rusty-nes-bad-cartridge.nes");
+        std::fs::write(&path, b"this is not a cartridge at all").unwrap();
+
+        let mut nes = Nes::new(Processor::new(None));
+        let error = nes.load_cartridge(path.to_str().unwrap()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            error,
+            RustyNesError::Cartridge(CartridgeError::InvalidMagic)
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Cartridge error: cartridge is missing the iNES \"NES\\x1A\" magic"
+        );
+
+        // .nes.gz files have to be unpacked first without the gzip feature
+        let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00];
+        gzip.resize(HEADER_BYTE_SIZE * 2, 0);
+        #[cfg(not(feature = "gzip"))]
+        assert!(matches!(
+            nes.cartridge.load(&gzip),
+            Err(CartridgeError::Compressed)
+        ));
+        #[cfg(feature = "gzip")]
+        assert!(matches!(
+            nes.cartridge.load(&gzip),
+            Err(CartridgeError::InvalidGzip(_))
+        ));
+
+        let error = nes.load_cartridge("/does/not/exist.nes").unwrap_err();
+        assert!(matches!(
+            error,
+            RustyNesError::Cartridge(CartridgeError::Io(_))
+        ));
+        assert!(error
+            .to_string()
+            .starts_with("Cartridge error: unable to read"));
     }
 }
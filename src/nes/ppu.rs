@@ -0,0 +1,975 @@
+use super::{Mirroring, Region};
+use std::ops::Range;
+
+// NTSC timing, every scanline is 341 dots and a frame is 262 scanlines
+pub const SCANLINE_DOTS: u16 = 341;
+pub const FRAME_SCANLINES: u16 = 262;
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+// PAL runs 50 more scanlines of vertical blank
+pub const PAL_FRAME_SCANLINES: u16 = 312;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+pub const PPUCTRL: usize = 0x2000;
+pub const PPUMASK: usize = 0x2001;
+pub const PPUSTATUS: usize = 0x2002;
+pub const OAMADDR: usize = 0x2003;
+pub const OAMDATA: usize = 0x2004;
+pub const PPUADDR: usize = 0x2006;
+pub const PPUDATA: usize = 0x2007;
+
+// Nametables are 32x30 tiles followed by 64 bytes of attributes
+pub const NAMETABLE_START: usize = 0x2000;
+pub const NAMETABLE_SIZE: usize = 0x400;
+pub const NAMETABLE_COLUMNS: usize = 32;
+pub const NAMETABLE_ROWS: usize = 30;
+// $3000-$3EFF mirrors the nametables, palettes sit above
+const NAMETABLE_TOP: usize = 0x3f00;
+const ATTRIBUTE_OFFSET: usize = 0x3c0;
+
+// Two 4K pattern tables, 8K of CHR RAM unless the cartridge has CHR ROM
+pub const CHR_SIZE: usize = 0x2000;
+const PATTERN_TABLE_SIZE: usize = 0x1000;
+
+// Eight 4 colour palettes, background first, mirrored up to $3FFF
+const PALETTE_SIZE: usize = 0x20;
+
+// 8x8 tiles, a low bitplane of 8 rows followed by the high bitplane
+pub const TILE_SIZE: usize = 8;
+pub const TILE_BYTE_SIZE: usize = 16;
+
+// PPUCTRL bit 2, step PPUDATA down a row instead of across a tile
+const INCREMENT_32_FLAG: u8 = 0b0000_0100;
+const NAMETABLE_SELECT: u8 = 0b0000_0011;
+const SPRITE_TABLE_FLAG: u8 = 0b0000_1000;
+const BACKGROUND_TABLE_FLAG: u8 = 0b0001_0000;
+const SPRITE_SIZE_FLAG: u8 = 0b0010_0000;
+const NMI_ENABLE_FLAG: u8 = 0b1000_0000;
+
+// Sprite attribute bits 2-4 aren't stored and read back as 0
+const OAM_ATTRIBUTE_MASK: u8 = 0b1110_0011;
+// Secondary OAM is filled with $FF over these dots of a visible scanline
+const OAM_CLEAR_DOTS: u16 = 64;
+// Sprite pattern fetches per scanline, empty slots fetch tile $FF
+const SPRITE_SLOTS: usize = 8;
+const EMPTY_SPRITE_TILE: usize = 0xFF;
+
+// PPUMASK show background | show sprites
+const RENDERING_MASK: u8 = 0b0001_1000;
+const SHOW_BACKGROUND_FLAG: u8 = 0b0000_1000;
+
+const VBLANK_FLAG: u8 = 0b1000_0000;
+const SPRITE_ZERO_HIT_FLAG: u8 = 0b0100_0000;
+const SPRITE_OVERFLOW_FLAG: u8 = 0b0010_0000;
+// The low bits of PPUSTATUS aren't driven and read back the open bus latch
+const OPEN_BUS_MASK: u8 = 0b0001_1111;
+
+/**
+ * Decoded PPUSTATUS flags
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PpuStatus {
+    pub vblank: bool,
+    pub sprite_zero_hit: bool,
+    pub sprite_overflow: bool,
+}
+
+/**
+ * Winner of the background/sprite priority mux for one dot, each carrying
+ * its 4 bit palette entry
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pixel {
+    Backdrop,
+    Background(u8),
+    Sprite(u8),
+}
+
+/**
+ * Pick the visible pixel from the background and sprite palette entries.
+ * Colour 0 of any palette is transparent, `sprite_priority` is bit 5 of the
+ * sprite attributes which puts the sprite behind an opaque background.
+ */
+pub fn mux_pixel(bg: u8, sprite: u8, sprite_priority: bool) -> Pixel {
+    let bg_opaque = bg & 0b11 != 0;
+    let sprite_opaque = sprite & 0b11 != 0;
+    match (bg_opaque, sprite_opaque) {
+        (false, false) => Pixel::Backdrop,
+        (false, true) => Pixel::Sprite(sprite),
+        (true, false) => Pixel::Background(bg),
+        (true, true) if sprite_priority => Pixel::Background(bg),
+        (true, true) => Pixel::Sprite(sprite),
+    }
+}
+
+/**
+ * Palette indices of a CHR tile, row by row from the top left. Bit 7 of each
+ * plane byte is the leftmost pixel, the high plane gives bit 1 of the index.
+ */
+pub fn decode_tile(chr: &[u8], tile_index: usize) -> [[u8; 8]; 8] {
+    let tile = &chr[tile_index * TILE_BYTE_SIZE..][..TILE_BYTE_SIZE];
+    let mut pixels = [[0; TILE_SIZE]; TILE_SIZE];
+    for (y, row) in pixels.iter_mut().enumerate() {
+        let low = tile[y];
+        let high = tile[y + TILE_SIZE];
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let bit = 7 - x;
+            *pixel = (low >> bit & 1) | (high >> bit & 1) << 1;
+        }
+    }
+    pixels
+}
+
+/**
+ * Binary PPM (P6) image of an RGBA frame buffer, alpha is dropped
+ */
+pub fn frame_to_ppm(rgba: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for pixel in rgba.chunks(4).take(width * height) {
+        ppm.extend_from_slice(&pixel[..3]);
+    }
+    ppm
+}
+
+/**
+ * RGB of the 64 colours a palette entry can pick
+ */
+#[rustfmt::skip]
+const SYSTEM_PALETTE: [u32; 64] = [
+    0x7c7c7c, 0x0000fc, 0x0000bc, 0x4428bc, 0x940084, 0xa80020, 0xa81000, 0x881400,
+    0x503000, 0x007800, 0x006800, 0x005800, 0x004058, 0x000000, 0x000000, 0x000000,
+    0xbcbcbc, 0x0078f8, 0x0058f8, 0x6844fc, 0xd800cc, 0xe40058, 0xf83800, 0xe45c10,
+    0xac7c00, 0x00b800, 0x00a800, 0x00a844, 0x008888, 0x000000, 0x000000, 0x000000,
+    0xf8f8f8, 0x3cbcfc, 0x6888fc, 0x9878f8, 0xf878f8, 0xf85898, 0xf87858, 0xfca044,
+    0xf8b800, 0xb8f818, 0x58d854, 0x58f898, 0x00e8d8, 0x787878, 0x000000, 0x000000,
+    0xfcfcfc, 0xa4e4fc, 0xb8b8f8, 0xd8b8f8, 0xf8b8f8, 0xf8a4c0, 0xf0d0b0, 0xfce0a8,
+    0xf8d878, 0xd8f878, 0xb8f8b8, 0xb8f8d8, 0x00fcfc, 0xf8d8f8, 0x000000, 0x000000,
+];
+
+/**
+ * Picture processing unit, the registers, nametables, frame timing and a
+ * background renderer
+ */
+#[derive(Clone, Debug)]
+pub struct Ppu {
+    pub ctrl: u8,
+    pub mask: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    // completed frames
+    pub frame: u64,
+    status: u8,
+    // Last value written to any register
+    latch: u8,
+    // SCREEN_WIDTH x SCREEN_HEIGHT RGBA pixels
+    pub frame_buffer: Vec<u8>,
+    // Off for fast-forward, the timing, flags & NMIs run without the pixels
+    pub render_enabled: bool,
+    // Pattern tables, writable when they are CHR RAM
+    pub chr: Vec<u8>,
+    pub chr_ram: bool,
+    palette: [u8; PALETTE_SIZE],
+    // Raised at VBlank with NMIs enabled, until the CPU takes it
+    nmi: bool,
+    // Addresses put on the bus by rendering fetches since the last
+    // take_fetches, mappers like MMC3 watch them
+    fetches: Vec<usize>,
+    pub region: Region,
+    pub mirroring: Mirroring,
+    // Room for four nametables, only four-screen boards use the top half
+    vram: Vec<u8>,
+    // VRAM address set through PPUADDR, loopy's v: fine Y in bits 12-14,
+    // nametable in 10-11, coarse Y in 5-9 and coarse X in 0-4
+    address: u16,
+    // PPUADDR takes the high byte first, reading PPUSTATUS resets it
+    write_low: bool,
+    // PPUDATA reads lag a byte behind
+    read_buffer: u8,
+    // sprite attributes, 64 sprites of 4 bytes
+    pub oam: [u8; 256],
+    oam_address: u8,
+    // dots owed from the last step_cpu when the clock ratio isn't whole
+    dot_remainder: u32,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu {
+            ctrl: 0,
+            mask: 0,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            status: 0,
+            latch: 0,
+            frame_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            render_enabled: true,
+            chr: vec![0; CHR_SIZE],
+            chr_ram: true,
+            palette: [0; PALETTE_SIZE],
+            nmi: false,
+            fetches: Vec::new(),
+            region: Region::Ntsc,
+            mirroring: Mirroring::Horizontal,
+            vram: vec![0; NAMETABLE_SIZE * 4],
+            address: 0,
+            write_low: false,
+            read_buffer: 0,
+            oam: [0; 256],
+            oam_address: 0,
+            dot_remainder: 0,
+        }
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu::default()
+    }
+
+    pub fn rendering(&self) -> bool {
+        self.mask & RENDERING_MASK != 0
+    }
+
+    /**
+     * Hand a pending NMI over to the CPU, true at most once per VBlank
+     */
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi, false)
+    }
+
+    pub fn status(&self) -> PpuStatus {
+        PpuStatus {
+            vblank: self.status & VBLANK_FLAG != 0,
+            sprite_zero_hit: self.status & SPRITE_ZERO_HIT_FLAG != 0,
+            sprite_overflow: self.status & SPRITE_OVERFLOW_FLAG != 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn force_flags_for_test(&mut self, flags: PpuStatus) {
+        self.status = 0;
+        if flags.vblank {
+            self.status |= VBLANK_FLAG;
+        }
+        if flags.sprite_zero_hit {
+            self.status |= SPRITE_ZERO_HIT_FLAG;
+        }
+        if flags.sprite_overflow {
+            self.status |= SPRITE_OVERFLOW_FLAG;
+        }
+    }
+
+    /**
+     * CPU read from $2000-$3FFF, the eight registers mirror every 8 bytes
+     */
+    pub fn read_register(&mut self, address: usize) -> u8 {
+        match PPUCTRL + (address & 0x7) {
+            PPUSTATUS => {
+                let value = self.status | (self.latch & OPEN_BUS_MASK);
+                // Only VBlank is cleared by the read, sprite 0 hit and
+                // overflow hold until the pre-render scanline
+                self.status &= !VBLANK_FLAG;
+                self.write_low = false;
+                value
+            }
+            OAMDATA => self.read_oam(),
+            PPUDATA => {
+                let value = self.read_buffer;
+                self.read_buffer = self.read_vram(self.vram_address());
+                self.increment_address();
+                value
+            }
+            // write-only registers
+            _ => self.latch,
+        }
+    }
+
+    pub fn write_register(&mut self, address: usize, value: u8) {
+        self.latch = value;
+        match PPUCTRL + (address & 0x7) {
+            PPUCTRL => {
+                // Enabling NMIs during VBlank raises one straight away
+                let enabled = self.ctrl & NMI_ENABLE_FLAG == 0
+                    && value & NMI_ENABLE_FLAG != 0;
+                if enabled && self.status & VBLANK_FLAG != 0 {
+                    self.nmi = true;
+                }
+                self.ctrl = value;
+            }
+            PPUMASK => self.mask = value,
+            OAMADDR => self.oam_address = value,
+            // Writes while rendering do not reach OAM, they bump the sprite
+            // part of OAMADDR instead
+            OAMDATA if self.rendering_scanline() => {
+                self.oam_address = self.oam_address.wrapping_add(4);
+            }
+            OAMDATA => {
+                self.oam[self.oam_address as usize] = value;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            PPUADDR => {
+                self.address = if self.write_low {
+                    (self.address & 0xff00) | value as u16
+                } else {
+                    ((value as u16) << 8) | (self.address & 0x00ff)
+                } & 0x3fff;
+                self.write_low = !self.write_low;
+            }
+            PPUDATA => {
+                self.write_vram(self.vram_address(), value);
+                self.increment_address();
+            }
+            _ => {}
+        }
+    }
+
+    // Fine Y is the top bit of v but not of a VRAM address
+    fn vram_address(&self) -> usize {
+        (self.address & 0x3fff) as usize
+    }
+
+    /**
+     * OAMDATA read, it never moves OAMADDR. While the start of a visible
+     * scanline clears secondary OAM the read sees $FF, sprite evaluation
+     * isn't emulated so the rest of the line reads OAM as usual.
+     */
+    fn read_oam(&self) -> u8 {
+        if self.rendering()
+            && (self.scanline as usize) < SCREEN_HEIGHT
+            && (1..=OAM_CLEAR_DOTS).contains(&self.dot)
+        {
+            return 0xff;
+        }
+        let value = self.oam[self.oam_address as usize];
+        if self.oam_address & 0b11 == 2 {
+            value & OAM_ATTRIBUTE_MASK
+        } else {
+            value
+        }
+    }
+
+    /**
+     * Visible and pre-render scanlines fetch tiles while rendering is on
+     */
+    fn rendering_scanline(&self) -> bool {
+        self.rendering()
+            && ((self.scanline as usize) < SCREEN_HEIGHT
+                || self.scanline == self.region.scanlines() - 1)
+    }
+
+    fn increment_address(&mut self) {
+        // While rendering the PPUDATA increment is replaced by the fetch
+        // pipeline's coarse X and Y increments, both at once
+        if self.rendering_scanline() {
+            self.increment_coarse_x();
+            self.increment_y();
+            return;
+        }
+        let step = if self.ctrl & INCREMENT_32_FLAG != 0 {
+            32
+        } else {
+            1
+        };
+        self.address = self.address.wrapping_add(step) & 0x7fff;
+    }
+
+    // Next tile across, wrapping into the horizontally adjacent nametable
+    fn increment_coarse_x(&mut self) {
+        if self.address & 0x001f == 31 {
+            self.address &= !0x001f;
+            self.address ^= 0x0400;
+        } else {
+            self.address += 1;
+        }
+    }
+
+    // Next pixel row, fine Y overflows into coarse Y which wraps at the
+    // bottom of the nametable. Rows 30 and 31 (attributes) wrap without
+    // switching nametables.
+    fn increment_y(&mut self) {
+        if self.address & 0x7000 != 0x7000 {
+            self.address += 0x1000;
+            return;
+        }
+        self.address &= !0x7000;
+        let coarse_y = match (self.address & 0x03e0) >> 5 {
+            29 => {
+                self.address ^= 0x0800;
+                0
+            }
+            31 => 0,
+            y => y + 1,
+        };
+        self.address = (self.address & !0x03e0) | (coarse_y << 5);
+    }
+
+    /**
+     * Offset into `vram` for a nametable address, folded by the mirroring
+     */
+    fn vram_index(&self, address: usize) -> usize {
+        let offset = (address - NAMETABLE_START) % (NAMETABLE_SIZE * 4);
+        let table = offset / NAMETABLE_SIZE;
+        let table = match self.mirroring {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::FourScreen => table,
+        };
+        table * NAMETABLE_SIZE + offset % NAMETABLE_SIZE
+    }
+
+    // $3F10/$3F14/$3F18/$3F1C share the backdrop entries below them
+    fn palette_index(address: usize) -> usize {
+        let index = address % PALETTE_SIZE;
+        if index & 0b10011 == 0b10000 {
+            index & 0x0f
+        } else {
+            index
+        }
+    }
+
+    fn read_vram(&self, address: usize) -> u8 {
+        if (0..NAMETABLE_START).contains(&address) {
+            self.chr.get(address).copied().unwrap_or(0)
+        } else if (NAMETABLE_START..NAMETABLE_TOP).contains(&address) {
+            self.vram[self.vram_index(address)]
+        } else {
+            self.palette[Ppu::palette_index(address)]
+        }
+    }
+
+    fn write_vram(&mut self, address: usize, value: u8) {
+        if (0..NAMETABLE_START).contains(&address) {
+            if self.chr_ram && address < self.chr.len() {
+                self.chr[address] = value;
+            }
+        } else if (NAMETABLE_START..NAMETABLE_TOP).contains(&address) {
+            let index = self.vram_index(address);
+            self.vram[index] = value;
+        } else {
+            self.palette[Ppu::palette_index(address)] = value;
+        }
+    }
+
+    // The PPUCTRL nametable and background pattern table
+    fn background_tables(&self) -> (usize, usize) {
+        let nametable = NAMETABLE_START
+            + (self.ctrl & NAMETABLE_SELECT) as usize * NAMETABLE_SIZE;
+        let pattern_table = if self.ctrl & BACKGROUND_TABLE_FLAG != 0 {
+            PATTERN_TABLE_SIZE
+        } else {
+            0
+        };
+        (nametable, pattern_table)
+    }
+
+    // Nametable, attribute and both pattern bytes of the background tiles
+    // in `columns` of `line`
+    fn fetch_tiles(&mut self, line: usize, columns: Range<usize>) {
+        let (nametable, pattern_table) = self.background_tables();
+        let line = line % SCREEN_HEIGHT;
+        let (row, fine_y) = (line / TILE_SIZE, line % TILE_SIZE);
+        for column in columns {
+            let address = nametable + row * NAMETABLE_COLUMNS + column;
+            let tile = self.read_vram(address) as usize;
+            let plane = pattern_table + tile * TILE_BYTE_SIZE + fine_y;
+            let attribute =
+                nametable + ATTRIBUTE_OFFSET + row / 4 * 8 + column / 4;
+            self.fetches.extend(&[
+                address,
+                attribute,
+                plane,
+                plane + TILE_SIZE,
+            ]);
+        }
+    }
+
+    // Sprite evaluation isn't implemented, every slot is empty and fetches
+    // tile $FF after two dummy nametable reads
+    fn fetch_sprites(&mut self, line: usize) {
+        let fine_y = line % TILE_SIZE;
+        let plane = if self.ctrl & SPRITE_SIZE_FLAG != 0 {
+            // 8x16 sprites take the table from bit 0 of the tile number
+            PATTERN_TABLE_SIZE
+                + (EMPTY_SPRITE_TILE & !1) * TILE_BYTE_SIZE
+                + fine_y
+        } else if self.ctrl & SPRITE_TABLE_FLAG != 0 {
+            PATTERN_TABLE_SIZE + EMPTY_SPRITE_TILE * TILE_BYTE_SIZE + fine_y
+        } else {
+            EMPTY_SPRITE_TILE * TILE_BYTE_SIZE + fine_y
+        };
+        for _ in 0..SPRITE_SLOTS {
+            self.fetches.extend(&[
+                NAMETABLE_START,
+                NAMETABLE_START,
+                plane,
+                plane + TILE_SIZE,
+            ]);
+        }
+    }
+
+    /**
+     * The addresses rendering has fetched from since the last call
+     */
+    pub fn take_fetches(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.fetches)
+    }
+
+    /**
+     * Draw visible scanline `line` into the frame buffer all at once. Only
+     * the background of the PPUCTRL nametable is drawn, scrolling and
+     * sprites aren't applied, and with the background off the line is the
+     * backdrop colour.
+     */
+    fn render_scanline(&mut self, line: usize) {
+        let show_background = self.mask & SHOW_BACKGROUND_FLAG != 0;
+        let (nametable, pattern_table) = self.background_tables();
+        let (row, fine_y) = (line / TILE_SIZE, line % TILE_SIZE);
+        for x in 0..SCREEN_WIDTH {
+            let mut background = 0;
+            if show_background {
+                let column = x / TILE_SIZE;
+                let tile = self
+                    .read_vram(nametable + row * NAMETABLE_COLUMNS + column)
+                    as usize;
+                let plane = pattern_table + tile * TILE_BYTE_SIZE + fine_y;
+                let bit = 7 - x % TILE_SIZE;
+                let low = self.read_vram(plane) >> bit & 1;
+                let high = self.read_vram(plane + TILE_SIZE) >> bit & 1;
+                // One attribute byte covers 4x4 tiles, 2 bits per 2x2
+                let attribute = self.read_vram(
+                    nametable + ATTRIBUTE_OFFSET + row / 4 * 8 + column / 4,
+                );
+                let shift = (row & 0b10) * 2 + (column & 0b10);
+                let palette = attribute >> shift & 0b11;
+                background = palette << 2 | high << 1 | low;
+            }
+            let entry = match mux_pixel(background, 0, false) {
+                Pixel::Background(entry) | Pixel::Sprite(entry) => entry,
+                Pixel::Backdrop => 0,
+            };
+            let colour = self.palette[entry as usize] as usize & 0x3f;
+            let rgb = SYSTEM_PALETTE[colour].to_be_bytes();
+            let pixel = (line * SCREEN_WIDTH + x) * 4;
+            self.frame_buffer[pixel..pixel + 4]
+                .copy_from_slice(&[rgb[1], rgb[2], rgb[3], 0xff]);
+        }
+    }
+
+    /**
+     * Tile IDs of nametable `index` (0-3) as a 32x30 hex grid, one row a line
+     */
+    pub fn dump_nametable(&self, index: u8) -> String {
+        let start = NAMETABLE_START + (index as usize & 0b11) * NAMETABLE_SIZE;
+        let mut dump = String::new();
+        for row in 0..NAMETABLE_ROWS {
+            let tiles: Vec<String> = (0..NAMETABLE_COLUMNS)
+                .map(|column| {
+                    let address = start + row * NAMETABLE_COLUMNS + column;
+                    format!("{:02X}", self.read_vram(address))
+                })
+                .collect();
+            dump.push_str(&tiles.join(" "));
+            dump.push('\n');
+        }
+        dump
+    }
+
+    /**
+     * Advance the PPU by the dots `cycles` CPU cycles take in this region
+     */
+    pub fn step_cpu(&mut self, cycles: u32) {
+        let (dots, per_cycles) = self.region.clock_ratio();
+        let total = cycles * dots + self.dot_remainder;
+        self.dot_remainder = total % per_cycles;
+        self.step(total / per_cycles);
+    }
+
+    /**
+     * Advance the PPU by `dots` PPU cycles
+     */
+    pub fn step(&mut self, dots: u32) {
+        let scanlines = self.region.scanlines();
+        let pre_render = scanlines - 1;
+        for _ in 0..dots {
+            self.dot += 1;
+            // Odd frames drop the last dot of the pre-render scanline while
+            // rendering, which keeps the colour subcarrier phase in line.
+            // PAL frames are all the same length.
+            if self.scanline == pre_render
+                && self.dot == SCANLINE_DOTS - 1
+                && self.frame % 2 == 1
+                && self.rendering()
+                && self.region == Region::Ntsc
+            {
+                self.dot = SCANLINE_DOTS;
+            }
+            if self.dot == SCANLINE_DOTS {
+                self.dot = 0;
+                self.scanline = (self.scanline + 1) % scanlines;
+                if self.scanline == 0 {
+                    self.frame += 1;
+                }
+            }
+
+            if self.dot == 1 {
+                match self.scanline {
+                    VBLANK_SCANLINE => {
+                        self.status |= VBLANK_FLAG;
+                        if self.ctrl & NMI_ENABLE_FLAG != 0 {
+                            self.nmi = true;
+                        }
+                    }
+                    line if line == pre_render => self.status = 0,
+                    _ => {}
+                }
+            }
+            // The line's pixels are out once its 256 visible dots are done
+            if self.dot == SCREEN_WIDTH as u16
+                && (self.scanline as usize) < SCREEN_HEIGHT
+                && self.render_enabled
+            {
+                self.render_scanline(self.scanline as usize);
+            }
+            // The fetches of each stretch of the scanline are reported
+            // together once it's over: the background tiles, the sprites and
+            // the first two tiles of the next line
+            if self.rendering_scanline() {
+                let line = self.scanline as usize;
+                match self.dot {
+                    256 => self.fetch_tiles(line, 2..NAMETABLE_COLUMNS),
+                    320 => self.fetch_sprites(line),
+                    336 => self.fetch_tiles(line + 1, 0..2),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut ppu = Ppu::new();
+        ppu.force_flags_for_test(PpuStatus {
+            vblank: true,
+            sprite_zero_hit: true,
+            sprite_overflow: false,
+        });
+
+        assert_eq!(ppu.read_register(PPUSTATUS), 0xc0);
+        assert_eq!(
+            ppu.status(),
+            PpuStatus {
+                vblank: false,
+                sprite_zero_hit: true,
+                sprite_overflow: false,
+            },
+            "reading $2002 clears only VBlank"
+        );
+        // $3FFA mirrors $2002
+        assert_eq!(ppu.read_register(0x3ffa), 0x40);
+
+        // run to dot 1 of the pre-render scanline
+        let dots = PRE_RENDER_SCANLINE as u32 * SCANLINE_DOTS as u32 + 1;
+        ppu.step(dots - 1);
+        assert!(ppu.status().sprite_zero_hit);
+        ppu.step(1);
+        assert_eq!(ppu.status(), PpuStatus::default());
+    }
+
+    #[test]
+    fn test_status_read_only() {
+        let mut ppu = Ppu::new();
+        ppu.force_flags_for_test(PpuStatus {
+            vblank: false,
+            sprite_zero_hit: true,
+            sprite_overflow: false,
+        });
+        ppu.write_register(PPUSTATUS, 0xff);
+
+        assert!(!ppu.status().vblank, "write can't set VBlank");
+        // flags from the PPU, only the undriven bits show the write
+        assert_eq!(ppu.read_register(PPUSTATUS), 0x40 | OPEN_BUS_MASK);
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut ppu = Ppu::new();
+        ppu.step(VBLANK_SCANLINE as u32 * SCANLINE_DOTS as u32 + 1);
+        assert!(ppu.status().vblank);
+        assert_eq!(ppu.read_register(PPUSTATUS) & VBLANK_FLAG, VBLANK_FLAG);
+        assert!(!ppu.status().vblank);
+    }
+
+    #[test]
+    fn test_dump_nametable() {
+        let mut ppu = Ppu::new();
+        ppu.mirroring = Mirroring::Vertical;
+        // $2400 is the second nametable, filled with 0x24
+        ppu.write_register(PPUADDR, 0x24);
+        ppu.write_register(PPUADDR, 0x00);
+        for _ in 0..NAMETABLE_COLUMNS * NAMETABLE_ROWS {
+            ppu.write_register(PPUDATA, 0x24);
+        }
+        // tiles 1 to 3 down the first column of row 2 in the first nametable
+        ppu.write_register(PPUCTRL, INCREMENT_32_FLAG);
+        ppu.write_register(PPUADDR, 0x20);
+        ppu.write_register(PPUADDR, 0x40);
+        for tile in 1..=3 {
+            ppu.write_register(PPUDATA, tile);
+        }
+
+        let dump = ppu.dump_nametable(0);
+        let rows: Vec<&str> = dump.lines().collect();
+        assert_eq!(rows.len(), NAMETABLE_ROWS);
+        assert_eq!(rows[0], ["00"; NAMETABLE_COLUMNS].join(" "));
+        assert!(rows[2].starts_with("01 00"));
+        assert!(rows[3].starts_with("02 00"));
+        assert!(rows[4].starts_with("03 00"));
+        assert_eq!(ppu.dump_nametable(1), ppu.dump_nametable(3));
+        assert!(ppu
+            .dump_nametable(1)
+            .lines()
+            .all(|row| row == ["24"; NAMETABLE_COLUMNS].join(" ")));
+
+        // horizontal mirroring folds $2400 onto the first nametable
+        ppu.mirroring = Mirroring::Horizontal;
+        assert_eq!(ppu.dump_nametable(0), ppu.dump_nametable(1));
+    }
+
+    #[test]
+    fn test_four_screen_mirroring() {
+        let mut ppu = Ppu::new();
+        ppu.mirroring = Mirroring::FourScreen;
+        let tables = [0x2000, 0x2400, 0x2800, 0x2c00];
+        for (i, &table) in tables.iter().enumerate() {
+            ppu.write_register(PPUADDR, (table >> 8) as u8);
+            ppu.write_register(PPUADDR, 0x00);
+            ppu.write_register(PPUDATA, 0x10 + i as u8);
+        }
+
+        for (i, &table) in tables.iter().enumerate() {
+            // $3000-$3EFF mirrors the same four
+            for &address in [table, table + 0x1000].iter() {
+                ppu.write_register(PPUADDR, (address >> 8) as u8);
+                ppu.write_register(PPUADDR, 0x00);
+                ppu.read_register(PPUDATA);
+                assert_eq!(
+                    ppu.read_register(PPUDATA),
+                    0x10 + i as u8,
+                    "{:#06x}",
+                    address
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_oamdata() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(OAMADDR, 0x10);
+        for value in [0x40, 0x21, 0xff, 0x80].iter() {
+            ppu.write_register(OAMDATA, *value);
+        }
+        assert_eq!(ppu.oam_address, 0x14, "writes advance OAMADDR");
+
+        ppu.write_register(OAMADDR, 0x11);
+        assert_eq!(ppu.read_register(OAMDATA), 0x21);
+        assert_eq!(ppu.read_register(OAMDATA), 0x21, "reads don't");
+        assert_eq!(ppu.oam_address, 0x11);
+        // the attribute byte drops the unimplemented bits
+        ppu.write_register(OAMADDR, 0x12);
+        assert_eq!(ppu.read_register(OAMDATA), 0xe3);
+
+        // start of a visible scanline, secondary OAM is being cleared
+        ppu.write_register(PPUMASK, RENDERING_MASK);
+        ppu.step(SCANLINE_DOTS as u32 + 10);
+        ppu.write_register(OAMADDR, 0x10);
+        assert_eq!(ppu.read_register(OAMDATA), 0xff);
+        ppu.write_register(OAMDATA, 0x00);
+        assert_eq!(ppu.oam_address, 0x14, "a sprite forward");
+        assert_eq!(ppu.oam[0x10], 0x40, "OAM is untouched");
+        ppu.step(100);
+        assert_eq!(ppu.read_register(OAMDATA), 0x00);
+    }
+
+    #[test]
+    fn test_ppudata_during_rendering() {
+        fn write_at(ppu: &mut Ppu, address: u16) -> u16 {
+            ppu.write_register(PPUADDR, (address >> 8) as u8);
+            ppu.write_register(PPUADDR, address as u8);
+            ppu.write_register(PPUDATA, 0xaa);
+            ppu.address
+        }
+
+        let mut ppu = Ppu::new();
+        assert_eq!(write_at(&mut ppu, 0x2000), 0x2001, "vblank steps by 1");
+
+        // into a visible scanline with the background on
+        ppu.write_register(PPUMASK, RENDERING_MASK);
+        ppu.step(10 * SCANLINE_DOTS as u32 + 5);
+        // fine Y 2 to 3 and coarse X 0 to 1
+        assert_eq!(write_at(&mut ppu, 0x2000), 0x3001);
+        // coarse X 31 wraps into the next nametable
+        assert_eq!(write_at(&mut ppu, 0x201f), 0x3400);
+        // fine Y 3 on row 29, four more writes carry fine Y into coarse Y
+        // which wraps to the nametable below
+        assert_eq!(write_at(&mut ppu, 0x33a0), 0x43a1);
+        for _ in 0..4 {
+            ppu.write_register(PPUDATA, 0xaa);
+        }
+        assert_eq!(ppu.address, 0x0805);
+        ppu.write_register(PPUCTRL, INCREMENT_32_FLAG);
+        assert_eq!(write_at(&mut ppu, 0x2040), 0x3041, "no +32 either");
+
+        // vblank lines aren't rendered
+        ppu.step((VBLANK_SCANLINE - 10) as u32 * SCANLINE_DOTS as u32);
+        assert_eq!(write_at(&mut ppu, 0x2040), 0x2060);
+    }
+
+    #[test]
+    fn test_ppudata_read_buffer() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(PPUADDR, 0x20);
+        ppu.write_register(PPUADDR, 0x00);
+        ppu.write_register(PPUDATA, 0x11);
+        ppu.write_register(PPUDATA, 0x22);
+
+        ppu.write_register(PPUADDR, 0x20);
+        ppu.write_register(PPUADDR, 0x00);
+        ppu.read_register(PPUDATA);
+        assert_eq!(ppu.read_register(PPUDATA), 0x11);
+        assert_eq!(ppu.read_register(PPUDATA), 0x22);
+    }
+
+    #[test]
+    fn test_mux_pixel() {
+        // the palette number doesn't make colour 0 opaque
+        let cases = [
+            (0x00, 0x00, false, Pixel::Backdrop),
+            (0x00, 0x00, true, Pixel::Backdrop),
+            (0x04, 0x08, true, Pixel::Backdrop),
+            (0x00, 0x11, false, Pixel::Sprite(0x11)),
+            (0x00, 0x11, true, Pixel::Sprite(0x11)),
+            (0x06, 0x00, false, Pixel::Background(0x06)),
+            (0x06, 0x00, true, Pixel::Background(0x06)),
+            (0x06, 0x11, false, Pixel::Sprite(0x11)),
+            (0x06, 0x11, true, Pixel::Background(0x06)),
+        ];
+        for &(bg, sprite, priority, expected) in cases.iter() {
+            assert_eq!(
+                mux_pixel(bg, sprite, priority),
+                expected,
+                "bg {:#04x} sprite {:#04x} priority {}",
+                bg,
+                sprite,
+                priority
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_tile() {
+        // the "½" tile from the NESdev wiki's pattern table page, after a
+        // blank tile
+        let mut chr = vec![0; TILE_BYTE_SIZE];
+        chr.extend_from_slice(&[
+            0x41, 0xc2, 0x44, 0x48, 0x10, 0x20, 0x40, 0x80, // low plane
+            0x01, 0x02, 0x04, 0x08, 0x16, 0x21, 0x42, 0x87, // high plane
+        ]);
+        assert_eq!(
+            decode_tile(&chr, 1),
+            [
+                [0, 1, 0, 0, 0, 0, 0, 3],
+                [1, 1, 0, 0, 0, 0, 3, 0],
+                [0, 1, 0, 0, 0, 3, 0, 0],
+                [0, 1, 0, 0, 3, 0, 0, 0],
+                [0, 0, 0, 3, 0, 2, 2, 0],
+                [0, 0, 3, 0, 0, 0, 0, 2],
+                [0, 3, 0, 0, 0, 0, 2, 0],
+                [3, 0, 0, 0, 0, 2, 2, 2],
+            ]
+        );
+        assert_eq!(decode_tile(&chr, 0), [[0; 8]; 8]);
+    }
+
+    #[test]
+    fn test_frame_to_ppm() {
+        let rgba = [0x12, 0x34, 0x56, 0xff].repeat(3 * 2);
+        let ppm = frame_to_ppm(&rgba, 3, 2);
+
+        let header = b"P6\n3 2\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(&ppm[header.len()..], &[0x12, 0x34, 0x56].repeat(6)[..]);
+
+        let ppu = Ppu::new();
+        let ppm = frame_to_ppm(&ppu.frame_buffer, SCREEN_WIDTH, SCREEN_HEIGHT);
+        assert_eq!(ppm.len(), 15 + SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+    }
+
+    #[test]
+    fn test_odd_frame_skip() {
+        // dots until the PPU is back at the start of a frame
+        fn frame_dots(ppu: &mut Ppu) -> u32 {
+            let mut dots = 0;
+            let frame = ppu.frame;
+            while ppu.frame == frame {
+                ppu.step(1);
+                dots += 1;
+            }
+            dots
+        }
+
+        let mut ppu = Ppu::new();
+        ppu.write_register(PPUMASK, RENDERING_MASK);
+        assert_eq!(frame_dots(&mut ppu), 89342, "even frame");
+        assert_eq!(frame_dots(&mut ppu), 89341, "odd frame");
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+
+        // without rendering every frame is full length
+        ppu.write_register(PPUMASK, 0);
+        assert_eq!(frame_dots(&mut ppu), 89342);
+        assert_eq!(frame_dots(&mut ppu), 89342);
+    }
+
+    #[test]
+    fn test_render_scanline() {
+        let mut ppu = Ppu::new();
+        // Tile 1 is a solid block of colour 3, top left of the nametable
+        ppu.chr[TILE_BYTE_SIZE..TILE_BYTE_SIZE * 2]
+            .copy_from_slice(&[0xff; 16]);
+        let mut write = |address: u16, value: u8| {
+            ppu.write_register(PPUADDR, (address >> 8) as u8);
+            ppu.write_register(PPUADDR, address as u8);
+            ppu.write_register(PPUDATA, value);
+        };
+        write(0x2000, 1);
+        // Palette 1 for the top left 2x2 tiles
+        write(0x23c0, 0b01);
+        write(0x3f00, 0x0f);
+        write(0x3f07, 0x30);
+        ppu.write_register(PPUMASK, SHOW_BACKGROUND_FLAG);
+
+        // Through the visible dots of the first scanline
+        ppu.step(SCREEN_WIDTH as u32 + 1);
+        let pixel =
+            |ppu: &Ppu, x: usize| ppu.frame_buffer[x * 4..x * 4 + 4].to_vec();
+        assert_eq!(pixel(&ppu, 0), vec![0xfc, 0xfc, 0xfc, 0xff]);
+        assert_eq!(pixel(&ppu, 7), vec![0xfc, 0xfc, 0xfc, 0xff]);
+        assert_eq!(pixel(&ppu, 8), vec![0x00, 0x00, 0x00, 0xff], "backdrop");
+
+        // $3F10 is the backdrop as well
+        ppu.write_register(PPUADDR, 0x3f);
+        ppu.write_register(PPUADDR, 0x10);
+        ppu.write_register(PPUDATA, 0x30);
+        ppu.step(SCANLINE_DOTS as u32);
+        let second_line = SCREEN_WIDTH * 4;
+        assert_eq!(ppu.frame_buffer[second_line + 8 * 4], 0xfc);
+    }
+}
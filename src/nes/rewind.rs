@@ -0,0 +1,142 @@
+// In-memory save states for rewinding. The whole machine is copied, CPU,
+// the flat memory, PPU, APU and mapper registers; the cartridge isn't since
+// it never changes while running.
+use super::apu::Apu;
+use super::mapper::Mapper;
+use super::ppu::Ppu;
+use super::Nes;
+use crate::cpu::base::{Fault, State};
+use crate::cpu::MicroOp;
+use std::collections::VecDeque;
+
+/**
+ * How often snapshots are taken and how many are kept. Memory use is about
+ * `capacity` copies of the 64K address space and the PPU.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RewindConfig {
+    // frames between snapshots
+    pub interval: u64,
+    pub capacity: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        // ten seconds of NTSC frames, a snapshot every quarter second
+        RewindConfig {
+            interval: 15,
+            capacity: 40,
+        }
+    }
+}
+
+/**
+ * The machine at the end of a frame
+ */
+pub struct Snapshot {
+    pub frame: u64,
+    state: State,
+    cycles: u32,
+    instruction_count: u64,
+    busy: u32,
+    micro_ops: VecDeque<MicroOp>,
+    address_latch: u16,
+    data_latch: u8,
+    fault: Option<Fault>,
+    nmi_at: Option<u64>,
+    clock: u64,
+    ram: Vec<u8>,
+    ppu: Ppu,
+    apu: Apu,
+    mapper: Option<Box<dyn Mapper>>,
+}
+
+impl Snapshot {
+    pub fn capture(nes: &Nes) -> Snapshot {
+        let cpu = &nes.cpu;
+        Snapshot {
+            frame: nes.frame_count,
+            state: cpu.state,
+            cycles: cpu.cycles,
+            instruction_count: cpu.instruction_count,
+            busy: cpu.busy,
+            micro_ops: cpu.micro_ops.clone(),
+            address_latch: cpu.address_latch,
+            data_latch: cpu.data_latch,
+            fault: cpu.fault,
+            nmi_at: cpu.nmi_at,
+            clock: nes.clock,
+            ram: cpu.mem.ram.to_vec(),
+            ppu: cpu.mem.ppu.clone(),
+            apu: cpu.mem.apu,
+            mapper: cpu.mem.mapper.as_ref().map(|mapper| mapper.box_clone()),
+        }
+    }
+
+    pub fn restore(&self, nes: &mut Nes) {
+        nes.frame_count = self.frame;
+        nes.clock = self.clock;
+        let cpu = &mut nes.cpu;
+        cpu.state = self.state;
+        cpu.cycles = self.cycles;
+        cpu.instruction_count = self.instruction_count;
+        cpu.busy = self.busy;
+        cpu.micro_ops = self.micro_ops.clone();
+        cpu.address_latch = self.address_latch;
+        cpu.data_latch = self.data_latch;
+        cpu.fault = self.fault;
+        cpu.nmi_at = self.nmi_at;
+        cpu.mem.ram.copy_from_slice(&self.ram);
+        cpu.mem.ppu = self.ppu.clone();
+        cpu.mem.apu = self.apu;
+        cpu.mem.mapper = self.mapper.as_ref().map(|mapper| mapper.box_clone());
+    }
+}
+
+/**
+ * Ring buffer of snapshots, oldest first
+ */
+pub struct Rewind {
+    pub config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Rewind {
+        Rewind {
+            config,
+            snapshots: VecDeque::with_capacity(config.capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    // Called once a frame, keeps every `interval`th
+    pub fn end_frame(&mut self, nes: &Nes) {
+        if self.config.capacity == 0
+            || !nes.frame_count.is_multiple_of(self.config.interval.max(1))
+        {
+            return;
+        }
+        if self.snapshots.len() == self.config.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot::capture(nes));
+    }
+
+    /**
+     * The newest snapshot from `frame` or before, the ones after it are
+     * dropped. Nothing is dropped when there isn't one.
+     */
+    pub fn rewind_to(&mut self, frame: u64) -> Option<&Snapshot> {
+        let keep = self.snapshots.iter().rposition(|s| s.frame <= frame)?;
+        self.snapshots.truncate(keep + 1);
+        self.snapshots.back()
+    }
+}